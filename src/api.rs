@@ -1,12 +1,26 @@
+use crate::clock::Clock;
+use crate::context::{RequestContext, is_boot_event};
+use crate::crash::CrashRecorder;
 use crate::error::MyError;
-use crate::hglobal::ShioriString;
+use crate::hglobal::{HGLOBAL, ShioriString, decode_charset, decode_lossy, encode_charset};
+use crate::middleware::{Middleware, Next};
+use crate::parsers::req::ShioriRequest;
+use crate::parsers::res::{ShioriResponse, Status};
+use crate::random::RandomSource;
+use crate::script::lint::Linter;
+use crate::services::Services;
+use crate::store::Store;
+use crate::trace_log::TraceLogger;
 
 use log::*;
 use std::borrow::Cow;
 use std::ffi::c_void;
-use std::path::Path;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::ptr;
-use windows_sys::Win32::Foundation::*;
+use std::str;
+use std::sync::{Arc, Mutex};
 
 #[allow(clippy::upper_case_acronyms)]
 type LPVOID = *mut c_void;
@@ -19,44 +33,465 @@ pub trait Shiori3: Sized {
         load_dir_bytes: &[u8],
     ) -> Result<Self, anyhow::Error>;
 
+    /// ホストが用意したサービスを受け取り、SHIORIインスタンスを作成します。
+    /// 既定の実装はサービスを使わずに`load()`を呼び出します。
+    fn load_with_services(
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        _services: &Services,
+    ) -> Result<Self, anyhow::Error> {
+        Self::load(h_inst, load_dir, load_dir_bytes)
+    }
+
     /// SHIORIリクエストを解釈し、応答を返します。
     fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error>;
+
+    /// SHIORIインスタンスの終了処理を行います。
+    /// drop前に呼ばれるので、状態の保存やログのフラッシュに利用してください。
+    fn unload(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
 }
 
-#[allow(dead_code)]
-struct Shiori3DI<T>
+/// オブジェクト安全なSHIORIハンドラです。
+/// `Box<dyn ShioriHandler>`として、実行時に実装を選択できます。
+/// `Shiori3`の実装は全て`ShioriHandler`としても利用できます。
+pub trait ShioriHandler: Send {
+    /// SHIORIリクエストを解釈し、応答を返します。
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error>;
+
+    /// バイト列のSHIORIリクエストに応答します。`RawShiori3`はこちらを呼び出します。
+    /// 既定の実装はリクエストをUTF-8として`handle()`を呼び出し、応答をそのまま返します。
+    /// Charsetの変換やバイト列の中継を行う場合に実装してください。
+    fn handle_bytes<'a>(
+        &mut self,
+        req: &'a [u8],
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        let res = self.handle(str::from_utf8(req)?, ctx)?;
+        Ok(into_bytes(res))
+    }
+
+    /// SHIORIインスタンスの終了処理を行います。unload時に呼ばれます。
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// 応答の文字列をバイト列にします。
+fn into_bytes(res: Cow<'_, str>) -> Cow<'_, [u8]> {
+    match res {
+        Cow::Borrowed(res) => Cow::Borrowed(res.as_bytes()),
+        Cow::Owned(res) => Cow::Owned(res.into_bytes()),
+    }
+}
+
+impl<T: Shiori3 + Send> ShioriHandler for T {
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        _ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        self.request(req)
+    }
+
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        self.unload()
+    }
+}
+
+/// 解析済みのリクエストを受け取り、構造化されたレスポンスを返すSHIORIです。
+/// リクエストの解析とレスポンスの文字列化、Charsetの変換は`ServiceHandler`が行います。
+/// `RawShiori3`で変換しないのは、`handle_bytes()`でバイト列を中継するハンドラ
+/// （`ProxyShiori`など）にリクエストを加工せずに渡すためです。
+/// `RawShiori3`には`ServiceHandler::boxed()`で包んで渡してください。
+pub trait ShioriService: Send {
+    /// SHIORIリクエストに応答します。
+    fn request(
+        &mut self,
+        req: &ShioriRequest,
+        ctx: &mut RequestContext,
+    ) -> Result<ShioriResponse, anyhow::Error>;
+
+    /// SHIORIインスタンスの終了処理を行います。
+    fn unload(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// `ShioriService`を`ShioriHandler`として扱います。
+/// リクエストと応答はCharsetヘッダに従って変換します。
+/// 解析できないリクエストには`400 Bad Request`を、
+/// エラーには`500 Internal Server Error`を返します。
+pub struct ServiceHandler<S> {
+    service: S,
+}
+
+impl<S: ShioriService + 'static> ServiceHandler<S> {
+    pub fn new(service: S) -> Self {
+        ServiceHandler { service }
+    }
+
+    /// `Box<dyn ShioriHandler>`を作成します。
+    pub fn boxed(service: S) -> Box<dyn ShioriHandler> {
+        Box::new(ServiceHandler::new(service))
+    }
+
+    /// サービスを参照します。
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+
+    fn respond(&mut self, text: &str, ctx: &mut RequestContext) -> ShioriResponse {
+        let req = match ShioriRequest::parse(text) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("[request] {}", e);
+                return ShioriResponse::bad_request();
+            }
+        };
+        let mut res = match self.service.request(&req, ctx) {
+            Ok(a) => a,
+            Err(e) => {
+                error!("[request] {}", e);
+                ShioriResponse::new(Status::InternalServerError)
+            }
+        };
+        if let Some(charset) = req.charset {
+            res.set_header("Charset", charset);
+        }
+        res
+    }
+}
+
+impl<S: ShioriService + 'static> ShioriHandler for ServiceHandler<S> {
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        Ok(Cow::Owned(self.respond(req, ctx).to_string()))
+    }
+
+    fn handle_bytes<'a>(
+        &mut self,
+        req: &'a [u8],
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        let res = match decode_charset(req) {
+            Ok(req) => self.respond(&req, ctx),
+            Err(e) => {
+                warn!("[request] {}", e);
+                ShioriResponse::bad_request()
+            }
+        };
+        Ok(Cow::Owned(encode_charset(&res.to_string())?.into_owned()))
+    }
+
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        self.service.unload()
+    }
+}
+
+/// load_dir pathのファイルでSHIORIハンドラを作成します。
+/// ロードディレクトリの内容に応じて実装を切り替える場合に利用してください。
+pub trait ShioriFactory: Send {
+    fn load(
+        &self,
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error>;
+
+    /// ホストが用意したサービスを受け取り、SHIORIハンドラを作成します。
+    /// 既定の実装はサービスを使わずに`load()`を呼び出します。
+    fn load_with_services(
+        &self,
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        _services: &Services,
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error> {
+        self.load(h_inst, load_dir, load_dir_bytes)
+    }
+}
+
+impl<F> ShioriFactory for F
 where
-    T: Shiori3,
+    F: Fn(usize, &Path, &[u8]) -> Result<Box<dyn ShioriHandler>, anyhow::Error> + Send,
 {
-    di: T,
+    fn load(
+        &self,
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error> {
+        self(h_inst, load_dir, load_dir_bytes)
+    }
+}
+
+/// ミドルウェアを作成する関数です。
+pub(crate) type LayerFn = Box<dyn Fn() -> Box<dyn Middleware> + Send>;
+
+/// `Shiori3`の実装を`ShioriFactory`として扱います。
+/// `with_layer()`で登録したミドルウェアをロード毎に作成し、`Shiori3DI`で包みます。
+pub struct Shiori3Factory<T> {
+    layers: Vec<LayerFn>,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for Shiori3Factory<T> {
+    fn default() -> Self {
+        Shiori3Factory {
+            layers: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Shiori3Factory<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// ミドルウェアを登録します。先に登録したものほど外側で動作します。
+    pub fn with_layer<M, F>(mut self, f: F) -> Self
+    where
+        M: Middleware + 'static,
+        F: Fn() -> M + Send + 'static,
+    {
+        self.layers.push(Box::new(move || Box::new(f())));
+        self
+    }
+}
+
+impl<T: Shiori3 + Send + 'static> ShioriFactory for Shiori3Factory<T> {
+    fn load(
+        &self,
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error> {
+        self.load_with_services(h_inst, load_dir, load_dir_bytes, &Services::new())
+    }
+
+    fn load_with_services(
+        &self,
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        services: &Services,
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error> {
+        let shiori =
+            Shiori3DI::<T>::load_with_services(h_inst, load_dir, load_dir_bytes, services)?;
+        let layers = self.layers.iter().map(|f| f()).collect();
+        Ok(Box::new(shiori.with_layers(layers)))
+    }
+}
+
+/// ミドルウェアのスタックでSHIORIハンドラを包みます。
+/// リクエストは登録順にミドルウェアを通ってハンドラに届き、
+/// 応答は逆順にミドルウェアを通って返ります。
+pub struct Shiori3DI<H> {
+    di: H,
+    layers: Vec<Box<dyn Middleware>>,
 }
 
-impl<T: Shiori3> Shiori3 for Shiori3DI<T> {
+impl<T: Shiori3> Shiori3DI<T> {
     /// load_dir pathのファイルでSHIORIインスタンスを作成します。
-    fn load<P: AsRef<Path>>(
+    pub fn load<P: AsRef<Path>>(
         h_inst: usize,
         load_dir: P,
         load_dir_bytes: &[u8],
     ) -> Result<Self, anyhow::Error> {
         let di = T::load(h_inst, load_dir, load_dir_bytes)?;
-        Ok(Shiori3DI { di })
+        Ok(Shiori3DI::new(di))
     }
 
-    /// SHIORIリクエストを解釈し、応答を返します。
-    fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
-        self.di.request(req)
+    /// サービスを渡してSHIORIインスタンスを作成します。
+    pub fn load_with_services(
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        services: &Services,
+    ) -> Result<Self, anyhow::Error> {
+        let di = T::load_with_services(h_inst, load_dir, load_dir_bytes, services)?;
+        Ok(Shiori3DI::new(di))
+    }
+}
+
+impl<H> Shiori3DI<H> {
+    /// ミドルウェアのないスタックを作成します。
+    pub fn new(di: H) -> Self {
+        Shiori3DI {
+            di,
+            layers: Vec::new(),
+        }
+    }
+
+    /// ミドルウェアを登録します。先に登録したものほど外側で動作します。
+    pub fn with_layer<M: Middleware + 'static>(mut self, layer: M) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// 作成済みのミドルウェアを登録します。
+    pub(crate) fn with_layers(mut self, layers: Vec<Box<dyn Middleware>>) -> Self {
+        self.layers.extend(layers);
+        self
+    }
+
+    /// 包んでいるハンドラを参照します。
+    pub fn inner(&self) -> &H {
+        &self.di
+    }
+
+    /// 包んでいるハンドラを可変で参照します。
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.di
+    }
+}
+
+impl<H: ShioriHandler> ShioriHandler for Shiori3DI<H> {
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        Next::new(&mut self.layers, &mut self.di).run(req, ctx)
+    }
+
+    /// ミドルウェアがなければハンドラにバイト列をそのまま渡します。
+    /// ミドルウェアがあればCharsetヘッダに従って文字列に変換して呼び出し、
+    /// 応答を応答のCharsetに変換します。
+    fn handle_bytes<'a>(
+        &mut self,
+        req: &'a [u8],
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        if self.layers.is_empty() {
+            return self.di.handle_bytes(req, ctx);
+        }
+        let req = decode_charset(req)?;
+        let res = self.handle(&req, ctx)?;
+        Ok(Cow::Owned(encode_charset(&res)?.into_owned()))
+    }
+
+    /// ハンドラ、ミドルウェアの順に終了処理を行います。
+    /// エラーがあっても全ての終了処理を行い、最初のエラーを返します。
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        let mut rc = self.di.close();
+        for layer in self.layers.iter_mut() {
+            let e = layer.unload();
+            if rc.is_ok() {
+                rc = e;
+            }
+        }
+        rc
+    }
+}
+
+/// `Shiori3`の実装`T`を箱に入れずに呼び出す関数です。
+/// `T`が`Send`でなくても`RawShiori3<T>`を利用できます。
+struct Direct<T> {
+    load: fn(usize, &Path, &[u8], &Services) -> Result<T, anyhow::Error>,
+    handle: for<'a> fn(&mut T, &'a str) -> Result<Cow<'a, str>, anyhow::Error>,
+    close: fn(&mut T) -> Result<(), anyhow::Error>,
+}
+
+impl<T> Clone for Direct<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Direct<T> {}
+
+impl<T: Shiori3> Direct<T> {
+    fn new() -> Self {
+        fn handle<'a, T: Shiori3>(
+            shiori: &mut T,
+            req: &'a str,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            shiori.request(req)
+        }
+        Direct {
+            load: T::load_with_services,
+            handle: handle::<T>,
+            close: T::unload,
+        }
+    }
+}
+
+/// SHIORIの作成方法です。
+enum Loader<T> {
+    Factory(Box<dyn ShioriFactory>),
+    Direct(Direct<T>),
+}
+
+/// ロード済みのSHIORIです。
+enum Instance<T> {
+    Boxed(Box<dyn ShioriHandler>),
+    Direct(T, Direct<T>),
+}
+
+impl<T> Instance<T> {
+    fn handle_bytes<'a>(
+        &mut self,
+        req: &'a [u8],
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        match self {
+            Instance::Boxed(shiori) => shiori.handle_bytes(req, ctx),
+            Instance::Direct(shiori, direct) => {
+                Ok(into_bytes((direct.handle)(shiori, str::from_utf8(req)?)?))
+            }
+        }
+    }
+
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        match self {
+            Instance::Boxed(shiori) => shiori.close(),
+            Instance::Direct(shiori, direct) => (direct.close)(shiori),
+        }
     }
 }
 
 /// SHIORI DLL API
+/// `RawShiori3<T>`は`Shiori3`の実装`T`を、
+/// `RawShiori3::with_factory()`は任意の`ShioriFactory`を利用します。
+/// `RawShiori3<T>`は`T`をそのまま保持するので、`T`が`Send`なら`Send`です。
 #[allow(dead_code)]
-#[derive(Default)]
-pub struct RawShiori3<T>
-where
-    T: Shiori3,
-{
+pub struct RawShiori3<T = ()> {
     h_inst: usize,
-    shiori: Option<Shiori3DI<T>>,
+    load_dir: PathBuf,
+    sequence: u64,
+    booted: bool,
+    services: Services,
+    loaded: Services,
+    loader: Loader<T>,
+    shiori: Option<Instance<T>>,
+    linter: Linter,
+    trace: Option<TraceLogger>,
+    crash: Option<CrashRecorder>,
+}
+
+impl<T: Shiori3> Default for RawShiori3<T> {
+    fn default() -> Self {
+        RawShiori3::with_loader(Loader::Direct(Direct::new()))
+    }
+}
+
+impl RawShiori3 {
+    /// ShioriFactoryでSHIORIハンドラを作成するRawShiori3を作成します。
+    #[allow(dead_code)]
+    pub fn with_factory<F: ShioriFactory + 'static>(factory: F) -> RawShiori3 {
+        RawShiori3::with_loader(Loader::Factory(Box::new(factory)))
+    }
 }
 
 #[allow(dead_code)]
@@ -68,7 +503,23 @@ const DLL_THREAD_ATTACH: u32 = 2;
 #[allow(dead_code)]
 const DLL_THREAD_DETACH: u32 = 3;
 
-impl<T: Shiori3> RawShiori3<T> {
+impl<T> RawShiori3<T> {
+    fn with_loader(loader: Loader<T>) -> Self {
+        RawShiori3 {
+            h_inst: 0,
+            load_dir: PathBuf::new(),
+            sequence: 0,
+            booted: false,
+            services: Services::new(),
+            loaded: Services::new(),
+            loader,
+            shiori: None,
+            linter: Default::default(),
+            trace: None,
+            crash: None,
+        }
+    }
+
     /// shiori.dll:dllmain
     #[allow(dead_code)]
     pub fn raw_dllmain(
@@ -87,11 +538,90 @@ impl<T: Shiori3> RawShiori3<T> {
         }
     }
 
+    /// デバッグビルドでレスポンスの検査に使うリンターを設定します。
+    #[allow(dead_code)]
+    pub fn set_linter(&mut self, linter: Linter) {
+        self.linter = linter;
+    }
+
+    /// ロード時に渡すサービスを設定します。
+    /// ファイルシステムが未設定なら、ロードディレクトリをルートとします。
+    #[allow(dead_code)]
+    pub fn set_services(&mut self, services: Services) {
+        self.services = services;
+    }
+
+    /// ロード時に渡す時計を設定します。
+    #[allow(dead_code)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.services = self.services.clone().with_clock(clock);
+    }
+
+    /// ロード時に渡す乱数を設定します。
+    #[allow(dead_code)]
+    pub fn set_random(&mut self, random: Arc<Mutex<dyn RandomSource>>) {
+        self.services = self.services.clone().with_shared_random(random);
+    }
+
+    /// ロード時に渡す永続化ストアを設定します。
+    /// ストアはロード時に読み込み、リクエスト毎に自動保存し、unload時に保存します。
+    /// 相対パスのストアはロード時のファイルシステムに保存します。
+    #[allow(dead_code)]
+    pub fn set_store(&mut self, store: Arc<Mutex<Store>>) {
+        self.services = self.services.clone().with_store(store);
+    }
+
+    /// リクエストと応答をlogfile.txt形式で記録するロガーを設定します。
+    /// 相対パスはロード時にロードディレクトリからのパスになります。
+    #[allow(dead_code)]
+    pub fn set_trace(&mut self, trace: TraceLogger) {
+        self.trace = Some(trace);
+    }
+
+    /// トレースの記録の有効・無効を切り替えます。
+    #[allow(dead_code)]
+    pub fn set_trace_enabled(&mut self, enabled: bool) {
+        if let Some(trace) = &self.trace {
+            trace.switch().set(enabled);
+        }
+    }
+
+    /// 直近のリクエストを保持し、異常時にダンプするレコーダーを設定します。
+    /// 相対パスはロード時にロードディレクトリからのパスになります。
+    #[allow(dead_code)]
+    pub fn set_crash_recorder(&mut self, crash: CrashRecorder) {
+        self.crash = Some(crash);
+    }
+
+    /// 次のリクエストのコンテキストを作成します。
+    fn next_context(&mut self) -> RequestContext {
+        self.sequence += 1;
+        RequestContext::new(self.sequence, self.loaded.clock().now())
+            .with_instance(self.h_inst, &self.load_dir)
+            .with_booted(self.booted)
+            .with_services(self.loaded.clone())
+    }
+
     /// shiori.dll:unload
     #[allow(dead_code)]
     pub fn raw_unload(&mut self) -> bool {
-        self.shiori = None;
-        true
+        if self.shiori.is_some() {
+            self.flush_store();
+        }
+        let rc = match self.shiori.as_mut().map(|shiori| shiori.close()) {
+            Some(Err(e)) => {
+                error!("[unload] {}", e);
+                false
+            }
+            _ => true,
+        };
+        // 終了処理での変更も保存します。
+        if self.shiori.take().is_some() {
+            self.flush_store();
+        }
+        self.sequence = 0;
+        self.booted = false;
+        rc
     }
 
     /// shiori.dll:load
@@ -110,7 +640,33 @@ impl<T: Shiori3> RawShiori3<T> {
         let gdir = ShioriString::capture(hdir, len);
         let load_dir = gdir.to_ansi_str()?;
         let load_dir_bytes = gdir.as_bytes();
-        let shiori = Shiori3DI::<T>::load(self.h_inst, load_dir, load_dir_bytes)?;
+        let load_dir = PathBuf::from(load_dir);
+        let services = self.services.clone().or_fs_root(&load_dir);
+        if let (Some(store), Some(fs)) = (services.store(), services.fs()) {
+            let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+            store.resolve(fs);
+            store.load()?;
+        }
+        let shiori = match &self.loader {
+            Loader::Factory(factory) => Instance::Boxed(factory.load_with_services(
+                self.h_inst,
+                &load_dir,
+                load_dir_bytes,
+                &services,
+            )?),
+            Loader::Direct(direct) => {
+                let shiori = (direct.load)(self.h_inst, &load_dir, load_dir_bytes, &services)?;
+                Instance::Direct(shiori, *direct)
+            }
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace.resolve(&load_dir);
+        }
+        if let Some(crash) = self.crash.as_mut() {
+            crash.resolve(&load_dir);
+        }
+        self.load_dir = load_dir;
+        self.loaded = services;
         self.shiori = Some(shiori);
         Ok(())
     }
@@ -136,13 +692,409 @@ impl<T: Shiori3> RawShiori3<T> {
         len: usize,
     ) -> Result<(HGLOBAL, usize), anyhow::Error> {
         let greq = ShioriString::capture(hreq, len);
-        let req = greq.to_utf8_str()?;
+        let bytes = greq.as_bytes();
+        // バイト列はそのままハンドラに渡し、文字列は記録と検査にのみ使います。
+        let req = decode_lossy(bytes);
+        let mut ctx = self.next_context();
+        // 未ロードもハンドラのエラーと同じく記録します。
+        let res = match self.shiori.as_mut() {
+            Some(shiori) => {
+                panic::catch_unwind(AssertUnwindSafe(|| shiori.handle_bytes(bytes, &mut ctx)))
+                    .unwrap_or_else(|payload| {
+                        Err(anyhow::anyhow!("panic: {}", panic_message(&payload)))
+                    })
+            }
+            None => Err(MyError::NotInitialized.into()),
+        };
+        self.autosave_store();
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                if let Some(trace) = &self.trace {
+                    trace.record(&req, None);
+                }
+                self.record_crash(&req, None, Some(&e));
+                return Err(e);
+            }
+        };
+        if is_boot_event(&req) {
+            self.booted = true;
+        }
+        // 検査で置き換えた応答だけをUTF-8で返します。
+        #[cfg(debug_assertions)]
         let res = {
-            let shiori = self.shiori.as_mut().ok_or(MyError::NotInitialized)?;
-            shiori.request(req)?
+            let text = decode_lossy(&res);
+            let linted = match self.linter.filter_response(&req, Cow::Borrowed(&text)) {
+                Cow::Owned(linted) => Some(linted.into_bytes()),
+                Cow::Borrowed(_) => None,
+            };
+            linted.map_or(res, Cow::Owned)
         };
-        let res_bytes = res.as_bytes();
-        let gres = ShioriString::clone_from_slice_nofree(res_bytes);
+        let text = decode_lossy(&res);
+        if let Some(trace) = &self.trace {
+            trace.record(&req, Some(&text));
+        }
+        self.record_crash(&req, Some(&text), None);
+        let gres = ShioriString::clone_from_slice_nofree(&res);
         Ok(gres.value())
     }
+
+    /// 永続化ストアの自動保存の間隔が経過していれば保存します。
+    fn autosave_store(&self) {
+        if let Some(store) = self.loaded.store()
+            && let Err(e) = store.lock().unwrap_or_else(|e| e.into_inner()).autosave()
+        {
+            warn!("[store] {}", e);
+        }
+    }
+
+    /// 永続化ストアの未保存の変更を保存します。
+    fn flush_store(&self) {
+        if let Some(store) = self.loaded.store()
+            && let Err(e) = store.lock().unwrap_or_else(|e| e.into_inner()).flush()
+        {
+            error!("[store] {}", e);
+        }
+    }
+
+    /// クラッシュレコーダーに記録し、エラーか`500`の応答ならダンプします。
+    fn record_crash(&mut self, req: &str, res: Option<&str>, error: Option<&anyhow::Error>) {
+        let Some(crash) = self.crash.as_mut() else {
+            return;
+        };
+        crash.push(req, res);
+        let internal;
+        let error = match error {
+            Some(e) => e,
+            None if res.is_some_and(is_internal_server_error) => {
+                internal = anyhow::anyhow!("{}", Status::InternalServerError);
+                &internal
+            }
+            None => return,
+        };
+        match crash.dump(self.loaded.clock().now(), error) {
+            Ok(path) => warn!("[crash] dump to {}", path.display()),
+            Err(e) => warn!("[crash] {}", e),
+        }
+    }
+}
+
+/// 応答のステータスが`500`かどうかを返します。
+fn is_internal_server_error(res: &str) -> bool {
+    res.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .is_some_and(|code| code == "500")
+}
+
+/// panicのペイロードからメッセージを取り出します。
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::fs::{FileSystem, MemoryFileSystem};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn ctx() -> RequestContext {
+        RequestContext::new(1, UNIX_EPOCH)
+    }
+
+    struct Echo(String);
+
+    impl Shiori3 for Echo {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            Ok(Echo(load_dir.as_ref().to_string_lossy().into_owned()))
+        }
+
+        fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(Cow::Owned(format!("{}:{}", self.0, req.into())))
+        }
+    }
+
+    struct Fixed(&'static str);
+
+    impl ShioriHandler for Fixed {
+        fn handle<'a>(
+            &mut self,
+            _req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(Cow::Borrowed(self.0))
+        }
+    }
+
+    struct Hello;
+
+    impl ShioriService for Hello {
+        fn request(
+            &mut self,
+            req: &ShioriRequest,
+            ctx: &mut RequestContext,
+        ) -> Result<ShioriResponse, anyhow::Error> {
+            ctx.extensions.insert(req.id.map(|id| id.to_string()));
+            match req.id {
+                Some("OnBoot") => Ok(ShioriResponse::ok(format!("\\0hello{}\\e", ctx.sequence))),
+                Some("OnError") => Err(anyhow::anyhow!("error")),
+                _ => Ok(ShioriResponse::no_content()),
+            }
+        }
+    }
+
+    #[test]
+    fn service_handler() {
+        let mut shiori = ServiceHandler::boxed(Hello);
+        let res = shiori
+            .handle(
+                "GET SHIORI/3.0\r\nCharset: Shift_JIS\r\nID: OnBoot\r\n\r\n",
+                &mut ctx(),
+            )
+            .unwrap();
+        assert_eq!(
+            res,
+            "SHIORI/3.0 200 OK\r\nCharset: Shift_JIS\r\nValue: \\0hello1\\e\r\n\r\n"
+        );
+        let res = shiori
+            .handle("GET SHIORI/3.0\r\nID: OnSecondChange\r\n\r\n", &mut ctx())
+            .unwrap();
+        assert_eq!(res, "SHIORI/3.0 204 No Content\r\nCharset: UTF-8\r\n\r\n");
+        let res = shiori
+            .handle("GET SHIORI/3.0\r\nID: OnError\r\n\r\n", &mut ctx())
+            .unwrap();
+        assert!(res.starts_with("SHIORI/3.0 500 Internal Server Error\r\n"));
+        let res = shiori.handle("BAD REQUEST\r\n\r\n", &mut ctx()).unwrap();
+        assert!(res.starts_with("SHIORI/3.0 400 Bad Request\r\n"));
+        let req = b"GET SHIORI/3.0\r\nCharset: X-Unknown\r\nID: OnBoot\r\n\r\n";
+        let res = shiori.handle_bytes(req, &mut ctx()).unwrap();
+        assert!(res.starts_with(b"SHIORI/3.0 400 Bad Request\r\n"));
+
+        let mut ctx = ctx();
+        shiori
+            .handle("GET SHIORI/3.0\r\nID: OnClose\r\n\r\n", &mut ctx)
+            .unwrap();
+        assert_eq!(
+            ctx.extensions.get::<Option<String>>(),
+            Some(&Some("OnClose".to_string()))
+        );
+    }
+
+    #[test]
+    fn factory_shiori3() {
+        let factory = Shiori3Factory::<Echo>::new();
+        let mut shiori = factory.load(0, Path::new("dir"), b"dir").unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "dir:req");
+        assert!(shiori.close().is_ok());
+    }
+
+    struct Suffix;
+
+    impl Middleware for Suffix {
+        fn call<'a>(
+            &mut self,
+            req: &'a str,
+            ctx: &mut RequestContext,
+            next: Next<'_>,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            let res = next.run(req, ctx)?;
+            Ok(Cow::Owned(format!("{}!", res)))
+        }
+    }
+
+    #[test]
+    fn factory_layer() {
+        let factory = Shiori3Factory::<Echo>::new().with_layer(|| Suffix);
+        let mut shiori = factory.load(0, Path::new("dir"), b"dir").unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "dir:req!");
+        let mut shiori = factory.load(0, Path::new("dir2"), b"dir2").unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "dir2:req!");
+    }
+
+    struct Greeting(String);
+
+    impl Shiori3 for Greeting {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            _load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            Ok(Greeting("default".into()))
+        }
+
+        fn load_with_services(
+            _h_inst: usize,
+            _load_dir: &Path,
+            _load_dir_bytes: &[u8],
+            services: &Services,
+        ) -> Result<Self, anyhow::Error> {
+            let fs = services.fs().ok_or(MyError::Load)?;
+            Ok(Greeting(fs.read_to_string(Path::new("greeting.txt"))?))
+        }
+
+        fn request<'a, S: Into<&'a str>>(
+            &mut self,
+            _req: S,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(Cow::Owned(self.0.clone()))
+        }
+    }
+
+    #[test]
+    fn factory_services() {
+        let fs = MemoryFileSystem::new("dir").with_file("greeting.txt", "hello");
+        let services = Services::new().with_fs(Arc::new(fs));
+        let factory = Shiori3Factory::<Greeting>::new();
+        let mut shiori = factory
+            .load_with_services(0, Path::new("dir"), b"dir", &services)
+            .unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "hello");
+        assert!(
+            factory
+                .load_with_services(0, Path::new("dir"), b"dir", &Services::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn factory_dyn() {
+        let factory = |_h_inst: usize, load_dir: &Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = if load_dir.ends_with("echo") {
+                Box::new(Echo::load(0, load_dir, &[])?)
+            } else {
+                Box::new(Fixed("fixed"))
+            };
+            Ok(shiori)
+        };
+        let factories: Vec<Box<dyn ShioriFactory>> = vec![Box::new(factory)];
+        let mut shiori = factories[0].load(0, Path::new("echo"), b"echo").unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "echo:req");
+        let mut shiori = factories[0].load(0, Path::new("other"), b"other").unwrap();
+        assert_eq!(shiori.handle("req", &mut ctx()).unwrap(), "fixed");
+    }
+
+    struct Counter;
+
+    impl ShioriHandler for Counter {
+        fn handle<'a>(
+            &mut self,
+            _req: &'a str,
+            ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            let mut store = ctx.store().ok_or(MyError::Load)?.lock().unwrap();
+            let count = store.get_or("count", 0) + 1;
+            store.set("count", count);
+            Ok(Cow::Owned(format!("{}", count)))
+        }
+    }
+
+    fn raw_call_bytes<T>(shiori: &mut RawShiori3<T>, req: &[u8]) -> Vec<u8> {
+        let (h, mut len) = ShioriString::clone_from_slice_nofree(req).value();
+        let h = shiori.raw_request(h, &mut len);
+        ShioriString::capture(h, len).as_bytes().to_vec()
+    }
+
+    fn raw_call<T>(shiori: &mut RawShiori3<T>, req: &str) -> String {
+        let res = raw_call_bytes(shiori, &encode_charset(req).unwrap());
+        decode_charset(&res).unwrap().into_owned()
+    }
+
+    #[test]
+    fn raw_store() {
+        let mem = Arc::new(
+            MemoryFileSystem::new("ghost")
+                .with_file("store.dat", "#shiori3-store 1\ncount\ti\t10\n"),
+        );
+        let clock = Arc::new(VirtualClock::new(UNIX_EPOCH));
+        let store = Arc::new(Mutex::new(
+            Store::new("store.dat", 1)
+                .with_clock(clock.clone())
+                .with_autosave(Duration::from_secs(60)),
+        ));
+        let mut shiori = RawShiori3::with_factory(|_: usize, _: &Path, _: &[u8]| {
+            Ok(Box::new(Counter) as Box<dyn ShioriHandler>)
+        });
+        shiori.set_services(Services::new().with_fs(mem.clone()));
+        shiori.set_store(store.clone());
+        let (h, len) = ShioriString::clone_from_slice_nofree(b"ghost").value();
+        assert!(shiori.raw_load(h, len));
+
+        let req = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnTest\r\n\r\n";
+        let saved = || mem.read_to_string(Path::new("store.dat")).unwrap();
+        assert_eq!(raw_call(&mut shiori, req), "11");
+        assert_eq!(saved(), "#shiori3-store 1\ncount\ti\t10\n");
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(raw_call(&mut shiori, req), "12");
+        assert_eq!(saved(), "#shiori3-store 1\ncount\ti\t12\n");
+        assert_eq!(raw_call(&mut shiori, req), "13");
+        assert!(shiori.raw_unload());
+        assert_eq!(saved(), "#shiori3-store 1\ncount\ti\t13\n");
+
+        // 別のディレクトリからロードし直すと、そのディレクトリのファイルを使います。
+        let other = Arc::new(
+            MemoryFileSystem::new("other")
+                .with_file("store.dat", "#shiori3-store 1\ncount\ti\t100\n"),
+        );
+        shiori.set_services(Services::new().with_fs(other.clone()));
+        shiori.set_store(store);
+        let (h, len) = ShioriString::clone_from_slice_nofree(b"other").value();
+        assert!(shiori.raw_load(h, len));
+        assert_eq!(raw_call(&mut shiori, req), "101");
+        assert!(shiori.raw_unload());
+        let text = other.read_to_string(Path::new("store.dat")).unwrap();
+        assert_eq!(text, "#shiori3-store 1\ncount\ti\t101\n");
+        assert_eq!(saved(), "#shiori3-store 1\ncount\ti\t13\n");
+    }
+
+    struct Local(std::rc::Rc<String>);
+
+    impl Shiori3 for Local {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            _load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            Ok(Local(std::rc::Rc::new("local".into())))
+        }
+
+        fn request<'a, S: Into<&'a str>>(
+            &mut self,
+            _req: S,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(Cow::Owned(self.0.to_string()))
+        }
+    }
+
+    #[test]
+    fn raw_not_send() {
+        let mut shiori = RawShiori3::<Local>::default();
+        let (h, len) = ShioriString::clone_from_slice_nofree(b"ghost").value();
+        assert!(shiori.raw_load(h, len));
+        let req = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnTest\r\n\r\n";
+        assert_eq!(raw_call(&mut shiori, req), "local");
+        assert!(shiori.raw_unload());
+    }
+
+    #[test]
+    fn raw_bytes() {
+        const RES: &str = "SHIORI/3.0 200 OK\r\nCharset: X-Unknown\r\nValue: \\0\\e\r\n\r\n";
+        let mut shiori = RawShiori3::with_factory(|_: usize, _: &Path, _: &[u8]| {
+            Ok(Box::new(Fixed(RES)) as Box<dyn ShioriHandler>)
+        });
+        let (h, len) = ShioriString::clone_from_slice_nofree(b"ghost").value();
+        assert!(shiori.raw_load(h, len));
+        let req = b"GET SHIORI/3.0\r\nCharset: X-Unknown\r\nID: OnTest\r\n\r\n";
+        assert_eq!(raw_call_bytes(&mut shiori, req), RES.as_bytes());
+    }
 }
@@ -0,0 +1,49 @@
+use shiori3::script::ScriptTree;
+
+/// 応答を読みやすく整形します。
+/// ヘッダ名をそろえ、Valueにはタグを除いた本文を添えます。
+pub fn pretty(res: &str) -> String {
+    let mut lines = res.lines();
+    let mut rc = match lines.next() {
+        Some(status) => format!("{}\n", status),
+        None => return String::new(),
+    };
+    let headers: Vec<(&str, &str)> = lines
+        .take_while(|line| !line.is_empty())
+        .map(|line| line.split_once(':').unwrap_or((line, "")))
+        .map(|(k, v)| (k, v.trim_start()))
+        .collect();
+    let width = headers.iter().map(|(k, _)| k.chars().count()).max();
+    for (k, v) in &headers {
+        let width = width.unwrap_or(0);
+        rc.push_str(&format!("  {:width$} : {}\n", k, v, width = width));
+        if *k == "Value"
+            && let Ok(tree) = ScriptTree::parse(v)
+        {
+            let text = tree.strip_tags();
+            if !text.is_empty() && text != *v {
+                rc.push_str(&format!("  {:width$} > {}\n", "", text, width = width));
+            }
+        }
+    }
+    rc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pretty_response() {
+        let res = "SHIORI/3.0 200 OK\r\nCharset: UTF-8\r\nValue: \\0\\s[0]hello\\e\r\n\r\n";
+        assert_eq!(
+            pretty(res),
+            "SHIORI/3.0 200 OK\n  Charset : UTF-8\n  Value   : \\0\\s[0]hello\\e\n          > hello\n"
+        );
+        assert_eq!(
+            pretty("SHIORI/3.0 204 No Content\r\n\r\n"),
+            "SHIORI/3.0 204 No Content\n"
+        );
+        assert_eq!(pretty(""), "");
+    }
+}
@@ -0,0 +1,34 @@
+use anyhow::Context;
+use shiori3::ShioriClient;
+use std::path::Path;
+
+/// SHIORI共有ライブラリのload/unload/requestを呼び出します。
+/// 呼び出しは`ShioriClient`が行い、drop時にロード済みであればunloadします。
+pub struct Library {
+    client: ShioriClient,
+}
+
+impl Library {
+    /// 共有ライブラリを開き、load/unload/requestがエクスポートされているか確認します。
+    pub fn open(path: &Path) -> anyhow::Result<Library> {
+        let client = ShioriClient::open(path)?;
+        Ok(Library { client })
+    }
+
+    /// loadを呼び出します。
+    pub fn load(&mut self, dir: &Path) -> anyhow::Result<()> {
+        self.client
+            .raw_load(dir)
+            .with_context(|| format!("load failed: {}", dir.display()))
+    }
+
+    /// requestを呼び出し、応答を返します。SHIORIがNULLを返した場合は`None`です。
+    pub fn request(&mut self, text: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.client.request_text(text)?)
+    }
+
+    /// unloadを呼び出します。
+    pub fn unload(&mut self) -> bool {
+        self.client.unload()
+    }
+}
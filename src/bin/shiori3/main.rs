@@ -0,0 +1,252 @@
+//! SHIORI共有ライブラリを読み込み、リクエストを送るコマンドラインツールです。
+
+mod format;
+mod library;
+mod repl;
+mod template;
+
+use crate::library::Library;
+use anyhow::{Context, bail};
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: shiori3 [OPTIONS] <LIBRARY> [REQUEST]...
+
+SHIORI共有ライブラリをloadし、リクエストを送って応答を表示します。
+終了時にunloadを呼び出します。REQUESTは生のリクエスト文字列で、
+`\\n`は改行になります。リクエストを指定せず標準入力が端末ならREPLになります。
+
+options:
+  -d, --dir <DIR>       loadに渡すディレクトリ (既定: LIBRARYのディレクトリ)
+  -f, --file <FILE>     空行区切りのリクエストをファイルから送る (`-`で標準入力)
+  -g, --get <ID>        GET SHIORI/3.0 リクエストを送る
+  -n, --notify <ID>     NOTIFY SHIORI/3.0 リクエストを送る
+  -r, --ref <VALUE>     直前の--get/--notifyにReferenceを追加する
+  -s, --sender <NAME>   Senderヘッダの値 (既定: shiori3)
+      --raw             応答を整形せずに表示する
+  -i, --interactive     リクエストを送った後にREPLを開始する
+  -h, --help            このヘルプを表示する
+";
+
+/// リクエストの送り元です。
+#[derive(PartialEq, Eq, Debug)]
+enum Source {
+    Text(String),
+    File(PathBuf),
+    Stdin,
+    Event {
+        method: &'static str,
+        id: String,
+        refs: Vec<String>,
+    },
+}
+
+/// コマンドライン引数です。
+#[derive(PartialEq, Eq, Debug)]
+struct Options {
+    library: PathBuf,
+    dir: Option<PathBuf>,
+    sources: Vec<Source>,
+    sender: String,
+    raw: bool,
+    interactive: bool,
+}
+
+/// 引数を解析します。ヘルプの場合は`None`を返します。
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Option<Options>> {
+    let mut args = args.into_iter();
+    let mut library = None;
+    let mut dir = None;
+    let mut sources = Vec::new();
+    let mut sender = "shiori3".to_string();
+    let mut raw = false;
+    let mut interactive = false;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .with_context(|| format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-d" | "--dir" => dir = Some(PathBuf::from(value(&arg)?)),
+            "-f" | "--file" => match value(&arg)?.as_str() {
+                "-" => sources.push(Source::Stdin),
+                path => sources.push(Source::File(path.into())),
+            },
+            "-g" | "--get" => sources.push(Source::Event {
+                method: "GET",
+                id: value(&arg)?,
+                refs: Vec::new(),
+            }),
+            "-n" | "--notify" => sources.push(Source::Event {
+                method: "NOTIFY",
+                id: value(&arg)?,
+                refs: Vec::new(),
+            }),
+            "-r" | "--ref" => {
+                let v = value(&arg)?;
+                match sources.last_mut() {
+                    Some(Source::Event { refs, .. }) => refs.push(v),
+                    _ => bail!("{} must follow --get or --notify", arg),
+                }
+            }
+            "-s" | "--sender" => sender = value(&arg)?,
+            "--raw" => raw = true,
+            "-i" | "--interactive" => interactive = true,
+            s if s.starts_with('-') && s != "-" => bail!("unknown option: {}", s),
+            _ if library.is_none() => library = Some(PathBuf::from(arg)),
+            _ => sources.push(Source::Text(arg)),
+        }
+    }
+    let library = library.context("missing <LIBRARY>")?;
+    Ok(Some(Options {
+        library,
+        dir,
+        sources,
+        sender,
+        raw,
+        interactive,
+    }))
+}
+
+/// 読み込んだSHIORIと表示の設定です。
+pub struct Session {
+    library: Library,
+    pub sender: String,
+    pub raw: bool,
+}
+
+impl Session {
+    /// リクエストを送り、応答を表示します。
+    pub fn send(&mut self, req: &str) -> anyhow::Result<()> {
+        match self.library.request(req)? {
+            Some(res) if self.raw => print!("{}", res),
+            Some(res) => print!("{}", format::pretty(&res)),
+            None => eprintln!("(no response)"),
+        }
+        Ok(())
+    }
+
+    fn send_source(&mut self, source: &Source) -> anyhow::Result<()> {
+        let requests = match source {
+            Source::Text(text) => vec![template::normalize(text)],
+            Source::File(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                template::split_requests(&text)
+            }
+            Source::Stdin => {
+                let mut text = String::new();
+                io::stdin().read_to_string(&mut text)?;
+                template::split_requests(&text)
+            }
+            Source::Event { method, id, refs } => {
+                let refs: Vec<&str> = refs.iter().map(|r| r.as_str()).collect();
+                vec![template::build(method, id, &refs, &self.sender)]
+            }
+        };
+        for req in requests {
+            self.send(&req)?;
+        }
+        Ok(())
+    }
+}
+
+fn run(opts: Options) -> anyhow::Result<()> {
+    let mut library = Library::open(&opts.library)?;
+    let dir = match opts.dir {
+        Some(dir) => dir,
+        None => opts
+            .library
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default(),
+    };
+    let dir = dir.canonicalize().unwrap_or(dir);
+    library.load(&dir)?;
+    let mut session = Session {
+        library,
+        sender: opts.sender,
+        raw: opts.raw,
+    };
+    let mut sources = opts.sources;
+    let interactive = opts.interactive || (sources.is_empty() && io::stdin().is_terminal());
+    if sources.is_empty() && !interactive {
+        sources.push(Source::Stdin);
+    }
+    for source in &sources {
+        session.send_source(source)?;
+    }
+    if interactive {
+        repl::run(&mut session)?;
+    }
+    if !session.library.unload() {
+        bail!("unload failed");
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(opts) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_options() {
+        let opts = parse_args(args(
+            "-d ghost/master --raw libghost.so -g OnBoot -r master -f - GET",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(opts.library, PathBuf::from("libghost.so"));
+        assert_eq!(opts.dir, Some(PathBuf::from("ghost/master")));
+        assert!(opts.raw);
+        assert!(!opts.interactive);
+        assert_eq!(opts.sender, "shiori3");
+        assert_eq!(
+            opts.sources,
+            vec![
+                Source::Event {
+                    method: "GET",
+                    id: "OnBoot".into(),
+                    refs: vec!["master".into()],
+                },
+                Source::Stdin,
+                Source::Text("GET".into()),
+            ]
+        );
+
+        assert_eq!(parse_args(args("-h")).unwrap(), None);
+        assert!(parse_args(args("")).is_err());
+        assert!(parse_args(args("lib.so -r x")).is_err());
+        assert!(parse_args(args("lib.so -d")).is_err());
+        assert!(parse_args(args("lib.so --unknown")).is_err());
+    }
+}
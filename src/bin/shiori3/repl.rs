@@ -0,0 +1,156 @@
+use crate::Session;
+use crate::template::{self, TEMPLATES};
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::env;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+  get <ID> [REF]...       GET SHIORI/3.0 リクエストを送る
+  notify <ID> [REF]...    NOTIFY SHIORI/3.0 リクエストを送る
+  :<NAME> [REF]...        テンプレートを送る (REFで既定のReferenceを上書き)
+  :list                   テンプレートの一覧
+  raw                     空行まで入力した生のリクエストを送る
+  format raw|pretty       応答の表示形式を切り替える
+  help                    このヘルプ
+  quit                    終了してunloadする
+";
+
+/// REPLで入力した一行の解釈です。
+#[derive(PartialEq, Eq, Debug)]
+enum Command {
+    Send(String),
+    Raw,
+    List,
+    Format(bool),
+    Help,
+    Quit,
+    Empty,
+    Error(String),
+}
+
+fn parse_command(line: &str, sender: &str) -> Command {
+    let words = template::split_words(line);
+    let Some((head, rest)) = words.split_first() else {
+        return Command::Empty;
+    };
+    let refs: Vec<&str> = rest.iter().map(|r| r.as_str()).collect();
+    match head.as_str() {
+        "get" | "notify" => match refs.split_first() {
+            Some((id, refs)) => {
+                let method = if head == "get" { "GET" } else { "NOTIFY" };
+                Command::Send(template::build(method, id, refs, sender))
+            }
+            None => Command::Error(format!("usage: {} <ID> [REF]...", head)),
+        },
+        ":list" => Command::List,
+        name if name.starts_with(':') => {
+            match template::build_template(&name[1..], &refs, sender) {
+                Some(req) => Command::Send(req),
+                None => Command::Error(format!("unknown template: {}", name)),
+            }
+        }
+        "raw" => Command::Raw,
+        "format" => match refs.as_slice() {
+            ["raw"] => Command::Format(true),
+            ["pretty"] => Command::Format(false),
+            _ => Command::Error("usage: format raw|pretty".into()),
+        },
+        "help" | "?" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        _ => Command::Error(format!("unknown command: {} (try help)", head)),
+    }
+}
+
+/// 履歴ファイルのパスです。`$HOME/.shiori3_history`を使います。
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".shiori3_history"))
+}
+
+/// 対話モードを実行します。quitかEOFで戻ります。
+pub fn run(session: &mut Session) -> anyhow::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline("shiori3> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        let rc = match parse_command(&line, &session.sender) {
+            Command::Send(req) => session.send(&req),
+            Command::Raw => read_raw(&mut editor).and_then(|req| session.send(&req)),
+            Command::List => {
+                for (name, method, id, refs) in TEMPLATES {
+                    println!("  :{:12} {} {} {:?}", name, method, id, refs);
+                }
+                Ok(())
+            }
+            Command::Format(raw) => {
+                session.raw = raw;
+                Ok(())
+            }
+            Command::Help => {
+                print!("{}", HELP);
+                Ok(())
+            }
+            Command::Quit => break,
+            Command::Empty => Ok(()),
+            Command::Error(message) => {
+                eprintln!("{}", message);
+                Ok(())
+            }
+        };
+        if let Err(e) = rc {
+            eprintln!("error: {:#}", e);
+        }
+    }
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+/// 空行まで読み込み、生のリクエストにします。
+fn read_raw(editor: &mut DefaultEditor) -> anyhow::Result<String> {
+    let mut text = String::new();
+    loop {
+        let line = editor.readline("     ... ")?;
+        if line.is_empty() {
+            break;
+        }
+        text.push_str(&line);
+        text.push('\n');
+    }
+    Ok(template::normalize(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command() {
+        assert_eq!(
+            parse_command("get OnBoot \"my shell\"", "x"),
+            Command::Send(template::build("GET", "OnBoot", &["my shell"], "x"))
+        );
+        assert_eq!(
+            parse_command(":boot", "x"),
+            Command::Send(template::build("GET", "OnBoot", &["master"], "x"))
+        );
+        assert_eq!(parse_command("format raw", "x"), Command::Format(true));
+        assert_eq!(parse_command("  ", "x"), Command::Empty);
+        assert_eq!(parse_command("quit", "x"), Command::Quit);
+        assert!(matches!(parse_command("get", "x"), Command::Error(_)));
+        assert!(matches!(parse_command(":nothing", "x"), Command::Error(_)));
+    }
+}
@@ -0,0 +1,158 @@
+/// REPLの`:名前`で送るよく使うイベントのテンプレートです。
+/// (名前, メソッド, ID, 既定のReference)
+pub const TEMPLATES: &[(&str, &str, &str, &[&str])] = &[
+    ("version", "GET", "version", &[]),
+    ("name", "GET", "name", &[]),
+    ("initialize", "NOTIFY", "OnInitialize", &[""]),
+    ("boot", "GET", "OnBoot", &["master"]),
+    ("firstboot", "GET", "OnFirstBoot", &["0"]),
+    ("close", "GET", "OnClose", &["user"]),
+    (
+        "second",
+        "GET",
+        "OnSecondChange",
+        &["0", "0", "0", "1", "0"],
+    ),
+    (
+        "minute",
+        "GET",
+        "OnMinuteChange",
+        &["0", "0", "0", "1", "0"],
+    ),
+    (
+        "click",
+        "GET",
+        "OnMouseDoubleClick",
+        &["0", "0", "0", "0", "", "0"],
+    ),
+    ("ai", "GET", "OnAITalk", &[]),
+];
+
+/// `SHIORI/3.0`リクエストを組み立てます。
+pub fn build(method: &str, id: &str, refs: &[&str], sender: &str) -> String {
+    let mut text = format!(
+        "{} SHIORI/3.0\r\nCharset: UTF-8\r\nSender: {}\r\nSecurityLevel: local\r\nID: {}\r\n",
+        method, sender, id
+    );
+    for (i, r) in refs.iter().enumerate() {
+        text.push_str(&format!("Reference{}: {}\r\n", i, r));
+    }
+    text.push_str("\r\n");
+    text
+}
+
+/// テンプレートからリクエストを組み立てます。
+/// refsは既定のReferenceを先頭から上書きします。
+pub fn build_template(name: &str, refs: &[&str], sender: &str) -> Option<String> {
+    let (_, method, id, defaults) = TEMPLATES.iter().find(|(n, ..)| *n == name)?;
+    let mut merged: Vec<&str> = defaults.to_vec();
+    for (i, r) in refs.iter().enumerate() {
+        match merged.get_mut(i) {
+            Some(v) => *v = r,
+            None => merged.push(r),
+        }
+    }
+    Some(build(method, id, &merged, sender))
+}
+
+/// リクエストの文字列を整えます。
+/// `\r\n`や`\n`のエスケープを改行にし、改行をCRLFにそろえ、空行で終わらせます。
+pub fn normalize(text: &str) -> String {
+    let text = text
+        .replace("\\r\\n", "\n")
+        .replace("\\n", "\n")
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    let mut rc: String = text
+        .trim_end_matches('\n')
+        .lines()
+        .map(|line| format!("{}\r\n", line))
+        .collect();
+    rc.push_str("\r\n");
+    rc
+}
+
+/// 空行で区切られた複数のリクエストを分割します。
+pub fn split_requests(text: &str) -> Vec<String> {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    text.split("\n\n")
+        .map(|block| block.trim_matches('\n'))
+        .filter(|block| !block.is_empty())
+        .map(normalize)
+        .collect()
+}
+
+/// 空白で区切り、二重引用符で囲まれた部分は一つの引数にします。
+pub fn split_words(line: &str) -> Vec<String> {
+    let mut rc = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut has_word = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_word {
+                    rc.push(std::mem::take(&mut word));
+                    has_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                has_word = true;
+            }
+        }
+    }
+    if has_word {
+        rc.push(word);
+    }
+    rc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template() {
+        assert_eq!(
+            build_template("boot", &["shell"], "shiori3").unwrap(),
+            "GET SHIORI/3.0\r\nCharset: UTF-8\r\nSender: shiori3\r\nSecurityLevel: local\r\nID: OnBoot\r\nReference0: shell\r\n\r\n"
+        );
+        let req = build_template("version", &["a", "b"], "x").unwrap();
+        assert!(req.ends_with("ID: version\r\nReference0: a\r\nReference1: b\r\n\r\n"));
+        assert_eq!(build_template("unknown", &[], "x"), None);
+    }
+
+    #[test]
+    fn normalize_text() {
+        assert_eq!(
+            normalize("GET SHIORI/3.0\\nID: OnBoot"),
+            "GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n"
+        );
+        assert_eq!(
+            normalize("GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n"),
+            "GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n"
+        );
+        let reqs = split_requests("GET SHIORI/3.0\nID: a\n\n\nGET SHIORI/3.0\r\nID: b\r\n");
+        assert_eq!(
+            reqs,
+            vec![
+                "GET SHIORI/3.0\r\nID: a\r\n\r\n",
+                "GET SHIORI/3.0\r\nID: b\r\n\r\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(
+            split_words(r#"get OnBoot "hello world" "" x"#),
+            vec!["get", "OnBoot", "hello world", "", "x"]
+        );
+        assert!(split_words("   ").is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::error::*;
+use crate::hglobal::{HGLOBAL, ShioriString, decode_charset, encode_charset, load_dir_bytes};
+use crate::parsers::req::ShioriRequest;
+use crate::parsers::res::ShioriResponse;
+use libloading::Library;
+use std::ffi::c_long;
+use std::path::Path;
+
+/// WindowsではBOOL、それ以外ではboolです。
+#[cfg(windows)]
+pub(crate) type Bool = i32;
+#[cfg(not(windows))]
+pub(crate) type Bool = bool;
+
+pub(crate) type LoadFn = unsafe extern "C" fn(HGLOBAL, c_long) -> Bool;
+pub(crate) type UnloadFn = unsafe extern "C" fn() -> Bool;
+pub(crate) type RequestFn = unsafe extern "C" fn(HGLOBAL, *mut c_long) -> HGLOBAL;
+
+#[cfg(windows)]
+fn is_true(b: Bool) -> bool {
+    b != 0
+}
+#[cfg(not(windows))]
+fn is_true(b: Bool) -> bool {
+    b
+}
+
+#[cfg(all(test, windows))]
+pub(crate) fn to_bool(b: bool) -> Bool {
+    b as Bool
+}
+#[cfg(all(test, not(windows)))]
+pub(crate) fn to_bool(b: bool) -> Bool {
+    b
+}
+
+impl From<libloading::Error> for MyError {
+    fn from(error: libloading::Error) -> MyError {
+        MyError::Library(error.to_string())
+    }
+}
+
+/// SHIORI共有ライブラリをホストするクライアントです。
+/// shiori.mdのメモリの所有規則に従い、リクエストはこちらで確保してSHIORIが解放し、
+/// 応答はSHIORIが確保してこちらで解放します。
+/// drop時にロード済みであればunloadを呼び出します。
+pub struct ShioriClient {
+    load: LoadFn,
+    unload: UnloadFn,
+    request: RequestFn,
+    loaded: bool,
+    _lib: Option<Library>,
+}
+
+impl ShioriClient {
+    /// 共有ライブラリを開き、load/unload/requestを解決します。loadは呼び出しません。
+    pub fn open<P: AsRef<Path>>(path: P) -> MyResult<ShioriClient> {
+        let path = path.as_ref();
+        let lib = unsafe { Library::new(path) }
+            .map_err(|e| MyError::Library(format!("{}: {}", path.display(), e)))?;
+        let (load, unload, request) = unsafe {
+            (
+                *lib.get::<LoadFn>(b"load\0")?,
+                *lib.get::<UnloadFn>(b"unload\0")?,
+                *lib.get::<RequestFn>(b"request\0")?,
+            )
+        };
+        Ok(ShioriClient {
+            load,
+            unload,
+            request,
+            loaded: false,
+            _lib: Some(lib),
+        })
+    }
+
+    /// 関数を直接指定してクライアントを作成します。テストで利用します。
+    #[cfg(test)]
+    pub(crate) fn from_fns(load: LoadFn, unload: UnloadFn, request: RequestFn) -> ShioriClient {
+        ShioriClient {
+            load,
+            unload,
+            request,
+            loaded: false,
+            _lib: None,
+        }
+    }
+
+    /// 共有ライブラリを開き、load_dirでloadを呼び出します。
+    pub fn load<P: AsRef<Path>, D: AsRef<Path>>(path: P, load_dir: D) -> MyResult<ShioriClient> {
+        let mut client = ShioriClient::open(path)?;
+        client.raw_load(load_dir.as_ref())?;
+        Ok(client)
+    }
+
+    /// loadを呼び出します。ロード済みであれば先にunloadします。
+    /// ディレクトリはパスセパレーターで終わるANSI文字列で渡します。
+    pub fn raw_load(&mut self, load_dir: &Path) -> MyResult<()> {
+        self.unload();
+        let bytes = load_dir_bytes(load_dir)?;
+        let (h, len) = ShioriString::clone_from_slice_nofree(&bytes).value();
+        if !is_true(unsafe { (self.load)(h, len as c_long) }) {
+            return Err(MyError::Load);
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    /// ロード済みかどうかを返します。
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// リクエストを送り、応答を解析して返します。
+    pub fn request(&mut self, req: &ShioriRequest) -> MyResult<ShioriResponse> {
+        let res = self.request_text(req.text)?.ok_or(MyError::NoResponse)?;
+        ShioriResponse::parse(&res)
+    }
+
+    /// リクエスト文字列を送り、応答文字列を返します。
+    /// 文字コードはCharsetヘッダに従って変換します。
+    /// SHIORIがNULLを返した場合は`None`です。
+    pub fn request_text(&mut self, text: &str) -> MyResult<Option<String>> {
+        let req = encode_charset(text)?;
+        match self.request_bytes(&req)? {
+            Some(res) => Ok(Some(decode_charset(&res)?.into_owned())),
+            None => Ok(None),
+        }
+    }
+
+    /// リクエストのバイト列をそのまま送り、応答のバイト列を返します。
+    /// SHIORIがNULLを返した場合は`None`です。
+    pub fn request_bytes(&mut self, req: &[u8]) -> MyResult<Option<Vec<u8>>> {
+        if !self.loaded {
+            return Err(MyError::NotInitialized);
+        }
+        let (h, len) = ShioriString::clone_from_slice_nofree(req).value();
+        let mut len = len as c_long;
+        let h = unsafe { (self.request)(h, &mut len) };
+        if h.is_null() {
+            return Ok(None);
+        }
+        let res = ShioriString::capture(h, len as usize);
+        Ok(Some(res.as_bytes().to_vec()))
+    }
+
+    /// unloadを呼び出します。ロードしていなければ何もしません。
+    pub fn unload(&mut self) -> bool {
+        if !self.loaded {
+            return true;
+        }
+        self.loaded = false;
+        is_true(unsafe { (self.unload)() })
+    }
+}
+
+impl Drop for ShioriClient {
+    fn drop(&mut self) {
+        self.unload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{RawShiori3, Shiori3};
+    use crate::parsers::res::Status;
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{LazyLock, Mutex};
+
+    static UNLOADED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Ghost;
+
+    impl Shiori3 for Ghost {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            assert!(load_dir.as_ref().ends_with("ghost/master"));
+            Ok(Ghost)
+        }
+
+        fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
+            let req = ShioriRequest::parse(req.into())?;
+            let res = match req.id {
+                Some("OnBoot") => ShioriResponse::ok("\\0hello\\e"),
+                Some("OnNull") => anyhow::bail!("null"),
+                _ => ShioriResponse::no_content(),
+            };
+            Ok(res.to_string().into())
+        }
+
+        fn unload(&mut self) -> Result<(), anyhow::Error> {
+            UNLOADED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    static SHIORI: LazyLock<Mutex<RawShiori3<Ghost>>> =
+        LazyLock::new(|| Mutex::new(RawShiori3::default()));
+
+    extern "C" fn load(h: HGLOBAL, len: c_long) -> Bool {
+        to_bool(SHIORI.lock().unwrap().raw_load(h, len as usize))
+    }
+
+    extern "C" fn unload() -> Bool {
+        to_bool(SHIORI.lock().unwrap().raw_unload())
+    }
+
+    extern "C" fn request(h: HGLOBAL, len: *mut c_long) -> HGLOBAL {
+        let len = unsafe { &mut *len };
+        let mut l = *len as usize;
+        let rc = SHIORI.lock().unwrap().raw_request(h, &mut l);
+        *len = l as c_long;
+        rc
+    }
+
+    #[test]
+    fn client_request() {
+        let mut client = ShioriClient::from_fns(load, unload, request);
+        let text = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\n\r\n";
+        let req = ShioriRequest::parse(text).unwrap();
+        assert_eq!(client.request(&req), Err(MyError::NotInitialized));
+        client.raw_load(Path::new("ghost/master")).unwrap();
+        assert!(client.is_loaded());
+
+        let res = client.request(&req).unwrap();
+        assert_eq!(res.status, Status::Ok);
+        assert_eq!(res.value(), Some("\\0hello\\e"));
+
+        let text = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnNull\r\n\r\n";
+        let req = ShioriRequest::parse(text).unwrap();
+        assert_eq!(client.request_text(text).unwrap(), None);
+        assert_eq!(client.request(&req), Err(MyError::NoResponse));
+
+        let before = UNLOADED.load(Ordering::SeqCst);
+        drop(client);
+        assert_eq!(UNLOADED.load(Ordering::SeqCst), before + 1);
+        assert!(ShioriClient::open("/nonexistent/libghost.so").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 現在時刻を提供します。
+/// テストでは差し替えて時刻を固定できます。
+pub trait Clock: Send + Sync {
+    /// 現在時刻を返します。
+    fn now(&self) -> SystemTime;
+}
+
+/// システム時刻を返す時計です。
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// テスト用の仮想時計です。`advance()`で時刻を進めます。
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: SystemTime,
+    now: Mutex<SystemTime>,
+}
+
+impl VirtualClock {
+    /// start時刻で止まった時計を作成します。
+    pub fn new(start: SystemTime) -> VirtualClock {
+        VirtualClock {
+            start,
+            now: Mutex::new(start),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SystemTime> {
+        self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 時刻を設定します。
+    pub fn set(&self, now: SystemTime) {
+        *self.lock() = now;
+    }
+
+    /// 時刻を進めます。
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    /// 作成時からの経過時間を返します。
+    pub fn elapsed(&self) -> Duration {
+        self.now().duration_since(self.start).unwrap_or_default()
+    }
+
+    /// 時刻を1秒ずつ進め、ベースウェアが送るイベントを`f`に渡します。
+    /// 毎秒`OnSecondChange`を、分が変わったときはさらに`OnMinuteChange`を生成します。
+    /// 1秒未満の端数は最後にまとめて進めます。
+    pub fn advance_with_ticks<F: FnMut(&str)>(&self, duration: Duration, mut f: F) {
+        for _ in 0..duration.as_secs() {
+            let before = self.now();
+            self.advance(Duration::from_secs(1));
+            let uptime = self.elapsed();
+            f(&tick_request("OnSecondChange", uptime));
+            if epoch_secs(before) / 60 != epoch_secs(self.now()) / 60 {
+                f(&tick_request("OnMinuteChange", uptime));
+            }
+        }
+        self.advance(Duration::from_nanos(duration.subsec_nanos().into()));
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.lock()
+    }
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 時刻イベントのリクエストを作成します。
+/// Reference0は起動からの時間(時)、Reference3はトーク可能(1)です。
+fn tick_request(id: &str, uptime: Duration) -> String {
+    format!(
+        "GET SHIORI/3.0\r\nCharset: UTF-8\r\nSender: shiori3\r\nSecurityLevel: local\r\nID: {}\r\nReference0: {}\r\nReference1: 0\r\nReference2: 0\r\nReference3: 1\r\nReference4: 0\r\n\r\n",
+        id,
+        uptime.as_secs() / 3600
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::req::ShioriRequest;
+
+    #[test]
+    fn virtual_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(58);
+        let clock = VirtualClock::new(start);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.elapsed(), Duration::from_millis(500));
+
+        let mut ids = Vec::new();
+        clock.advance_with_ticks(Duration::from_millis(3200), |req| {
+            let req = ShioriRequest::parse(req).unwrap();
+            assert_eq!(req.reference[3], (3, "1"));
+            ids.push(req.id.unwrap().to_string());
+        });
+        assert_eq!(
+            ids,
+            vec![
+                "OnSecondChange",
+                "OnSecondChange",
+                "OnMinuteChange",
+                "OnSecondChange"
+            ]
+        );
+        assert_eq!(clock.now(), start + Duration::from_millis(3700));
+        clock.set(start);
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+}
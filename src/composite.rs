@@ -0,0 +1,371 @@
+use crate::api::{Shiori3, ShioriHandler};
+use crate::context::RequestContext;
+use crate::parsers::res::{ShioriResponse, Status};
+use crate::services::Services;
+use log::*;
+use std::any::type_name;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// `Composite`の子の組です。`Shiori3`の実装のタプルに実装されています。
+pub trait Children {
+    #[doc(hidden)]
+    fn load_children(
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        services: &Services,
+    ) -> Result<Vec<ChildEntry>, anyhow::Error>;
+}
+
+#[doc(hidden)]
+pub struct ChildEntry {
+    name: Cow<'static, str>,
+    shiori: Box<dyn ShioriHandler>,
+}
+
+macro_rules! impl_children {
+    ($($t:ident),+) => {
+        impl<$($t: Shiori3 + Send + 'static),+> Children for ($($t,)+) {
+            fn load_children(
+                h_inst: usize,
+                load_dir: &Path,
+                load_dir_bytes: &[u8],
+                services: &Services,
+            ) -> Result<Vec<ChildEntry>, anyhow::Error> {
+                let mut children = Vec::new();
+                $(
+                    match $t::load_with_services(h_inst, load_dir, load_dir_bytes, services) {
+                        Ok(shiori) => children.push(ChildEntry {
+                            name: type_name::<$t>().into(),
+                            shiori: Box::new(shiori),
+                        }),
+                        Err(e) => {
+                            unload_loaded(children);
+                            return Err(e.context(type_name::<$t>()));
+                        }
+                    }
+                )+
+                Ok(children)
+            }
+        }
+    };
+}
+
+/// ロードに失敗したとき、ロード済みの子を逆順に終了します。
+fn unload_loaded(children: Vec<ChildEntry>) {
+    for mut child in children.into_iter().rev() {
+        if let Err(e) = child.shiori.close() {
+            error!("[composite] unload {}: {}", child.name, e);
+        }
+    }
+}
+
+impl_children!(A);
+impl_children!(A, B);
+impl_children!(A, B, C);
+impl_children!(A, B, C, D);
+impl_children!(A, B, C, D, E);
+impl_children!(A, B, C, D, E, F);
+impl_children!(A, B, C, D, E, F, G);
+impl_children!(A, B, C, D, E, F, G, H);
+
+/// 複数のSHIORIを順に呼び出すSHIORIです。
+/// 子は`Composite<(Core, Game, Clock)>`のようにタプルで指定し、
+/// 同じロードディレクトリとサービスで全てロードします。
+/// 実行時に子を組み立てる場合は`Composite::from_handlers()`を利用してください。
+/// リクエストは先頭の子から順に渡し、`204 No Content`以外を返した最初の応答を採用します。
+/// エラーを返した子は読み飛ばし、どの子も応答しなければ最初のエラーを返します。
+pub struct Composite<L = ()> {
+    children: Vec<ChildEntry>,
+    phantom: PhantomData<fn() -> L>,
+}
+
+impl Composite {
+    /// `ShioriHandler`の列から作成します。子の名前は`#0`からの連番です。
+    /// `ShioriHandler`として、受け取った`RequestContext`を全ての子に渡します。
+    pub fn from_handlers(handlers: Vec<Box<dyn ShioriHandler>>) -> Composite {
+        let children = handlers
+            .into_iter()
+            .enumerate()
+            .map(|(i, shiori)| ChildEntry {
+                name: format!("#{}", i).into(),
+                shiori,
+            })
+            .collect();
+        Composite {
+            children,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<L> Composite<L> {
+    /// 子の名前を返します。
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.children.iter().map(|c| c.name.as_ref())
+    }
+
+    fn dispatch<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        let mut last = None;
+        let mut error = None;
+        for child in self.children.iter_mut() {
+            match child.shiori.handle(req, ctx) {
+                Ok(res) if is_no_content(&res) => last = Some(res),
+                Ok(res) => {
+                    debug!("[composite] answered by {}", child.name);
+                    return Ok(res);
+                }
+                Err(e) => {
+                    error!("[composite] request {}: {}", child.name, e);
+                    if error.is_none() {
+                        error = Some(e.context(child.name.clone()));
+                    }
+                }
+            }
+        }
+        debug!("[composite] no content");
+        match (last, error) {
+            (_, Some(e)) => Err(e),
+            (Some(res), None) => Ok(res),
+            (None, None) => Ok(ShioriResponse::no_content().to_string().into()),
+        }
+    }
+
+    /// 全ての子の終了処理を行い、最初のエラーを返します。
+    fn close_children(&mut self) -> Result<(), anyhow::Error> {
+        let mut rc = Ok(());
+        for child in self.children.iter_mut() {
+            if let Err(e) = child.shiori.close() {
+                error!("[composite] unload {}: {}", child.name, e);
+                if rc.is_ok() {
+                    rc = Err(e.context(child.name.clone()));
+                }
+            }
+        }
+        rc
+    }
+}
+
+fn is_no_content(res: &str) -> bool {
+    res.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .and_then(Status::from_code)
+        == Some(Status::NoContent)
+}
+
+impl<L: Children> Shiori3 for Composite<L> {
+    fn load<P: AsRef<Path>>(
+        h_inst: usize,
+        load_dir: P,
+        load_dir_bytes: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        Self::load_with_services(h_inst, load_dir.as_ref(), load_dir_bytes, &Services::new())
+    }
+
+    /// 全ての子に同じサービスを渡します。
+    fn load_with_services(
+        h_inst: usize,
+        load_dir: &Path,
+        load_dir_bytes: &[u8],
+        services: &Services,
+    ) -> Result<Self, anyhow::Error> {
+        let children = L::load_children(h_inst, load_dir, load_dir_bytes, services)?;
+        Ok(Composite {
+            children,
+            phantom: PhantomData,
+        })
+    }
+
+    /// `Shiori3`の子はコンテキストを受け取らないため、空のコンテキストで呼び出します。
+    fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
+        let mut ctx = RequestContext::new(0, SystemTime::now());
+        self.dispatch(req.into(), &mut ctx)
+    }
+
+    fn unload(&mut self) -> Result<(), anyhow::Error> {
+        self.close_children()
+    }
+}
+
+impl ShioriHandler for Composite {
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        self.dispatch(req, ctx)
+    }
+
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        self.close_children()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static UNLOADED: AtomicUsize = AtomicUsize::new(0);
+
+    const NO_CONTENT: &str = "SHIORI/3.0 204 No Content\r\nCharset: UTF-8\r\n\r\n";
+
+    /// IDが一致したときだけ応答します。
+    struct Answer<const N: usize>;
+
+    impl<const N: usize> Shiori3 for Answer<N> {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            assert_eq!(load_dir.as_ref(), Path::new("dir"));
+            Ok(Answer)
+        }
+
+        fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
+            let req = req.into();
+            if req.contains(&format!("ID: On{}", N)) {
+                Ok(ShioriResponse::ok(format!("{}", N)).to_string().into())
+            } else if req.contains("ID: OnError") {
+                Err(anyhow::anyhow!("error {}", N))
+            } else {
+                Ok(NO_CONTENT.into())
+            }
+        }
+
+        fn unload(&mut self) -> Result<(), anyhow::Error> {
+            UNLOADED.fetch_add(N, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn req(id: &str) -> String {
+        format!("GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: {}\r\n\r\n", id)
+    }
+
+    #[test]
+    fn composite_fallthrough() {
+        let mut shiori =
+            Composite::<(Answer<1>, Answer<2>, Answer<4>)>::load(0, "dir", b"dir").unwrap();
+        assert_eq!(shiori.names().count(), 3);
+        let r = req("On2");
+        let res = shiori.request(r.as_str()).unwrap();
+        assert!(res.contains("Value: 2\r\n"));
+        let r = req("On4");
+        let res = shiori.request(r.as_str()).unwrap();
+        assert!(res.contains("Value: 4\r\n"));
+        let r = req("OnOther");
+        assert_eq!(shiori.request(r.as_str()).unwrap(), NO_CONTENT);
+        let r = req("OnError");
+        let e = shiori.request(r.as_str()).unwrap_err();
+        assert_eq!(e.root_cause().to_string(), "error 1");
+        assert!(e.to_string().contains("Answer<1>"));
+        shiori.unload().unwrap();
+        assert_eq!(UNLOADED.load(Ordering::SeqCst), 7);
+    }
+
+    static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    /// Nが0ならロードに失敗します。終了した順序を記録します。
+    struct Track<const N: usize>;
+
+    impl<const N: usize> Shiori3 for Track<N> {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            _load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            match N {
+                0 => Err(anyhow::anyhow!("load error")),
+                _ => Ok(Track),
+            }
+        }
+
+        fn request<'a, S: Into<&'a str>>(
+            &mut self,
+            _req: S,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(NO_CONTENT.into())
+        }
+
+        fn unload(&mut self) -> Result<(), anyhow::Error> {
+            ORDER.lock().unwrap().push(N);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn composite_load_error() {
+        let e = Composite::<(Track<1>, Track<2>, Track<0>, Track<3>)>::load(0, "dir", b"dir")
+            .err()
+            .unwrap();
+        assert_eq!(e.root_cause().to_string(), "load error");
+        assert!(e.to_string().contains("Track<0>"));
+        assert_eq!(*ORDER.lock().unwrap(), [2, 1]);
+    }
+
+    /// リクエストの通し番号を応答します。
+    struct Sequence;
+
+    impl ShioriHandler for Sequence {
+        fn handle<'a>(
+            &mut self,
+            req: &'a str,
+            ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            if req.contains("ID: OnSequence") {
+                Ok(ShioriResponse::ok(ctx.sequence.to_string())
+                    .to_string()
+                    .into())
+            } else {
+                Ok(NO_CONTENT.into())
+            }
+        }
+    }
+
+    /// 常にエラーを返します。
+    struct Fail;
+
+    impl ShioriHandler for Fail {
+        fn handle<'a>(
+            &mut self,
+            _req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Err(anyhow::anyhow!("fail"))
+        }
+    }
+
+    #[test]
+    fn composite_handlers() {
+        let mut shiori = Composite::from_handlers(vec![Box::new(Fail), Box::new(Sequence)]);
+        assert_eq!(shiori.names().collect::<Vec<_>>(), ["#0", "#1"]);
+        let mut ctx = RequestContext::new(42, SystemTime::now());
+        let r = req("OnSequence");
+        let res = shiori.handle(&r, &mut ctx).unwrap();
+        assert!(res.contains("Value: 42\r\n"));
+        let r = req("OnOther");
+        let e = shiori.handle(&r, &mut ctx).unwrap_err();
+        assert_eq!(e.root_cause().to_string(), "fail");
+        assert_eq!(e.to_string(), "#0");
+        shiori.close().unwrap();
+    }
+
+    #[test]
+    fn no_content() {
+        assert!(is_no_content(NO_CONTENT));
+        assert!(!is_no_content("SHIORI/3.0 200 OK\r\n\r\n"));
+        assert!(!is_no_content(""));
+    }
+}
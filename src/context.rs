@@ -0,0 +1,168 @@
+use crate::clock::Clock;
+use crate::random::RandomSource;
+use crate::services::Services;
+use crate::store::Store;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// ベースウェアの起動完了を示すイベントです。
+const BOOT_EVENTS: [&str; 4] = ["OnBoot", "OnFirstBoot", "OnGhostChanged", "OnShellChanged"];
+
+/// ミドルウェア等がリクエストに付加する型付きの値です。
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Default::default()
+    }
+
+    /// 値を登録します。同じ型の値があれば置き換えて返します。
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// 値を参照します。
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    /// 値を可変参照します。
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut())
+    }
+
+    /// 値を取り除いて返します。
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok())
+            .map(|v| *v)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// リクエスト毎に`RawShiori3`が作成する実行コンテキストです。
+pub struct RequestContext {
+    /// DLLのインスタンスハンドル
+    pub h_inst: usize,
+    /// ロードディレクトリ
+    pub load_dir: PathBuf,
+    /// リクエストの通し番号(1から始まる)
+    pub sequence: u64,
+    /// リクエストを受け付けた時刻
+    pub timestamp: SystemTime,
+    /// ベースウェアの起動(OnBoot等)が完了しているかどうか
+    pub booted: bool,
+    /// ミドルウェア等が付加する値
+    pub extensions: Extensions,
+    services: Services,
+}
+
+impl RequestContext {
+    /// 既定のサービスを使うコンテキストを作成します。
+    pub fn new(sequence: u64, timestamp: SystemTime) -> RequestContext {
+        RequestContext {
+            h_inst: 0,
+            load_dir: PathBuf::new(),
+            sequence,
+            timestamp,
+            booted: false,
+            extensions: Extensions::new(),
+            services: Services::new(),
+        }
+    }
+
+    /// インスタンスハンドルとロードディレクトリを設定します。
+    pub fn with_instance<P: AsRef<Path>>(mut self, h_inst: usize, load_dir: P) -> Self {
+        self.h_inst = h_inst;
+        self.load_dir = load_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// 起動完了状態を設定します。
+    pub fn with_booted(mut self, booted: bool) -> Self {
+        self.booted = booted;
+        self
+    }
+
+    /// サービスを設定します。
+    pub fn with_services(mut self, services: Services) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// ロード時に渡されたサービスを返します。
+    pub fn services(&self) -> &Services {
+        &self.services
+    }
+
+    /// 時計を返します。
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.services.clock()
+    }
+
+    /// 乱数を返します。
+    pub fn random(&self) -> &Arc<Mutex<dyn RandomSource>> {
+        self.services.random()
+    }
+
+    /// 永続化ストアを返します。設定されていなければNoneです。
+    pub fn store(&self) -> Option<&Arc<Mutex<Store>>> {
+        self.services.store()
+    }
+
+    /// ロガーを返します。
+    pub fn logger(&self) -> &dyn log::Log {
+        self.services.logger()
+    }
+}
+
+/// リクエストが起動完了を示すイベントかどうかを返します。
+pub(crate) fn is_boot_event(req: &str) -> bool {
+    req.lines()
+        .filter_map(|line| line.strip_prefix("ID: "))
+        .any(|id| BOOT_EVENTS.contains(&id.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions() {
+        let mut ext = Extensions::new();
+        assert!(ext.is_empty());
+        assert_eq!(ext.insert(1_u32), None);
+        assert_eq!(ext.insert(String::from("a")), None);
+        assert_eq!(ext.insert(2_u32), Some(1));
+        *ext.get_mut::<String>().unwrap() += "b";
+        assert_eq!(ext.get::<String>().map(|s| s.as_str()), Some("ab"));
+        assert_eq!(ext.remove::<u32>(), Some(2));
+        assert_eq!(ext.get::<u32>(), None);
+        assert_eq!(ext.get::<i64>(), None);
+    }
+
+    #[test]
+    fn boot_event() {
+        assert!(is_boot_event("GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n"));
+        assert!(is_boot_event("GET SHIORI/3.0\r\nID: OnFirstBoot\r\n\r\n"));
+        assert!(!is_boot_event("GET SHIORI/3.0\r\nID: OnBootX\r\n\r\n"));
+        assert!(!is_boot_event("GET SHIORI/3.0\r\nID: version\r\n\r\n"));
+    }
+}
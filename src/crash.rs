@@ -0,0 +1,225 @@
+use crate::simulator::Exchange;
+use crate::trace::format_exchange;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 直近のリクエストと応答をメモリに保持し、異常時にダンプします。
+/// エラー、捕捉したpanic、`500`の応答で`RawShiori3`が`dump()`を呼び出します。
+/// ダンプはエラーの連鎖を`//`コメントにしたlogfile.txt形式で、
+/// `parse_trace()`でそのまま読み込めます。
+#[derive(Debug)]
+pub struct CrashRecorder {
+    capacity: usize,
+    dir: PathBuf,
+    ring: VecDeque<Exchange>,
+}
+
+impl CrashRecorder {
+    /// 直近capacity件を保持するレコーダーを作成します。
+    /// ダンプ先はロードディレクトリです。
+    pub fn new(capacity: usize) -> CrashRecorder {
+        CrashRecorder {
+            capacity,
+            dir: PathBuf::new(),
+            ring: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// ダンプ先のディレクトリを設定します。
+    /// 相対パスは`RawShiori3`のロード時にロードディレクトリからのパスになります。
+    pub fn with_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// ダンプ先のディレクトリを返します。
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 保持しているリクエストと応答を古い順に返します。
+    pub fn recent(&self) -> impl Iterator<Item = &Exchange> {
+        self.ring.iter()
+    }
+
+    /// 相対パスをdirからのパスにします。
+    pub(crate) fn resolve(&mut self, dir: &Path) {
+        if self.dir.is_relative() {
+            self.dir = dir.join(&self.dir);
+        }
+    }
+
+    /// リクエストと応答を追加し、capacityを超えた古いものを捨てます。
+    pub fn push(&mut self, request: &str, response: Option<&str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ring.len() == self.capacity {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(Exchange {
+            request: request.to_string(),
+            response: response.map(|res| res.to_string()),
+        });
+    }
+
+    /// 保持している内容とエラーの連鎖を`crash-<UNIXミリ秒>.txt`に書き出します。
+    pub fn dump(&self, now: SystemTime, error: &anyhow::Error) -> io::Result<PathBuf> {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let mut buf = format!("// crash at {}\n", millis);
+        for (i, cause) in error.chain().enumerate() {
+            let label = if i == 0 { "error" } else { "caused by" };
+            for line in cause.to_string().lines() {
+                buf.push_str(&format!("// {}: {}\n", label, line));
+            }
+        }
+        for exchange in &self.ring {
+            buf.push_str(&format_exchange(exchange));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let mut path = self.dir.join(format!("crash-{}.txt", millis));
+        let mut n = 1;
+        while path.exists() {
+            path = self.dir.join(format!("crash-{}-{}.txt", millis, n));
+            n += 1;
+        }
+        fs::write(&path, buf)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{RawShiori3, ShioriHandler};
+    use crate::clock::VirtualClock;
+    use crate::context::RequestContext;
+    use crate::hglobal::ShioriString;
+    use crate::simulator::BasewareSimulator;
+    use crate::trace::parse_trace;
+    use anyhow::Context;
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("shiori3-crash-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn req(id: &str) -> String {
+        format!("GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: {}\r\n\r\n", id)
+    }
+
+    #[test]
+    fn crash_dump() {
+        let dir = test_dir("dump");
+        let mut crash = CrashRecorder::new(2).with_dir("crash");
+        crash.resolve(&dir);
+        assert_eq!(crash.dir(), dir.join("crash"));
+        crash.push(&req("OnBoot"), Some("SHIORI/3.0 204 No Content\r\n\r\n"));
+        crash.push(&req("OnMouseClick"), Some("SHIORI/3.0 200 OK\r\n\r\n"));
+        crash.push(&req("OnClose"), None);
+        let ids: Vec<_> = crash.recent().filter_map(|e| e.id()).collect();
+        assert_eq!(ids, vec!["OnMouseClick", "OnClose"]);
+
+        let error = Err::<(), _>(anyhow::anyhow!("file not found"))
+            .context("OnClose failed")
+            .unwrap_err();
+        let now = UNIX_EPOCH + Duration::from_millis(1234);
+        let path = crash.dump(now, &error).unwrap();
+        assert_eq!(path, dir.join("crash/crash-1234.txt"));
+        let path2 = crash.dump(now, &error).unwrap();
+        assert_eq!(path2, dir.join("crash/crash-1234-1.txt"));
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(
+            "// crash at 1234\n// error: OnClose failed\n// caused by: file not found\n"
+        ));
+        let trace = parse_trace(&text).unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].id(), Some("OnMouseClick"));
+        assert_eq!(trace[1].response, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Fragile;
+
+    impl ShioriHandler for Fragile {
+        fn handle<'a>(
+            &mut self,
+            req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            if req.contains("\r\nID: OnPanic\r\n") {
+                panic!("broken ghost");
+            }
+            if req.contains("\r\nID: OnFail\r\n") {
+                return Ok("SHIORI/3.0 500 Internal Server Error\r\n\r\n".into());
+            }
+            Ok("SHIORI/3.0 204 No Content\r\n\r\n".into())
+        }
+    }
+
+    #[test]
+    fn crash_raw_shiori() {
+        let dir = test_dir("raw");
+        let factory = |_h_inst: usize, _load_dir: &Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = Box::new(Fragile);
+            Ok(shiori)
+        };
+        let mut raw = RawShiori3::with_factory(factory);
+        raw.set_crash_recorder(CrashRecorder::new(8));
+        let clock = Arc::new(VirtualClock::new(UNIX_EPOCH + Duration::from_secs(10)));
+        let mut sim = BasewareSimulator::with_raw(raw, &dir).with_clock(clock.clone());
+        sim.load().unwrap();
+        sim.get("OnBoot", &[]).unwrap();
+        assert!(!dir.join("crash-10000.txt").exists());
+
+        sim.get("OnFail", &[]).unwrap();
+        let text = fs::read_to_string(dir.join("crash-10000.txt")).unwrap();
+        assert!(text.contains("// error: 500 Internal Server Error\n"));
+        assert_eq!(parse_trace(&text).unwrap().len(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        let res = sim.get("OnPanic", &[]).unwrap();
+        assert_eq!(res.response, None);
+        let text = fs::read_to_string(dir.join("crash-11000.txt")).unwrap();
+        assert!(text.contains("// error: panic: broken ghost\n"));
+        let trace = parse_trace(&text).unwrap();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace[2].id(), Some("OnPanic"));
+        assert_eq!(trace[2].response, None);
+
+        sim.get("OnBoot", &[]).unwrap();
+        drop(sim);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_not_initialized() {
+        let dir = test_dir("uninit");
+        let mut raw = RawShiori3::<()>::with_factory(|_: usize, _: &Path, _: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = Box::new(Fragile);
+            Ok(shiori)
+        });
+        raw.set_crash_recorder(CrashRecorder::new(8).with_dir(&dir));
+        let (h, mut len) = ShioriString::clone_from_str_nofree(req("OnBoot").as_str()).value();
+        assert!(raw.raw_request(h, &mut len).is_null());
+        let dumps: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(dumps.len(), 1);
+        let text = fs::read_to_string(dumps[0].as_ref().unwrap().path()).unwrap();
+        assert!(text.contains("// error: not initialized error\n"));
+        assert_eq!(parse_trace(&text).unwrap()[0].id(), Some("OnBoot"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 use super::parsers;
+use super::script;
+use std::io;
 use std::str::Utf8Error;
 use std::sync::PoisonError;
 use thiserror::Error;
@@ -22,13 +24,42 @@ pub enum MyError {
     #[error("Shiori request parse error for '{0}'")]
     ParseRequest(Box<parsers::req::ParseError>),
 
+    #[error("Shiori response parse error: {0}")]
+    ParseResponse(String),
+
+    #[error("Sakura Script parse error for '{0}'")]
+    ParseScript(Box<script::token::ParseError>),
+
     #[error("ANSI encodeing error")]
     EncodeAnsi,
     #[error("UTF8 encodeing error")]
     EncodeUtf8(Utf8Error),
+    #[error("unknown charset '{0}'")]
+    UnknownCharset(String),
 
     #[error("script error: {}", message)]
     Script { message: String },
+
+    #[error("IO error: {}", message)]
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+
+    #[error("store error: {}", message)]
+    Store { message: String },
+
+    #[error("trace error at line {}: {}", line, message)]
+    Trace { line: usize, message: String },
+
+    #[error("library error: {0}")]
+    Library(String),
+
+    #[error("no response")]
+    NoResponse,
+
+    #[error("message too large: {0} bytes")]
+    MessageTooLarge(usize),
 }
 
 impl From<parsers::req::ParseError> for MyError {
@@ -37,6 +68,12 @@ impl From<parsers::req::ParseError> for MyError {
     }
 }
 
+impl From<script::token::ParseError> for MyError {
+    fn from(error: script::token::ParseError) -> MyError {
+        MyError::ParseScript(Box::new(error))
+    }
+}
+
 impl<G> From<PoisonError<G>> for MyError {
     fn from(_error: PoisonError<G>) -> MyError {
         MyError::Poison
@@ -48,9 +85,65 @@ impl From<Utf8Error> for MyError {
     }
 }
 
+impl From<io::Error> for MyError {
+    fn from(error: io::Error) -> MyError {
+        MyError::Io {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// `serde`フィーチャーでは`{"kind": "UnknownCharset", "message": "unknown charset 'x'"}`
+/// の形式でシリアライズします。`kind`はバリアント名です。
+#[cfg(feature = "serde")]
+impl serde::Serialize for MyError {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut st = s.serialize_struct("MyError", 2)?;
+        st.serialize_field("kind", self.kind())?;
+        st.serialize_field("message", &self.to_string())?;
+        st.end()
+    }
+}
+
 impl MyError {
+    /// バリアント名を返します。
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MyError::Others => "Others",
+            MyError::Load => "Load",
+            MyError::NotInitialized => "NotInitialized",
+            MyError::Poison => "Poison",
+            MyError::ParseRequest(_) => "ParseRequest",
+            MyError::ParseResponse(_) => "ParseResponse",
+            MyError::ParseScript(_) => "ParseScript",
+            MyError::EncodeAnsi => "EncodeAnsi",
+            MyError::EncodeUtf8(_) => "EncodeUtf8",
+            MyError::UnknownCharset(_) => "UnknownCharset",
+            MyError::Script { .. } => "Script",
+            MyError::Io { .. } => "Io",
+            MyError::Store { .. } => "Store",
+            MyError::Trace { .. } => "Trace",
+            MyError::Library(_) => "Library",
+            MyError::NoResponse => "NoResponse",
+            MyError::MessageTooLarge(_) => "MessageTooLarge",
+        }
+    }
+
     #[allow(dead_code)]
     pub fn script_error(message: String) -> MyError {
         MyError::Script { message }
     }
+
+    pub fn store_error(message: String) -> MyError {
+        MyError::Store { message }
+    }
+
+    pub fn trace_error(line: usize, message: &str) -> MyError {
+        MyError::Trace {
+            line,
+            message: message.into(),
+        }
+    }
 }
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// ゴーストが読み書きするファイルシステムです。
+/// 相対パスはルートからのパスとして扱います。
+/// テストではメモリ上の実装に差し替えられます。
+pub trait FileSystem: Send + Sync {
+    /// ルートディレクトリを返します。
+    fn root(&self) -> &Path;
+
+    /// ファイルを読み込みます。
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// ファイルに書き込みます。
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// ファイルが存在するかどうかを返します。
+    fn exists(&self, path: &Path) -> bool;
+
+    /// ファイルの名前を変更します。変更先のファイルは置き換えます。
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// ファイルをUTF-8文字列として読み込みます。
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        let bytes = self.read(path)?;
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// ルートを基準にパスを解決します。
+    /// 絶対パスや`..`を含むパスはルートの外を指せるため、エラーになります。
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let inside = path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !inside {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("path outside root: {}", path.display()),
+            ));
+        }
+        Ok(self.root().join(path))
+    }
+}
+
+/// ディスク上のディレクトリをルートとするファイルシステムです。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DiskFileSystem {
+    root: PathBuf,
+}
+
+impl DiskFileSystem {
+    pub fn new<P: AsRef<Path>>(root: P) -> DiskFileSystem {
+        DiskFileSystem {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl FileSystem for DiskFileSystem {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.resolve(path)?)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let path = self.resolve(path)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(path)?;
+        file.write_all(data)?;
+        file.sync_all()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.resolve(path).is_ok_and(|path| path.exists())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.resolve(from)?, self.resolve(to)?)
+    }
+}
+
+/// メモリ上のファイルシステムです。
+#[derive(Debug, Default)]
+pub struct MemoryFileSystem {
+    root: PathBuf,
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new<P: AsRef<Path>>(root: P) -> MemoryFileSystem {
+        MemoryFileSystem {
+            root: root.as_ref().to_path_buf(),
+            files: Default::default(),
+        }
+    }
+
+    /// ファイルを追加します。ルートの外を指すパスならパニックします。
+    pub fn with_file<P: AsRef<Path>, D: Into<Vec<u8>>>(self, path: P, data: D) -> Self {
+        let path = self.resolve(path.as_ref()).unwrap();
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(path, data.into());
+        self
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = self.resolve(path)?;
+        let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files
+            .get(&path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let path = self.resolve(path)?;
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.insert(path, data.to_vec());
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let Ok(path) = self.resolve(path) else {
+            return false;
+        };
+        let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.contains_key(&path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.resolve(from)?, self.resolve(to)?);
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let data = files
+            .remove(&from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_fs() {
+        let fs = MemoryFileSystem::new("ghost/master").with_file("a.txt", "abc");
+        assert!(fs.exists(Path::new("a.txt")));
+        assert_eq!(
+            fs.resolve(Path::new("a.txt")).unwrap(),
+            Path::new("ghost/master/a.txt")
+        );
+        assert_eq!(fs.read_to_string(Path::new("a.txt")).unwrap(), "abc");
+        fs.write(Path::new("sub/b.txt"), b"xyz").unwrap();
+        assert_eq!(fs.read(Path::new("sub/b.txt")).unwrap(), b"xyz");
+        fs.rename(Path::new("sub/b.txt"), Path::new("a.txt"))
+            .unwrap();
+        assert_eq!(fs.read_to_string(Path::new("a.txt")).unwrap(), "xyz");
+        assert!(!fs.exists(Path::new("sub/b.txt")));
+        let e = fs.read(Path::new("c.txt")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn outside_root() {
+        let dir = std::env::temp_dir().join(format!("shiori3-fs-{}", std::process::id()));
+        let fs = DiskFileSystem::new(dir.join("ghost"));
+        for path in ["../escape.txt", "sub/../../escape.txt"] {
+            let e = fs.write(Path::new(path), b"x").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert!(!fs.exists(Path::new(path)));
+        }
+        assert!(!dir.join("escape.txt").exists());
+        let abs = dir.join("abs.txt");
+        let e = fs.write(&abs, b"x").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(!abs.exists());
+        let e = fs.read(Path::new("/etc/hostname")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let fs = MemoryFileSystem::new("ghost/master");
+        let e = fs.rename(Path::new("a.txt"), Path::new("../a.txt"));
+        assert_eq!(e.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs.write(Path::new("./a.txt"), b"abc").unwrap();
+        assert!(fs.exists(Path::new("a.txt")));
+    }
+}
@@ -19,9 +19,8 @@
 //! original: https://github.com/bozaro/local-encoding-rs/blob/master/src/lib.rs
 #![allow(dead_code)]
 
-use super::windows_api;
+use super::sys::{self, CP_ACP, CP_OEMCP, CP_UTF8};
 use std::io::Result;
-use windows_sys::Win32::Globalization::*;
 
 /// Converter between string and multibyte encoding.
 pub trait Encoder {
@@ -56,14 +55,41 @@ impl CodePage for Encoding {
 impl Encoder for Encoding {
     /// Convert from bytes to string.
     fn to_string(&self, data: &[u8]) -> Result<String> {
-        windows_api::EncoderCodePage(self.codepage()).to_string(data)
+        sys::EncoderCodePage(self.codepage()).to_string(data)
     }
     /// Convert from bytes to string.
     fn to_bytes(&self, data: &str) -> Result<Vec<u8>> {
-        windows_api::EncoderCodePage(self.codepage()).to_bytes(data)
+        sys::EncoderCodePage(self.codepage()).to_bytes(data)
     }
 }
 
+/// SHIORIメッセージのCharsetヘッダの値を返します。
+pub fn find_charset(bytes: &[u8]) -> Option<&str> {
+    bytes
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix(b"Charset: "))
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .map(|value| value.trim())
+}
+
+/// Charsetヘッダの値に対応するコードページを返します。
+pub fn charset_codepage(charset: &str) -> Option<u32> {
+    let cp = match charset.to_ascii_lowercase().as_str() {
+        "utf-8" | "utf8" => CP_UTF8,
+        "shift_jis" | "shift-jis" | "sjis" | "x-sjis" | "windows-31j" | "cp932" | "ms932" => 932,
+        "euc-jp" => 20932,
+        "iso-2022-jp" => 50220,
+        "gbk" | "gb2312" => 936,
+        "big5" => 950,
+        "euc-kr" | "ks_c_5601-1987" => 949,
+        "default" | "ansi" => CP_ACP,
+        _ => return None,
+    };
+    Some(cp)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,4 +121,17 @@ mod tests {
     fn from_string_test(encoding: Encoding) {
         assert_eq!(encoding.to_bytes("Test").unwrap(), b"Test");
     }
+
+    #[test]
+    fn find_charset_test() {
+        let text = b"GET SHIORI/3.0\r\nCharset: Shift_JIS\r\nID: OnBoot\r\n\r\n";
+        assert_eq!(find_charset(text), Some("Shift_JIS"));
+        assert_eq!(
+            find_charset(b"GET SHIORI/3.0\r\n\r\nCharset: UTF-8\r\n"),
+            None
+        );
+        assert_eq!(charset_codepage("Shift_JIS"), Some(932));
+        assert_eq!(charset_codepage("UTF-8"), Some(CP_UTF8));
+        assert_eq!(charset_codepage("unknown"), None);
+    }
 }
@@ -1,16 +1,24 @@
-#![cfg(windows)]
 pub mod enc;
+#[cfg(not(windows))]
+mod unix_api;
+#[cfg(windows)]
 mod windows_api;
 
-use self::enc::{Encoder, Encoding};
+#[cfg(not(windows))]
+use self::unix_api as sys;
+#[cfg(windows)]
+use self::windows_api as sys;
+
+pub use self::sys::HGLOBAL;
+
+use self::enc::{Encoder, Encoding, charset_codepage, find_charset};
+use self::sys::{CP_UTF8, EncoderCodePage, global_alloc, global_free};
 use crate::error::*;
+use std::borrow::Cow;
 use std::ffi::OsString;
+use std::path::{MAIN_SEPARATOR, Path};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 use std::str;
-use windows_sys::Win32::Foundation::*;
-use windows_sys::Win32::System::Memory::*;
-
-const GMEM_FIXED: u32 = 0;
 
 /// HGLOBALを文字列にキャプチャーします。
 #[derive(Debug, PartialEq)]
@@ -29,7 +37,7 @@ impl Drop for ShioriString {
             return;
         }
         unsafe {
-            GlobalFree(self.h);
+            global_free(self.h);
         }
     }
 }
@@ -50,7 +58,7 @@ impl ShioriString {
     fn clone_from_slice_impl(bytes: &[u8], has_free: bool) -> ShioriString {
         let len = bytes.len();
         unsafe {
-            let h = GlobalAlloc(GMEM_FIXED, len as _);
+            let h = global_alloc(len);
             let p = h as *mut u8;
             let dst = from_raw_parts_mut::<u8>(p, len);
             dst[..].clone_from_slice(bytes);
@@ -83,6 +91,15 @@ impl ShioriString {
         ShioriString::clone_from_slice_impl(bytes, false)
     }
 
+    /// textをCharsetヘッダに従ってエンコードし、HGLOBAL領域にクローンします。
+    /// Charsetヘッダがない場合はUTF-8とみなします。
+    /// drop時にHGLOBALを開放しません。
+    /// shiori応答の作成に利用してください。
+    pub fn clone_from_str_charset_nofree(text: &str) -> MyResult<ShioriString> {
+        let bytes = encode_charset(text)?;
+        Ok(ShioriString::clone_from_slice_impl(&bytes, false))
+    }
+
     /// 要素を&[u8]として参照します。
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
@@ -133,6 +150,68 @@ impl ShioriString {
         let bytes = self.as_bytes();
         Ok(str::from_utf8(bytes)?)
     }
+
+    /// 格納データをCharsetヘッダに従って文字列に変換します。
+    /// Charsetヘッダがない場合はUTF-8とみなします。
+    /// SHIORI::request()文字列の取り出しに利用する。
+    pub fn to_charset_str(&self) -> MyResult<Cow<'_, str>> {
+        decode_charset(self.as_bytes())
+    }
+}
+
+/// バイト列をCharsetヘッダに従って文字列に変換します。
+/// Charsetヘッダがない場合はUTF-8とみなします。
+pub(crate) fn decode_charset(bytes: &[u8]) -> MyResult<Cow<'_, str>> {
+    match charset_encoder(find_charset(bytes))? {
+        None => Ok(Cow::Borrowed(str::from_utf8(bytes)?)),
+        Some(enc) => Ok(Cow::Owned(
+            enc.to_string(bytes).map_err(|_| MyError::EncodeAnsi)?,
+        )),
+    }
+}
+
+/// 文字列をCharsetヘッダに従ってバイト列に変換します。
+/// Charsetヘッダがない場合はUTF-8とみなします。
+pub(crate) fn encode_charset(text: &str) -> MyResult<Cow<'_, [u8]>> {
+    match charset_encoder(find_charset(text.as_bytes()))? {
+        None => Ok(Cow::Borrowed(text.as_bytes())),
+        Some(enc) => Ok(Cow::Owned(
+            enc.to_bytes(text).map_err(|_| MyError::EncodeAnsi)?,
+        )),
+    }
+}
+
+/// ログ等に使う文字列に変換します。
+/// Charsetヘッダに従って変換できない場合は、UTF-8として不正な部分を置き換えます。
+pub(crate) fn decode_lossy(bytes: &[u8]) -> Cow<'_, str> {
+    match decode_charset(bytes) {
+        Ok(text) => text,
+        Err(_) => String::from_utf8_lossy(bytes),
+    }
+}
+
+/// loadに渡すディレクトリを、パスセパレーターで終わるANSI文字列にします。
+pub(crate) fn load_dir_bytes(dir: &Path) -> MyResult<Vec<u8>> {
+    let mut dir = dir.to_string_lossy().into_owned();
+    if !dir.ends_with(MAIN_SEPARATOR) {
+        dir.push(MAIN_SEPARATOR);
+    }
+    Encoding::ANSI
+        .to_bytes(&dir)
+        .map_err(|_| MyError::EncodeAnsi)
+}
+
+/// Charsetに対応するエンコーダーを返します。UTF-8の場合は`None`を返します。
+pub(crate) fn charset_encoder(charset: Option<&str>) -> MyResult<Option<EncoderCodePage>> {
+    let charset = match charset {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+    match charset_codepage(charset) {
+        Some(CP_UTF8) => Ok(None),
+        Some(cp) => Ok(Some(EncoderCodePage(cp))),
+        None => Err(MyError::UnknownCharset(charset.into())),
+    }
 }
 
 #[test]
@@ -146,6 +225,7 @@ fn shiori_string_test() {
         let dst = ShioriString::capture(src.handle(), src.len());
         assert_eq!(dst.to_utf8_str().unwrap(), text);
     }
+    #[cfg(windows)]
     {
         let text = "適当なShioriString";
         let sjis = Encoding::ANSI.to_bytes(text).unwrap();
@@ -166,6 +246,25 @@ fn shiori_string_test() {
         let src = ShioriString::clone_from_str(text);
         assert_eq!(src.to_utf8_str().unwrap(), text);
     }
+    {
+        let text = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nReference0: 適当\r\n\r\n";
+        let src = ShioriString::clone_from_str_charset_nofree(text).unwrap();
+        assert_eq!(src.len(), text.len());
+        let dst = ShioriString::capture(src.handle(), src.len());
+        assert_eq!(dst.to_charset_str().unwrap(), text);
+        assert!(
+            ShioriString::clone_from_str_charset_nofree("GET SHIORI/3.0\r\nCharset: X\r\n\r\n")
+                .is_err()
+        );
+    }
+    #[cfg(windows)]
+    {
+        let text = "GET SHIORI/3.0\r\nCharset: Shift_JIS\r\nReference0: 適当\r\n\r\n";
+        let src = ShioriString::clone_from_str_charset_nofree(text).unwrap();
+        assert_eq!(src.len(), text.len() - 2);
+        let dst = ShioriString::capture(src.handle(), src.len());
+        assert_eq!(dst.to_charset_str().unwrap(), text);
+    }
     {
         let text = "適当なShioriString";
         let string = text.to_owned();
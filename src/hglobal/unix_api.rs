@@ -0,0 +1,73 @@
+//! SHIORI shared library memory and string converters for UNIX like systems.
+//! HGLOBALは`char*`に、GlobalAlloc/GlobalFreeはmalloc/freeに置き換えます。
+//! 8-bit文字列はUTF-8として扱います。
+
+#![allow(dead_code)]
+
+use super::enc::Encoder;
+use std::ffi::c_void;
+use std::io::{Error, ErrorKind, Result};
+
+/// `char*`に相当するハンドルです。
+#[allow(clippy::upper_case_acronyms)]
+pub type HGLOBAL = *mut c_void;
+
+pub const CP_ACP: u32 = 0;
+pub const CP_OEMCP: u32 = 1;
+pub const CP_UTF8: u32 = 65001;
+
+/// `malloc(len)`で領域を確保します。
+pub unsafe fn global_alloc(len: usize) -> HGLOBAL {
+    // malloc(0)はNULLを返す場合があるため、最低1byte確保する。
+    unsafe { libc::malloc(len.max(1)) }
+}
+
+/// `free(h)`で領域を開放します。
+pub unsafe fn global_free(h: HGLOBAL) {
+    unsafe { libc::free(h) }
+}
+
+/// コードページによる変換です。UTF-8(ANSI/OEMを含む)のみ扱えます。
+pub struct EncoderCodePage(pub u32);
+
+impl EncoderCodePage {
+    fn check(&self) -> Result<()> {
+        match self.0 {
+            CP_ACP | CP_OEMCP | CP_UTF8 => Ok(()),
+            cp => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("codepage {} is not supported", cp),
+            )),
+        }
+    }
+}
+
+impl Encoder for EncoderCodePage {
+    /// Convert from bytes to string.
+    fn to_string(&self, data: &[u8]) -> Result<String> {
+        self.check()?;
+        String::from_utf8(data.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Convert from string to bytes.
+    fn to_bytes(&self, data: &str) -> Result<Vec<u8>> {
+        self.check()?;
+        Ok(data.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_encoder_test() {
+        assert_eq!(EncoderCodePage(CP_ACP).to_string(b"Test").unwrap(), "Test");
+        assert_eq!(
+            EncoderCodePage(CP_UTF8).to_bytes("Тест").unwrap(),
+            "Тест".as_bytes()
+        );
+        assert!(EncoderCodePage(CP_UTF8).to_string(b"Test\xC0").is_err());
+        assert!(EncoderCodePage(932).to_bytes("Test").is_err());
+    }
+}
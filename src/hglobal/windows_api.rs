@@ -9,6 +9,24 @@ use std::io::{Error, ErrorKind, Result};
 use std::os::windows::ffi::OsStrExt;
 use std::ptr;
 use windows_sys::Win32::Globalization::*;
+use windows_sys::Win32::System::Memory::*;
+
+pub use windows_sys::Win32::Foundation::HGLOBAL;
+pub use windows_sys::Win32::Globalization::{CP_ACP, CP_OEMCP, CP_UTF8};
+
+const GMEM_FIXED: u32 = 0;
+
+/// `GlobalAlloc(GMEM_FIXED, len)`で領域を確保します。
+pub unsafe fn global_alloc(len: usize) -> HGLOBAL {
+    unsafe { GlobalAlloc(GMEM_FIXED, len as _) }
+}
+
+/// `GlobalFree(h)`で領域を開放します。
+pub unsafe fn global_free(h: HGLOBAL) {
+    unsafe {
+        GlobalFree(h);
+    }
+}
 
 /// Always use precomposed characters, that is, characters having a single character value for
 /// a base or nonspacing character combination.
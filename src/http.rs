@@ -0,0 +1,418 @@
+use crate::api::{RawShiori3, Shiori3};
+use crate::error::*;
+use crate::hglobal::{ShioriString, load_dir_bytes};
+use crate::parsers::codec;
+use crate::parsers::req::OwnedShioriRequest;
+use crate::parsers::res::ShioriResponse;
+use log::*;
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// リクエスト本文の最大長です。
+const MAX_BODY: usize = codec::DEFAULT_MAX_SIZE;
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// `HttpBridge`の待ち受けを止めるハンドルです。
+#[derive(Clone)]
+pub struct HttpShutdown(Arc<Server>);
+
+impl HttpShutdown {
+    /// 待ち受けを止めます。`HttpBridge::run()`はunloadして戻ります。
+    pub fn shutdown(&self) {
+        self.0.unblock();
+    }
+}
+
+/// `Shiori3`をlocalhostのHTTPで公開します。
+/// ブラウザ上のベースウェアやWebのツールから利用してください。
+/// 待ち受けはループバックアドレスに限ります。
+/// DNSリバインディングやCSRFを防ぐため、Hostがループバックでないリクエストと、
+/// ループバック以外のOriginを持つリクエストは`403`で拒否します。
+///
+/// - `POST /shiori`: 本文のSHIORIリクエストを送り、応答をそのまま返します。
+///   文字コードはCharsetヘッダに従います。SHIORIがNULLを返すと`502`です。
+/// - `POST /shiori.json`: `OwnedShioriRequest`のJSONを送り、`ShioriResponse`のJSONを返します。
+///   形式は`serde`フィーチャーのシリアライズと同じです。Charsetを省略するとUTF-8です。
+///
+/// `run()`の開始時にload、`HttpShutdown::shutdown()`で止まるとunloadを呼び出します。
+pub struct HttpBridge<T = ()> {
+    server: Arc<Server>,
+    shiori: RawShiori3<T>,
+    load_dir: PathBuf,
+    loaded: bool,
+}
+
+impl<T: Shiori3> HttpBridge<T> {
+    /// `Shiori3`の実装`T`を公開するブリッジを作成します。
+    pub fn bind<A: ToSocketAddrs, P: AsRef<Path>>(addr: A, load_dir: P) -> MyResult<HttpBridge<T>> {
+        HttpBridge::with_raw(addr, RawShiori3::default(), load_dir)
+    }
+}
+
+impl<T> HttpBridge<T> {
+    /// 作成済みの`RawShiori3`を公開するブリッジを作成します。
+    /// ループバック以外のアドレスはエラーです。
+    pub fn with_raw<A: ToSocketAddrs, P: AsRef<Path>>(
+        addr: A,
+        shiori: RawShiori3<T>,
+        load_dir: P,
+    ) -> MyResult<HttpBridge<T>> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+            return Err(MyError::Io {
+                kind: std::io::ErrorKind::PermissionDenied,
+                message: format!("not a loopback address: {}", addr),
+            });
+        }
+        let server = Server::http(&addrs[..]).map_err(|e| MyError::Io {
+            kind: std::io::ErrorKind::AddrNotAvailable,
+            message: e.to_string(),
+        })?;
+        Ok(HttpBridge {
+            server: Arc::new(server),
+            shiori,
+            load_dir: load_dir.as_ref().to_path_buf(),
+            loaded: false,
+        })
+    }
+
+    /// 待ち受けているアドレスを返します。
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// 待ち受けを止めるハンドルを返します。
+    pub fn shutdown_handle(&self) -> HttpShutdown {
+        HttpShutdown(self.server.clone())
+    }
+
+    /// 公開している`RawShiori3`を返します。
+    pub fn shiori_mut(&mut self) -> &mut RawShiori3<T> {
+        &mut self.shiori
+    }
+
+    /// loadを呼び出し、止められるまでリクエストを処理してからunloadを呼び出します。
+    pub fn run(&mut self) -> MyResult<()> {
+        self.load()?;
+        let server = self.server.clone();
+        for req in server.incoming_requests() {
+            self.serve(req);
+        }
+        if !self.unload() {
+            return Err(MyError::Others);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self) -> MyResult<()> {
+        let bytes = load_dir_bytes(&self.load_dir)?;
+        let (h, len) = ShioriString::clone_from_slice_nofree(&bytes).value();
+        if !self.shiori.raw_load(h, len) {
+            return Err(MyError::Load);
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    fn unload(&mut self) -> bool {
+        if !self.loaded {
+            return true;
+        }
+        self.loaded = false;
+        self.shiori.raw_unload()
+    }
+
+    /// SHIORIリクエストを送ります。NULLの場合は`None`です。
+    fn request(&mut self, text: &str) -> MyResult<Option<String>> {
+        let (h, mut len) = ShioriString::clone_from_str_charset_nofree(text)?.value();
+        let h = self.shiori.raw_request(h, &mut len);
+        if h.is_null() {
+            return Ok(None);
+        }
+        let res = ShioriString::capture(h, len);
+        Ok(Some(res.to_charset_str()?.into_owned()))
+    }
+
+    fn serve(&mut self, mut req: Request) {
+        if !is_local_request(&req) {
+            let res = error_response(403, "forbidden host or origin");
+            if let Err(e) = req.respond(res) {
+                warn!("[http] respond: {}", e);
+            }
+            return;
+        }
+        let mut body = Vec::new();
+        let read = req
+            .as_reader()
+            .take(MAX_BODY as u64 + 1)
+            .read_to_end(&mut body);
+        let res = match (req.method(), req.url(), read) {
+            (_, _, Err(e)) => error_response(400, &e.to_string()),
+            (_, _, Ok(_)) if body.len() > MAX_BODY => error_response(413, "request too large"),
+            (Method::Post, "/shiori", _) => self.serve_text(&body),
+            (Method::Post, "/shiori.json", _) => self.serve_json(&body),
+            (_, "/shiori" | "/shiori.json", _) => error_response(405, "method not allowed"),
+            _ => error_response(404, "not found"),
+        };
+        if let Err(e) = req.respond(res) {
+            warn!("[http] respond: {}", e);
+        }
+    }
+
+    fn serve_text(&mut self, body: &[u8]) -> HttpResponse {
+        let text = match codec::decode(body) {
+            Ok(text) => text,
+            Err(e) => return error_response(400, &e.to_string()),
+        };
+        let res = match self.request(&text) {
+            Ok(Some(res)) => res,
+            Ok(None) => return error_response(502, "no response"),
+            Err(e) => return error_response(500, &e.to_string()),
+        };
+        let charset = codec::charset(res.as_bytes())
+            .unwrap_or("UTF-8")
+            .to_string();
+        match codec::encode(&res) {
+            Ok(bytes) => Response::from_data(bytes)
+                .with_header(content_type(&format!("text/plain; charset={}", charset))),
+            Err(e) => error_response(500, &e.to_string()),
+        }
+    }
+
+    fn serve_json(&mut self, body: &[u8]) -> HttpResponse {
+        let mut req: OwnedShioriRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(e) => return error_response(400, &e.to_string()),
+        };
+        req.charset.get_or_insert_with(|| "UTF-8".into());
+        if let Err(e) = req.check() {
+            return error_response(400, &e.to_string());
+        }
+        let text = req.to_text();
+        let res = match self.request(&text) {
+            Ok(Some(res)) => res,
+            Ok(None) => return error_response(502, "no response"),
+            Err(e) => return error_response(500, &e.to_string()),
+        };
+        let json = ShioriResponse::parse(&res)
+            .map_err(|e| e.to_string())
+            .and_then(|res| serde_json::to_vec(&res).map_err(|e| e.to_string()));
+        match json {
+            Ok(json) => Response::from_data(json).with_header(content_type("application/json")),
+            Err(e) => error_response(502, &e),
+        }
+    }
+}
+
+impl<T> Drop for HttpBridge<T> {
+    fn drop(&mut self) {
+        self.unload();
+    }
+}
+
+/// Hostがループバックで、Originがないかループバックのリクエストかどうかを返します。
+fn is_local_request(req: &Request) -> bool {
+    let header = |name: &'static str| {
+        req.headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str())
+    };
+    let origin = header("Origin").is_none_or(|origin| {
+        origin.split_once("://").is_some_and(|(scheme, host)| {
+            matches!(scheme, "http" | "https") && is_loopback_host(host)
+        })
+    });
+    origin && header("Host").is_some_and(is_loopback_host)
+}
+
+/// `host[:port]`のホストがlocalhostかループバックアドレスかどうかを返します。
+fn is_loopback_host(host: &str) -> bool {
+    let host = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+fn error_response(code: u16, message: &str) -> HttpResponse {
+    Response::from_data(message.as_bytes().to_vec())
+        .with_status_code(code)
+        .with_header(content_type("text/plain; charset=UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ServiceHandler, ShioriHandler, ShioriService};
+    use crate::context::RequestContext;
+    use crate::parsers::req::ShioriRequest;
+    use crate::parsers::res::Status;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    struct Ghost {
+        unloaded: Arc<AtomicBool>,
+    }
+
+    impl ShioriService for Ghost {
+        fn request(
+            &mut self,
+            req: &ShioriRequest,
+            _ctx: &mut RequestContext,
+        ) -> Result<ShioriResponse, anyhow::Error> {
+            let refs: Vec<&str> = req.reference.iter().map(|(_, r)| *r).collect();
+            Ok(ShioriResponse::ok(format!(
+                "{}({})",
+                req.id.unwrap_or_default(),
+                refs.join(",")
+            )))
+        }
+
+        fn unload(&mut self) -> Result<(), anyhow::Error> {
+            self.unloaded.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// 1回のHTTPリクエストを送り、ステータスと本文を返します。
+    fn post(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        send(addr, method, path, "Host: localhost\r\n", body)
+    }
+
+    /// 任意のヘッダを付けてHTTPリクエストを送ります。
+    fn send(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &str,
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\n{}Connection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        let code = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (code, body.to_string())
+    }
+
+    #[test]
+    fn http_bridge() {
+        let unloaded = Arc::new(AtomicBool::new(false));
+        let flag = unloaded.clone();
+        let (tx, rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let factory = move |_h_inst: usize, _load_dir: &Path, _bytes: &[u8]| {
+                let shiori: Box<dyn ShioriHandler> = ServiceHandler::boxed(Ghost {
+                    unloaded: flag.clone(),
+                });
+                Ok(shiori)
+            };
+            let shiori = RawShiori3::with_factory(factory);
+            let mut bridge = HttpBridge::with_raw("127.0.0.1:0", shiori, "ghost/master").unwrap();
+            tx.send((bridge.local_addr().unwrap(), bridge.shutdown_handle()))
+                .unwrap();
+            bridge.run().unwrap();
+        });
+        let (addr, shutdown) = rx.recv().unwrap();
+
+        let (code, body) = post(
+            addr,
+            "POST",
+            "/shiori",
+            "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\nReference0: master\r\n\r\n",
+        );
+        assert_eq!(code, 200);
+        let res = ShioriResponse::parse(&body).unwrap();
+        assert_eq!(res.value(), Some("OnBoot(master)"));
+
+        let json = r#"{"method": "NOTIFY", "id": "OnTest",
+            "reference": [[0, "a"], [1, "1"], [2, ""]], "headers": [["X-Extra", "1"]]}"#;
+        let (code, body) = post(addr, "POST", "/shiori.json", json);
+        assert_eq!(code, 200);
+        let res: ShioriResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.status, Status::Ok);
+        assert_eq!(res.value(), Some("OnTest(a,1,)"));
+        assert_eq!(res.header("Charset"), Some("UTF-8"));
+        let back = serde_json::to_value(&res).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            back
+        );
+
+        for json in [
+            "[]",
+            r#"{"method": "PUT"}"#,
+            r#"{"method": "GET", "reference": ["a"]}"#,
+            r#"{"method": "GET", "headers": {"X-Extra": "1"}}"#,
+            r#"{"method": "GET", "headers": [["ID", "OnEvil"]]}"#,
+            r#"{"method": "GET", "headers": [["X Bad", "1"]]}"#,
+            r#"{"method": "GET", "headers": [["X-Evil", "a\r\nID: OnEvil"]]}"#,
+        ] {
+            assert_eq!(post(addr, "POST", "/shiori.json", json).0, 400, "{}", json);
+        }
+        assert_eq!(post(addr, "GET", "/shiori", "").0, 405);
+
+        let boot = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\n\r\n";
+        let host = format!(
+            "Host: 127.0.0.1:{}\r\nOrigin: http://localhost:3000\r\n",
+            addr.port()
+        );
+        assert_eq!(send(addr, "POST", "/shiori", &host, boot).0, 200);
+        let rebinding = "Host: evil.example\r\n";
+        assert_eq!(send(addr, "POST", "/shiori", rebinding, boot).0, 403);
+        let csrf = "Host: localhost\r\nOrigin: http://evil.example\r\n";
+        assert_eq!(send(addr, "POST", "/shiori", csrf, boot).0, 403);
+        assert_eq!(send(addr, "POST", "/shiori", "", boot).0, 403);
+        assert_eq!(post(addr, "POST", "/other", "").0, 404);
+
+        assert!(!unloaded.load(Ordering::SeqCst));
+        shutdown.shutdown();
+        server.join().unwrap();
+        assert!(unloaded.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn loopback_only() {
+        let e = HttpBridge::<()>::with_raw(
+            "0.0.0.0:0",
+            RawShiori3::with_factory(|_: usize, _: &Path, _: &[u8]| {
+                let shiori: Box<dyn ShioriHandler> = ServiceHandler::boxed(Ghost {
+                    unloaded: Default::default(),
+                });
+                Ok(shiori)
+            }),
+            "ghost",
+        )
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("not a loopback address"));
+        assert!(is_loopback_host("localhost:8080"));
+        assert!(is_loopback_host("[::1]:8080"));
+        assert!(is_loopback_host("127.0.0.2"));
+        assert!(!is_loopback_host("localhost.evil.example"));
+        assert!(!is_loopback_host("192.168.0.1:80"));
+    }
+}
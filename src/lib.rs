@@ -1,13 +1,92 @@
 mod api;
+#[cfg(feature = "client")]
+mod client;
+mod clock;
+mod composite;
+mod context;
+mod crash;
 mod error;
+mod fs;
 mod hglobal;
+#[cfg(feature = "http")]
+mod http;
+mod middleware;
 mod parsers;
+#[cfg(feature = "client")]
+mod proxy;
+mod random;
+mod scheduler;
+pub mod script;
+mod services;
+mod simulator;
+mod stdio_host;
+mod store;
+mod trace;
+mod trace_log;
 
 pub use crate::api::RawShiori3;
+pub use crate::api::ServiceHandler;
 pub use crate::api::Shiori3;
+pub use crate::api::Shiori3DI;
+pub use crate::api::Shiori3Factory;
+pub use crate::api::ShioriFactory;
+pub use crate::api::ShioriHandler;
+pub use crate::api::ShioriService;
+#[cfg(feature = "client")]
+pub use crate::client::ShioriClient;
+pub use crate::clock::Clock;
+pub use crate::clock::SystemClock;
+pub use crate::clock::VirtualClock;
+pub use crate::composite::Children;
+pub use crate::composite::Composite;
+pub use crate::context::Extensions;
+pub use crate::context::RequestContext;
+pub use crate::crash::CrashRecorder;
 pub use crate::error::MyError as ShioriError;
 pub use crate::error::MyResult as ShioriResult;
+pub use crate::fs::DiskFileSystem;
+pub use crate::fs::FileSystem;
+pub use crate::fs::MemoryFileSystem;
 pub use crate::hglobal::enc::Encoder;
 pub use crate::hglobal::enc::Encoding;
+pub use crate::hglobal::HGLOBAL;
 pub use crate::hglobal::ShioriString;
+#[cfg(feature = "http")]
+pub use crate::http::HttpBridge;
+#[cfg(feature = "http")]
+pub use crate::http::HttpShutdown;
+pub use crate::middleware::LogLayer;
+pub use crate::middleware::Middleware;
+pub use crate::middleware::Next;
+pub use crate::parsers::codec;
 pub use crate::parsers::req;
+pub use crate::parsers::res;
+pub use crate::parsers::sstp;
+#[cfg(feature = "client")]
+pub use crate::proxy::ProxyFactory;
+#[cfg(feature = "client")]
+pub use crate::proxy::ProxyShiori;
+pub use crate::random::RandomSource;
+pub use crate::random::SeededRandom;
+pub use crate::random::SystemRandom;
+pub use crate::scheduler::DEFAULT_BLOCKING_STATUS;
+pub use crate::scheduler::TalkScheduler;
+pub use crate::script::SakuraScript;
+pub use crate::services::Services;
+pub use crate::simulator::BasewareSimulator;
+pub use crate::simulator::Exchange;
+pub use crate::stdio_host::DEFAULT_WORKER_TIMEOUT;
+pub use crate::stdio_host::StdioHost;
+pub use crate::stdio_host::StdioWorker;
+pub use crate::stdio_host::WorkerConnection;
+pub use crate::store::FromStoreValue;
+pub use crate::store::Store;
+pub use crate::store::StoreValue;
+pub use crate::trace::HeaderDiff;
+pub use crate::trace::Replay;
+pub use crate::trace::ReplayDiff;
+pub use crate::trace::ReplayReport;
+pub use crate::trace::format_exchange;
+pub use crate::trace::parse_trace;
+pub use crate::trace_log::TraceLogger;
+pub use crate::trace_log::TraceSwitch;
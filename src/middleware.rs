@@ -0,0 +1,173 @@
+use crate::api::ShioriHandler;
+use crate::context::RequestContext;
+use log::*;
+use std::borrow::Cow;
+
+/// リクエスト処理の前後に割り込むミドルウェアです。
+/// `next.run()`を呼ばずに応答を返すと、以降の処理を打ち切ります。
+/// リクエストを書き換える場合は、書き換えた文字列で`next.run()`を呼び、
+/// 応答を`Cow::Owned`にして返してください。
+pub trait Middleware: Send {
+    /// リクエストを処理します。
+    fn call<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+        next: Next<'_>,
+    ) -> Result<Cow<'a, str>, anyhow::Error>;
+
+    /// SHIORIインスタンスの終了処理を行います。
+    fn unload(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// 後続のミドルウェアとハンドラです。
+pub struct Next<'n> {
+    layers: &'n mut [Box<dyn Middleware>],
+    inner: &'n mut dyn ShioriHandler,
+}
+
+impl<'n> Next<'n> {
+    pub(crate) fn new(
+        layers: &'n mut [Box<dyn Middleware>],
+        inner: &'n mut dyn ShioriHandler,
+    ) -> Next<'n> {
+        Next { layers, inner }
+    }
+
+    /// 後続の処理を呼び出します。
+    pub fn run<'a>(
+        self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        match self.layers.split_first_mut() {
+            Some((layer, layers)) => layer.call(
+                req,
+                ctx,
+                Next {
+                    layers,
+                    inner: self.inner,
+                },
+            ),
+            None => self.inner.handle(req, ctx),
+        }
+    }
+}
+
+/// リクエストのIDと処理時間をログに出力するミドルウェアです。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LogLayer {
+    level: Level,
+}
+
+impl Default for LogLayer {
+    fn default() -> LogLayer {
+        LogLayer {
+            level: Level::Debug,
+        }
+    }
+}
+
+impl LogLayer {
+    pub fn new(level: Level) -> LogLayer {
+        LogLayer { level }
+    }
+}
+
+impl Middleware for LogLayer {
+    fn call<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+        next: Next<'_>,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        let id = req
+            .lines()
+            .find_map(|line| line.strip_prefix("ID: "))
+            .unwrap_or("-");
+        let clock = ctx.clock().clone();
+        let start = clock.now();
+        let rc = next.run(req, ctx);
+        let elapsed = clock.now().duration_since(start).unwrap_or_default();
+        let status = match &rc {
+            Ok(res) => res.lines().next().unwrap_or(""),
+            Err(_) => "error",
+        };
+        log!(
+            self.level,
+            "[request] #{} {} -> {} ({:?})",
+            ctx.sequence,
+            id,
+            status,
+            elapsed
+        );
+        rc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Shiori3DI;
+    use std::time::UNIX_EPOCH;
+
+    struct Echo;
+
+    impl ShioriHandler for Echo {
+        fn handle<'a>(
+            &mut self,
+            req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            Ok(Cow::Borrowed(req))
+        }
+    }
+
+    /// リクエストの前後に印を付けます。
+    struct Mark(&'static str);
+
+    impl Middleware for Mark {
+        fn call<'a>(
+            &mut self,
+            req: &'a str,
+            ctx: &mut RequestContext,
+            next: Next<'_>,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            let req = format!("{}{}", req, self.0);
+            let res = next.run(&req, ctx)?;
+            Ok(Cow::Owned(format!("{}{}", res, self.0.to_uppercase())))
+        }
+    }
+
+    /// "stop"を含むリクエストに即座に応答します。
+    struct Stop;
+
+    impl Middleware for Stop {
+        fn call<'a>(
+            &mut self,
+            req: &'a str,
+            ctx: &mut RequestContext,
+            next: Next<'_>,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            if req.contains("stop") {
+                return Ok(Cow::Borrowed("stopped"));
+            }
+            next.run(req, ctx)
+        }
+    }
+
+    #[test]
+    fn middleware_order() {
+        let mut ctx = RequestContext::new(1, UNIX_EPOCH);
+        let mut shiori = Shiori3DI::new(Echo)
+            .with_layer(LogLayer::default())
+            .with_layer(Mark("a"))
+            .with_layer(Stop)
+            .with_layer(Mark("b"));
+        assert_eq!(shiori.handle("req:", &mut ctx).unwrap(), "req:abBA");
+        assert_eq!(shiori.handle("stop:", &mut ctx).unwrap(), "stoppedA");
+        assert!(shiori.close().is_ok());
+    }
+}
@@ -0,0 +1,326 @@
+//! SHIORIメッセージのストリーム用の符号化と復号です。
+//!
+//! パイプやソケット、SSTPではバイト列が分割されて届くため、
+//! `MessageDecoder`に少しずつ渡し、空行で終わるメッセージ単位で取り出します。
+//! 改行はCRLFのほか、文法の`_eol`と同じくLFとCRも受け付けます。
+
+use crate::error::*;
+use crate::hglobal::charset_encoder;
+use crate::hglobal::enc::Encoder;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str;
+
+/// 既定のメッセージの最大長です。
+pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+/// 改行を探した結果です。
+enum Eol {
+    /// 改行の位置と長さです。
+    Found(usize, usize),
+    /// 末尾のCRの後にLFが続くかどうか、まだ分かりません。
+    Pending,
+    /// 改行がありません。
+    None,
+}
+
+/// `start`以降の最初の改行を探します。
+fn find_eol(bytes: &[u8], start: usize, eof: bool) -> Eol {
+    let pos = match bytes[start..]
+        .iter()
+        .position(|b| *b == b'\r' || *b == b'\n')
+    {
+        Some(pos) => start + pos,
+        None => return Eol::None,
+    };
+    match (bytes[pos], bytes.get(pos + 1)) {
+        (b'\n', _) => Eol::Found(pos, 1),
+        (_, Some(b'\n')) => Eol::Found(pos, 2),
+        (_, Some(_)) => Eol::Found(pos, 1),
+        (_, None) if eof => Eol::Found(pos, 1),
+        (_, None) => Eol::Pending,
+    }
+}
+
+/// ヘッダ部の行を返します。空行で終わります。
+fn header_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if start >= bytes.len() {
+            return None;
+        }
+        let (end, next) = match find_eol(bytes, start, true) {
+            Eol::Found(pos, len) => (pos, pos + len),
+            _ => (bytes.len(), bytes.len()),
+        };
+        let line = &bytes[start..end];
+        start = next;
+        Some(line)
+    })
+    .take_while(|line| !line.is_empty())
+}
+
+/// メッセージのCharsetヘッダの値を返します。
+pub fn charset(bytes: &[u8]) -> Option<&str> {
+    header_lines(bytes)
+        .skip(1)
+        .find_map(|line| line.strip_prefix(b"Charset:"))
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim())
+}
+
+/// メッセージのバイト列をCharsetヘッダに従って文字列に変換します。
+/// Charsetヘッダがない場合はUTF-8とみなします。
+pub fn decode(bytes: &[u8]) -> MyResult<String> {
+    match charset_encoder(charset(bytes))? {
+        None => Ok(str::from_utf8(bytes)?.to_string()),
+        Some(enc) => enc.to_string(bytes).map_err(|_| MyError::EncodeAnsi),
+    }
+}
+
+/// メッセージをCharsetヘッダに従ってバイト列に変換します。
+/// 空行で終わっていなければCRLFを補います。
+pub fn encode(text: &str) -> MyResult<Vec<u8>> {
+    let text = match text {
+        t if t.ends_with("\r\n\r\n") || t.ends_with("\n\n") || t.ends_with("\r\r") => {
+            Cow::Borrowed(t)
+        }
+        t if t.ends_with('\n') || t.ends_with('\r') => Cow::Owned(format!("{}\r\n", t)),
+        t => Cow::Owned(format!("{}\r\n\r\n", t)),
+    };
+    match charset_encoder(charset(text.as_bytes()))? {
+        None => Ok(text.into_owned().into_bytes()),
+        Some(enc) => enc.to_bytes(&text).map_err(|_| MyError::EncodeAnsi),
+    }
+}
+
+/// メッセージを符号化して書き込みます。
+pub fn write_message<W: Write + ?Sized>(w: &mut W, text: &str) -> MyResult<()> {
+    w.write_all(&encode(text)?)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// 分割して届くバイト列からSHIORIメッセージを取り出します。
+/// 要求と応答のどちらも扱えます。メッセージの前の空行は読み飛ばします。
+///
+/// ```
+/// use shiori3::codec::MessageDecoder;
+///
+/// let mut decoder = MessageDecoder::new();
+/// decoder.push(b"GET SHIORI/3.0\r\nID: OnBoot\r");
+/// assert_eq!(decoder.next_message().unwrap(), None);
+/// decoder.push(b"\n\r\nNOTIFY SHIORI/3.0\n");
+/// let req = decoder.next_message().unwrap().unwrap();
+/// assert_eq!(req, "GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n");
+/// assert_eq!(decoder.buffered(), 18);
+/// ```
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+    /// 走査済みの行の先頭です。
+    line: usize,
+    max_size: usize,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        MessageDecoder::new()
+    }
+}
+
+impl MessageDecoder {
+    pub fn new() -> MessageDecoder {
+        MessageDecoder {
+            buf: Vec::new(),
+            line: 0,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// メッセージの最大長を設定します。超えると`MessageTooLarge`になります。
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// 受信したバイト列を追加します。
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// まだメッセージになっていないバイト数を返します。
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// 完全なメッセージのバイト列を取り出します。足りなければ`None`です。
+    pub fn next_bytes(&mut self) -> MyResult<Option<Vec<u8>>> {
+        self.next_bytes_impl(false)
+    }
+
+    /// 入力の終わりに呼び出します。末尾のCRを改行として扱います。
+    /// 空行で終わっていない残りがあればエラーです。
+    pub fn finish_bytes(&mut self) -> MyResult<Option<Vec<u8>>> {
+        if let Some(bytes) = self.next_bytes_impl(true)? {
+            return Ok(Some(bytes));
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let size = self.buf.len();
+        self.buf.clear();
+        self.line = 0;
+        let message = format!("incomplete message: {} bytes", size);
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, message).into())
+    }
+
+    /// 完全なメッセージをCharsetヘッダに従って文字列で取り出します。
+    pub fn next_message(&mut self) -> MyResult<Option<String>> {
+        self.next_bytes()?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    /// 入力の終わりに、残りのメッセージを文字列で取り出します。
+    pub fn finish_message(&mut self) -> MyResult<Option<String>> {
+        self.finish_bytes()?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    fn next_bytes_impl(&mut self, eof: bool) -> MyResult<Option<Vec<u8>>> {
+        loop {
+            let (pos, len) = match find_eol(&self.buf, self.line, eof) {
+                Eol::Found(pos, len) => (pos, len),
+                Eol::Pending | Eol::None => {
+                    if self.buf.len() > self.max_size {
+                        let size = self.buf.len();
+                        self.buf.clear();
+                        self.line = 0;
+                        return Err(MyError::MessageTooLarge(size));
+                    }
+                    return Ok(None);
+                }
+            };
+            let end = pos + len;
+            if pos != self.line {
+                self.line = end;
+                continue;
+            }
+            if pos == 0 {
+                // メッセージの前の空行です。
+                self.buf.drain(..end);
+                continue;
+            }
+            if end > self.max_size {
+                self.buf.drain(..end);
+                self.line = 0;
+                return Err(MyError::MessageTooLarge(end));
+            }
+            let rest = self.buf.split_off(end);
+            self.line = 0;
+            return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::req::ShioriRequest;
+    use crate::parsers::res::{ShioriResponse, Status};
+
+    #[test]
+    fn decode_chunks() {
+        let stream: &[u8] = b"\r\nGET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\n\r\n\
+            NOTIFY SHIORI/3.0\nID: OnSecondChange\n\n\
+            GET SHIORI/3.0\rCharset: default\rID: OnClose\r\r\
+            SHIORI/3.0 200 OK\r\nValue: \\0\\e\r\n\r\n";
+        // 1バイトずつ渡しても、一度に渡しても同じ結果になります。
+        for size in [1, 2, 7, stream.len()] {
+            let mut decoder = MessageDecoder::new();
+            let mut messages = Vec::new();
+            for chunk in stream.chunks(size) {
+                decoder.push(chunk);
+                while let Some(message) = decoder.next_message().unwrap() {
+                    messages.push(message);
+                }
+            }
+            assert_eq!(decoder.buffered(), 0);
+            assert_eq!(messages.len(), 4);
+            let ids: Vec<_> = messages[..3]
+                .iter()
+                .map(|m| ShioriRequest::parse(m).unwrap().id.unwrap())
+                .collect();
+            assert_eq!(ids, ["OnBoot", "OnSecondChange", "OnClose"]);
+            let res = ShioriResponse::parse(&messages[3]).unwrap();
+            assert_eq!(res.status, Status::Ok);
+            assert_eq!(res.value(), Some("\\0\\e"));
+        }
+    }
+
+    #[test]
+    fn decode_finish() {
+        // 末尾のCRはLFが続くかもしれないため、入力の終わりまで待ちます。
+        let mut decoder = MessageDecoder::new();
+        decoder.push(b"GET SHIORI/3.0\rID: OnBoot\r\r");
+        assert_eq!(decoder.next_bytes().unwrap(), None);
+        let req = decoder.finish_message().unwrap().unwrap();
+        assert_eq!(req, "GET SHIORI/3.0\rID: OnBoot\r\r");
+        assert_eq!(decoder.finish_bytes().unwrap(), None);
+
+        decoder.push(b"GET SHIORI/3.0\r\n");
+        assert!(matches!(
+            decoder.finish_bytes(),
+            Err(MyError::Io {
+                kind: io::ErrorKind::UnexpectedEof,
+                ..
+            })
+        ));
+        assert_eq!(decoder.buffered(), 0);
+
+        let mut decoder = MessageDecoder::new().with_max_size(16);
+        decoder.push(b"GET SHIORI/3.0\r\nID: OnBoot");
+        assert_eq!(decoder.next_bytes(), Err(MyError::MessageTooLarge(26)));
+        decoder.push(b"GET SHIORI/3.0\r\n\r\n");
+        assert_eq!(decoder.next_bytes(), Err(MyError::MessageTooLarge(18)));
+        decoder.push(b"\r\nGET\r\n\r\n");
+        assert_eq!(decoder.next_bytes().unwrap().unwrap(), b"GET\r\n\r\n");
+
+        let mut decoder = MessageDecoder::new();
+        decoder.push(b"GET SHIORI/3.0\r\nCharset: unknown\r\n\r\n");
+        assert_eq!(
+            decoder.next_message(),
+            Err(MyError::UnknownCharset("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn encode_message() {
+        let text = "SHIORI/3.0 204 No Content\r\nCharset: UTF-8\r\n\r\n";
+        assert_eq!(encode(text).unwrap(), text.as_bytes());
+        assert_eq!(
+            encode("SHIORI/3.0 204 No Content\r\nCharset: UTF-8\r\n").unwrap(),
+            text.as_bytes()
+        );
+        assert_eq!(
+            encode("SHIORI/3.0 204 No Content\r\nCharset: UTF-8").unwrap(),
+            text.as_bytes()
+        );
+        assert_eq!(
+            encode("GET SHIORI/3.0\r\nCharset: unknown"),
+            Err(MyError::UnknownCharset("unknown".into()))
+        );
+
+        let mut out = Vec::new();
+        write_message(&mut out, "GET SHIORI/3.0\nCharset: default\nID: 起動\n").unwrap();
+        write_message(&mut out, "GET SHIORI/3.0\r\nID: OnClose").unwrap();
+        assert_eq!(charset(&out), Some("default"));
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&out);
+        assert_eq!(
+            decoder.next_message().unwrap().unwrap(),
+            "GET SHIORI/3.0\nCharset: default\nID: 起動\n\r\n"
+        );
+        assert_eq!(
+            decoder.next_message().unwrap().unwrap(),
+            "GET SHIORI/3.0\r\nID: OnClose\r\n\r\n"
+        );
+    }
+}
@@ -1,2 +1,7 @@
+pub mod codec;
 pub mod req;
 pub mod req_parser;
+pub mod res;
+pub mod sstp;
+#[cfg(feature = "serde")]
+mod serde_impl;
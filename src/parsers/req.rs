@@ -3,6 +3,7 @@ use pest;
 use pest::Parser as PestParser;
 use pest::iterators::FlatPairs;
 use std::collections::HashMap;
+use std::fmt;
 
 pub use super::req_parser::Rule;
 pub use super::req_parser::ShioriRequestParser as Parser;
@@ -23,6 +24,7 @@ pub struct ShioriRequest<'a> {
     pub base_id: Option<&'a str>,
     pub reference: Vec<(i32, &'a str)>,
     pub dic: HashMap<String, &'a str>,
+    /// 全てのヘッダをキーの種類とともに出現順に格納します。
     pub key_values: Vec<(Rule, &'a str, &'a str)>,
 }
 
@@ -52,6 +54,37 @@ impl<'a> ShioriRequest<'a> {
         }
     }
 
+    /// リクエストのメソッドを返します。
+    pub fn method(&self) -> Option<Method> {
+        Method::from_rule(self.method)
+    }
+
+    /// 所有するリクエストに変換します。
+    pub fn to_owned_request(&self) -> OwnedShioriRequest {
+        let owned = |v: Option<&str>| v.map(|v| v.to_string());
+        OwnedShioriRequest {
+            method: self.method().unwrap_or(Method::Get),
+            version: self.version,
+            id: owned(self.id),
+            sender: owned(self.sender),
+            security_level: owned(self.security_level),
+            charset: owned(self.charset),
+            status: owned(self.status),
+            base_id: owned(self.base_id),
+            reference: self
+                .reference
+                .iter()
+                .map(|(i, v)| (*i, v.to_string()))
+                .collect(),
+            headers: self
+                .key_values
+                .iter()
+                .filter(|(rule, _, _)| *rule == Rule::key_other)
+                .map(|(_, k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
     #[allow(dead_code)]
     fn parse1(mut self, mut it: FlatPairs<'a, Rule>) -> MyResult<ShioriRequest<'a>> {
         let pair = match it.next() {
@@ -89,7 +122,15 @@ impl<'a> ShioriRequest<'a> {
         let key = pair.as_str();
         let value = match rule {
             Rule::key_ref => {
-                let nums = it.next().unwrap().as_str().parse().unwrap();
+                let pair = it.next().unwrap();
+                let nums = pair.as_str().parse().map_err(|_| {
+                    ParseError::new_from_span(
+                        pest::error::ErrorVariant::CustomError {
+                            message: "reference number out of range".into(),
+                        },
+                        pair.as_span(),
+                    )
+                })?;
                 let value = it.next().unwrap().as_str();
                 self.reference.push((nums, value));
                 value
@@ -110,10 +151,224 @@ impl<'a> ShioriRequest<'a> {
             }
         };
         self.dic.entry(key.into()).or_insert(value);
+        self.key_values.push((rule, key, value));
         Ok(())
     }
 }
 
+/// SHIORIリクエストのメソッドです。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Method {
+    #[cfg_attr(feature = "serde", serde(rename = "GET"))]
+    Get,
+    #[cfg_attr(feature = "serde", serde(rename = "NOTIFY"))]
+    Notify,
+}
+
+impl Method {
+    /// 解析結果の`Rule`から値を求めます。
+    pub fn from_rule(rule: Rule) -> Option<Method> {
+        match rule {
+            Rule::get => Some(Method::Get),
+            Rule::notify => Some(Method::Notify),
+            _ => None,
+        }
+    }
+
+    /// メソッド名を返します。
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Notify => "NOTIFY",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 文字列を借用しないSHIORIリクエストです。
+/// 保存や送信のために`ShioriRequest::to_owned_request()`で作成します。
+/// `serde`フィーチャーでは次の形式でシリアライズします。
+///
+/// ```json
+/// {"method": "GET", "version": "3.0", "id": "OnBoot",
+///  "sender": "SSP", "security_level": "local", "charset": "UTF-8",
+///  "status": null, "base_id": null,
+///  "reference": [[0, "master"]],
+///  "headers": [["X-Extra", "1"]]}
+/// ```
+///
+/// `headers`は型付きのフィールド以外のヘッダを出現順に格納します。
+/// `to_text()`はフィールドだけから文字列を作ります。
+/// デシリアライズでは`version`の既定は`"3.0"`で、`reference`と`headers`は省略できます。
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedShioriRequest {
+    pub method: Method,
+    /// `30`ならSHIORI/3.0、`26`ならSHIORI/2.6です。
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "super::serde_impl::version",
+            default = "super::serde_impl::version::default"
+        )
+    )]
+    pub version: i32,
+    pub id: Option<String>,
+    pub sender: Option<String>,
+    pub security_level: Option<String>,
+    pub charset: Option<String>,
+    pub status: Option<String>,
+    pub base_id: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reference: Vec<(i32, String)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub headers: Vec<(String, String)>,
+}
+
+impl OwnedShioriRequest {
+    /// リクエスト文字列を作成します。
+    /// ヘッダはCharset、Sender、SecurityLevel、Status、BaseID、ID、Reference、
+    /// `headers`の順です。SHIORI/2.xではIDを開始行に置きます。
+    pub fn to_text(&self) -> String {
+        let mut text = match self.version {
+            30.. => format!("{} SHIORI/3.0\r\n", self.method),
+            v => format!(
+                "{} {} SHIORI/2.{}\r\n",
+                self.method,
+                self.id.as_deref().unwrap_or_default(),
+                v - 20
+            ),
+        };
+        let id = match self.version {
+            30.. => self.id.as_deref(),
+            _ => None,
+        };
+        let fields = [
+            ("Charset", self.charset.as_deref()),
+            ("Sender", self.sender.as_deref()),
+            ("SecurityLevel", self.security_level.as_deref()),
+            ("Status", self.status.as_deref()),
+            ("BaseID", self.base_id.as_deref()),
+            ("ID", id),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                text.push_str(&format!("{}: {}\r\n", key, value));
+            }
+        }
+        for (i, value) in &self.reference {
+            text.push_str(&format!("Reference{}: {}\r\n", i, value));
+        }
+        for (key, value) in &self.headers {
+            text.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        text.push_str("\r\n");
+        text
+    }
+
+    /// `to_text()`の文字列が同じリクエストとして読めるかどうかを検査します。
+    /// `headers`のキーが文法に合わないか型付きのフィールドのキー(IDやReference0等)である場合や、
+    /// 値が改行を含む場合はエラーです。
+    pub fn check(&self) -> MyResult<()> {
+        let text = self.to_text();
+        if ShioriRequest::parse(&text)?.to_owned_request() == *self {
+            return Ok(());
+        }
+        let error = ParseError::new_from_pos(
+            pest::error::ErrorVariant::CustomError {
+                message: "reserved header name or line break in a value".into(),
+            },
+            pest::Position::from_start(&text),
+        );
+        Err(error.into())
+    }
+}
+
+/// `ShioriEvent`に変換できるReferenceの番号の上限です。
+pub const MAX_EVENT_REFERENCES: usize = 1024;
+
+/// メソッドとIDとReferenceだけを持つイベントです。
+/// テストのフィクスチャや、ダッシュボードへの送信に利用してください。
+/// `serde`フィーチャーでは`{"method": "NOTIFY", "id": "OnBoot", "reference": ["master"]}`
+/// の形式になります。
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShioriEvent {
+    pub method: Method,
+    pub id: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reference: Vec<String>,
+}
+
+impl ShioriEvent {
+    /// `GET`イベントを作成します。
+    pub fn get<S: Into<String>>(id: S) -> ShioriEvent {
+        ShioriEvent {
+            method: Method::Get,
+            id: id.into(),
+            reference: Vec::new(),
+        }
+    }
+
+    /// `NOTIFY`イベントを作成します。
+    pub fn notify<S: Into<String>>(id: S) -> ShioriEvent {
+        ShioriEvent {
+            method: Method::Notify,
+            id: id.into(),
+            reference: Vec::new(),
+        }
+    }
+
+    /// Referenceを追加します。
+    pub fn with_reference<S: Into<String>>(mut self, value: S) -> Self {
+        self.reference.push(value.into());
+        self
+    }
+
+    /// リクエストからイベントを作成します。
+    /// IDがないか、Referenceの番号が`MAX_EVENT_REFERENCES`以上なら`None`です。
+    /// Referenceは番号順に並べ、欠けた番号は空文字列にします。
+    pub fn from_request(req: &ShioriRequest) -> Option<ShioriEvent> {
+        let mut reference = Vec::new();
+        for (i, value) in &req.reference {
+            let Ok(i) = usize::try_from(*i) else {
+                continue;
+            };
+            if i >= MAX_EVENT_REFERENCES {
+                return None;
+            }
+            if reference.len() <= i {
+                reference.resize(i + 1, String::new());
+            }
+            reference[i] = value.to_string();
+        }
+        Some(ShioriEvent {
+            method: req.method()?,
+            id: req.id?.to_string(),
+            reference,
+        })
+    }
+
+    /// UTF-8のSHIORI/3.0リクエスト文字列を作成します。
+    pub fn to_request(&self, sender: &str) -> String {
+        let mut text = format!(
+            "{} SHIORI/3.0\r\nCharset: UTF-8\r\nSender: {}\r\nID: {}\r\n",
+            self.method, sender, self.id
+        );
+        for (i, r) in self.reference.iter().enumerate() {
+            text.push_str(&format!("Reference{}: {}\r\n", i, r));
+        }
+        text.push_str("\r\n");
+        text
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,7 +397,8 @@ mod tests {
         assert_eq!(req.dic["SecurityLevel"], "local");
         assert_eq!(req.dic["Sender"], "SSP");
 
-        assert_eq!(req.key_values.len(), 0);
+        assert_eq!(req.key_values.len(), 4);
+        assert_eq!(req.key_values[0], (Rule::key_charset, "Charset", "UTF-8"));
 
         assert_eq!(req.reference.len(), 0);
     }
@@ -169,7 +425,7 @@ mod tests {
         assert_eq!(req.dic.len(), 5);
         assert_eq!(req.dic["Reference0"], "セキュリティボール");
 
-        assert_eq!(req.key_values.len(), 0);
+        assert_eq!(req.key_values.len(), 5);
 
         assert_eq!(req.reference.len(), 1);
         let mut it = req.reference.into_iter();
@@ -197,7 +453,88 @@ mod tests {
         assert_eq!(req.base_id, None);
 
         assert_eq!(req.dic.len(), 2);
-        assert_eq!(req.key_values.len(), 0);
+        assert_eq!(req.key_values.len(), 2);
         assert_eq!(req.reference.len(), 0);
     }
+
+    #[test]
+    fn req_owned() {
+        let text =
+            "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\nReference1: b\r\nX-Extra: 1\r\n\r\n";
+        let req = ShioriRequest::parse(text).unwrap();
+        assert_eq!(req.method(), Some(Method::Get));
+        assert_eq!(
+            req.key_values,
+            vec![
+                (Rule::key_charset, "Charset", "UTF-8"),
+                (Rule::key_id, "ID", "OnBoot"),
+                (Rule::key_ref, "Reference1", "b"),
+                (Rule::key_other, "X-Extra", "1"),
+            ]
+        );
+        let mut owned = req.to_owned_request();
+        assert_eq!(owned.id.as_deref(), Some("OnBoot"));
+        assert_eq!(owned.headers, vec![("X-Extra".into(), "1".into())]);
+        assert_eq!(owned.to_text(), text);
+
+        // 文字列は型付きのフィールドから作ります。
+        owned.id = Some("OnClose".into());
+        owned.sender = Some("SSP".into());
+        assert_eq!(
+            owned.to_text(),
+            "GET SHIORI/3.0\r\nCharset: UTF-8\r\nSender: SSP\r\nID: OnClose\r\nReference1: b\r\nX-Extra: 1\r\n\r\n"
+        );
+
+        let event = ShioriEvent::from_request(&req).unwrap();
+        assert_eq!(
+            event,
+            ShioriEvent::get("OnBoot")
+                .with_reference("")
+                .with_reference("b")
+        );
+        let text = event.to_request("shiori3");
+        let req = ShioriRequest::parse(&text).unwrap();
+        assert_eq!(req.sender, Some("shiori3"));
+        assert_eq!(ShioriEvent::from_request(&req), Some(event));
+    }
+
+    #[test]
+    fn owned_check() {
+        let text = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\nX-Extra: 1\r\n\r\n";
+        let owned = ShioriRequest::parse(text).unwrap().to_owned_request();
+        assert!(owned.check().is_ok());
+        for (key, value) in [
+            ("ID", "OnEvil"),
+            ("Charset", "Shift_JIS"),
+            ("Reference0", "a"),
+            ("X Bad", "1"),
+            ("", "1"),
+            ("X-Evil", "a\r\nID: OnEvil"),
+            ("X-Evil", "a\r\n\r\n"),
+        ] {
+            let mut bad = owned.clone();
+            bad.headers.push((key.into(), value.into()));
+            assert!(bad.check().is_err(), "{:?}", key);
+        }
+        let mut bad = owned.clone();
+        bad.id = Some("OnBoot\r\nSender: evil".into());
+        assert!(bad.check().is_err());
+    }
+
+    #[test]
+    fn event_huge_reference() {
+        let req = |i: &str| format!("GET SHIORI/3.0\r\nID: OnBoot\r\nReference{}: x\r\n\r\n", i);
+        let text = req("2147483647");
+        let parsed = ShioriRequest::parse(&text).unwrap();
+        assert_eq!(ShioriEvent::from_request(&parsed), None);
+        let text = req("1000000000");
+        let parsed = ShioriRequest::parse(&text).unwrap();
+        assert_eq!(ShioriEvent::from_request(&parsed), None);
+        let text = req("1023");
+        let parsed = ShioriRequest::parse(&text).unwrap();
+        let event = ShioriEvent::from_request(&parsed).unwrap();
+        assert_eq!(event.reference.len(), MAX_EVENT_REFERENCES);
+        assert_eq!(event.reference[1023], "x");
+        assert!(ShioriRequest::parse(&req("2147483648")).is_err());
+    }
 }
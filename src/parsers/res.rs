@@ -0,0 +1,264 @@
+use crate::error::*;
+use std::fmt;
+
+/// SHIORIレスポンスのステータスコードです。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Status {
+    /// 200 OK
+    Ok,
+    /// 204 No Content
+    NoContent,
+    /// 310 Communicate (deprecated)
+    Communicate,
+    /// 311 Not Enough
+    NotEnough,
+    /// 312 Advice
+    Advice,
+    /// 400 Bad Request
+    BadRequest,
+    /// 500 Internal Server Error
+    InternalServerError,
+}
+
+impl Status {
+    /// ステータスコードを返します。
+    pub fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::Communicate => 310,
+            Status::NotEnough => 311,
+            Status::Advice => 312,
+            Status::BadRequest => 400,
+            Status::InternalServerError => 500,
+        }
+    }
+
+    /// ステータス文字列を返します。
+    pub fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NoContent => "No Content",
+            Status::Communicate => "Communicate",
+            Status::NotEnough => "Not Enough",
+            Status::Advice => "Advice",
+            Status::BadRequest => "Bad Request",
+            Status::InternalServerError => "Internal Server Error",
+        }
+    }
+
+    /// ステータスコードから値を求めます。
+    pub fn from_code(code: u16) -> Option<Status> {
+        Some(match code {
+            200 => Status::Ok,
+            204 => Status::NoContent,
+            310 => Status::Communicate,
+            311 => Status::NotEnough,
+            312 => Status::Advice,
+            400 => Status::BadRequest,
+            500 => Status::InternalServerError,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// SHIORI3レスポンスを格納します。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ShioriResponse {
+    pub version: i32,
+    pub status: Status,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ShioriResponse {
+    /// 指定ステータスのレスポンスを作成します。Charsetは常にUTF-8です。
+    pub fn new(status: Status) -> ShioriResponse {
+        ShioriResponse {
+            version: 30,
+            status,
+            headers: vec![("Charset".into(), "UTF-8".into())],
+        }
+    }
+
+    /// Valueを持つ`200 OK`レスポンスを作成します。
+    pub fn ok<S: Into<String>>(value: S) -> ShioriResponse {
+        ShioriResponse::new(Status::Ok).with_value(value)
+    }
+
+    /// `204 No Content`レスポンスを作成します。
+    pub fn no_content() -> ShioriResponse {
+        ShioriResponse::new(Status::NoContent)
+    }
+
+    /// `400 Bad Request`レスポンスを作成します。
+    pub fn bad_request() -> ShioriResponse {
+        ShioriResponse::new(Status::BadRequest)
+    }
+
+    /// `500 Internal Server Error`レスポンスを作成します。
+    pub fn internal_server_error() -> ShioriResponse {
+        ShioriResponse::new(Status::InternalServerError)
+    }
+
+    /// ヘッダを設定します。同名のヘッダがあれば置き換えます。
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.set_header(key, value);
+        self
+    }
+
+    /// Valueヘッダを設定します。
+    pub fn with_value<S: Into<String>>(self, value: S) -> Self {
+        self.with_header("Value", value)
+    }
+
+    /// ヘッダを設定します。同名のヘッダがあれば置き換えます。
+    pub fn set_header<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        match self.headers.iter_mut().find(|(k, _)| *k == key) {
+            Some(item) => item.1 = value,
+            None => self.headers.push((key, value)),
+        }
+    }
+
+    /// ヘッダを参照します。
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Valueヘッダを参照します。
+    pub fn value(&self) -> Option<&str> {
+        self.header("Value")
+    }
+
+    /// SHIORIレスポンス文字列を解析します。
+    /// 空行以降は無視し、改行はCRLFとLFのどちらも受け付けます。
+    pub fn parse(text: &str) -> MyResult<ShioriResponse> {
+        let mut lines = text.lines();
+        let status_line = lines.next().unwrap_or_default();
+        let error = || MyError::ParseResponse(status_line.to_string());
+        let (version, rest) = status_line
+            .strip_prefix("SHIORI/")
+            .and_then(|s| s.split_once(' '))
+            .ok_or_else(error)?;
+        let version = parse_version(version).ok_or_else(error)?;
+        let code = rest.split(' ').next().unwrap_or_default();
+        let status = code
+            .parse()
+            .ok()
+            .and_then(Status::from_code)
+            .ok_or_else(error)?;
+        let mut headers = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| MyError::ParseResponse(line.to_string()))?;
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            headers.push((key.to_string(), value.to_string()));
+        }
+        Ok(ShioriResponse {
+            version,
+            status,
+            headers,
+        })
+    }
+}
+
+/// `3.0`形式のバージョンを`30`形式の数値にします。
+/// マイナーバージョンは1桁に限り、範囲外の値は`None`を返します。
+pub(crate) fn parse_version(text: &str) -> Option<i32> {
+    let (major, minor) = text.split_once('.')?;
+    let major: i32 = major.parse().ok()?;
+    let minor: i32 = minor.parse().ok().filter(|n| (0..10).contains(n))?;
+    major.checked_mul(10)?.checked_add(minor)
+}
+
+impl fmt::Display for ShioriResponse {
+    /// SHIORIレスポンス文字列を出力します。
+    /// ヘッダ値に含まれる改行は出力しません。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SHIORI/{}.{} {}\r\n",
+            self.version / 10,
+            self.version % 10,
+            self.status
+        )?;
+        for (key, value) in &self.headers {
+            f.write_str(key)?;
+            f.write_str(": ")?;
+            for c in value.chars().filter(|c| *c != '\r' && *c != '\n') {
+                fmt::Write::write_char(f, c)?;
+            }
+            f.write_str("\r\n")?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+impl From<ShioriResponse> for String {
+    fn from(res: ShioriResponse) -> String {
+        res.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn res_1() {
+        let res = ShioriResponse::ok("\\0\\s[0]Hello\\e").with_header("Sender", "shiori3");
+        assert_eq!(
+            res.to_string(),
+            "SHIORI/3.0 200 OK\r\nCharset: UTF-8\r\nValue: \\0\\s[0]Hello\\e\r\nSender: shiori3\r\n\r\n"
+        );
+        assert_eq!(res.value(), Some("\\0\\s[0]Hello\\e"));
+    }
+
+    #[test]
+    fn res_2() {
+        let res = ShioriResponse::no_content().with_value("a\r\nb");
+        assert_eq!(
+            res.to_string(),
+            "SHIORI/3.0 204 No Content\r\nCharset: UTF-8\r\nValue: ab\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn res_parse() {
+        let res = ShioriResponse::ok("\\0\\s[0]Hello\\e").with_header("Sender", "shiori3");
+        assert_eq!(ShioriResponse::parse(&res.to_string()).unwrap(), res);
+
+        let res = ShioriResponse::parse("SHIORI/2.6 204 No Content\nX-Note: a: b\n").unwrap();
+        assert_eq!(res.version, 26);
+        assert_eq!(res.status, Status::NoContent);
+        assert_eq!(res.header("X-Note"), Some("a: b"));
+
+        assert!(ShioriResponse::parse("").is_err());
+        assert!(ShioriResponse::parse("SHIORI/3.0 999 Unknown\r\n\r\n").is_err());
+        assert!(ShioriResponse::parse("HTTP/1.1 200 OK\r\n\r\n").is_err());
+        assert!(ShioriResponse::parse("SHIORI/3.0 200 OK\r\nbroken\r\n\r\n").is_err());
+
+        let e = ShioriResponse::parse("SHIORI/300000000.0 200 OK\r\n\r\n").unwrap_err();
+        assert!(matches!(e, MyError::ParseResponse(_)));
+        assert!(ShioriResponse::parse("SHIORI/3.10 200 OK\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn status_1() {
+        for code in [200, 204, 310, 311, 312, 400, 500] {
+            assert_eq!(Status::from_code(code).unwrap().code(), code);
+        }
+        assert_eq!(Status::from_code(404), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! `serde`フィーチャーのシリアライズ実装です。
+//!
+//! - `Status`: ステータスコードの数値 (`200`)
+//! - `ShioriResponse`: `{"version": "3.0", "status": 200, "headers": [["Charset", "UTF-8"]]}`
+//! - `ShioriRequest`: `OwnedShioriRequest`と同じ形式です。デシリアライズは`OwnedShioriRequest`で行います。
+//!
+//! ヘッダは順序と重複を保つため、名前と値の配列の配列にします。
+
+use super::req::ShioriRequest;
+use super::res::{ShioriResponse, Status, parse_version};
+use serde::de::{self, Deserializer};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+
+/// バージョン番号(`30`)を文字列(`"3.0"`)でシリアライズします。
+pub(crate) mod version {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(version: &i32, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&format_args!("{}.{}", version / 10, version % 10))
+    }
+
+    /// 省略時はSHIORI/3.0です。
+    pub fn default() -> i32 {
+        30
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<i32, D::Error> {
+        let text = String::deserialize(d)?;
+        let error = || de::Error::invalid_value(de::Unexpected::Str(&text), &"\"3.0\"");
+        parse_version(&text).ok_or_else(error)
+    }
+}
+
+impl Serialize for Status {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Status {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Status, D::Error> {
+        let code = u16::deserialize(d)?;
+        Status::from_code(code).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Unsigned(code.into()), &"SHIORI status code")
+        })
+    }
+}
+
+impl Serialize for ShioriResponse {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        struct Version(i32);
+        impl Serialize for Version {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                version::serialize(&self.0, s)
+            }
+        }
+        let mut st = s.serialize_struct("ShioriResponse", 3)?;
+        st.serialize_field("version", &Version(self.version))?;
+        st.serialize_field("status", &self.status)?;
+        st.serialize_field("headers", &self.headers)?;
+        st.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "ShioriResponse")]
+struct ResponseRepr {
+    #[serde(with = "version")]
+    version: i32,
+    status: Status,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+}
+
+impl<'de> Deserialize<'de> for ShioriResponse {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<ShioriResponse, D::Error> {
+        let repr = ResponseRepr::deserialize(d)?;
+        Ok(ShioriResponse {
+            version: repr.version,
+            status: repr.status,
+            headers: repr.headers,
+        })
+    }
+}
+
+impl Serialize for ShioriRequest<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.to_owned_request().serialize(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parsers::req::{Method, OwnedShioriRequest, ShioriEvent, ShioriRequest};
+    use crate::parsers::res::{ShioriResponse, Status};
+    use serde_json::json;
+
+    #[test]
+    fn serde_request() {
+        let text = "NOTIFY SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\n\
+            Reference0: master\r\nReference2: \r\nX-Extra: 1\r\n\r\n";
+        let req = ShioriRequest::parse(text).unwrap();
+        let value = serde_json::to_value(&req).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "method": "NOTIFY",
+                "version": "3.0",
+                "id": "OnBoot",
+                "sender": null,
+                "security_level": null,
+                "charset": "UTF-8",
+                "status": null,
+                "base_id": null,
+                "reference": [[0, "master"], [2, ""]],
+                "headers": [["X-Extra", "1"]],
+            })
+        );
+        let owned: OwnedShioriRequest = serde_json::from_value(value).unwrap();
+        assert_eq!(owned, req.to_owned_request());
+        assert_eq!(owned.to_text(), text);
+
+        let v2 = ShioriRequest::parse("GET Version SHIORI/2.6\r\nSender: SSP\r\n\r\n").unwrap();
+        let owned: OwnedShioriRequest =
+            serde_json::from_str(&serde_json::to_string(&v2).unwrap()).unwrap();
+        assert_eq!(owned.version, 26);
+        assert_eq!(owned.to_text(), v2.text);
+
+        let event = ShioriEvent::from_request(&req).unwrap();
+        assert_eq!(
+            event,
+            ShioriEvent::notify("OnBoot")
+                .with_reference("master")
+                .with_reference("")
+                .with_reference("")
+        );
+        let value = serde_json::to_value(ShioriEvent::get("OnClose")).unwrap();
+        assert_eq!(
+            value,
+            json!({"method": "GET", "id": "OnClose", "reference": []})
+        );
+        let event: ShioriEvent =
+            serde_json::from_value(json!({"method": "NOTIFY", "id": "OnClose"})).unwrap();
+        assert_eq!(event.method, Method::Notify);
+        assert!(
+            serde_json::from_value::<ShioriEvent>(json!({"method": "PUT", "id": "a"})).is_err()
+        );
+    }
+
+    #[test]
+    fn serde_response() {
+        let res = ShioriResponse::ok("\\0hello\\e").with_header("X-Extra", "1");
+        let value = serde_json::to_value(&res).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "version": "3.0",
+                "status": 200,
+                "headers": [["Charset", "UTF-8"], ["Value", "\\0hello\\e"], ["X-Extra", "1"]],
+            })
+        );
+        let back: ShioriResponse = serde_json::from_value(value).unwrap();
+        assert_eq!(back, res);
+
+        assert_eq!(serde_json::to_value(Status::NoContent).unwrap(), json!(204));
+        assert!(serde_json::from_value::<Status>(json!(999)).is_err());
+        let res = json!({"version": "three", "status": 200});
+        assert!(serde_json::from_value::<ShioriResponse>(res).is_err());
+        let res = json!({"version": "300000000.0", "status": 200});
+        assert!(serde_json::from_value::<ShioriResponse>(res).is_err());
+    }
+
+    #[test]
+    fn serde_error() {
+        let error = crate::error::MyError::UnknownCharset("x".into());
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({"kind": "UnknownCharset", "message": "unknown charset 'x'"})
+        );
+    }
+}
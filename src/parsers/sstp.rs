@@ -0,0 +1,557 @@
+//! SSTP/1.xのメッセージの解析と作成です。
+//! ヘッダの文法は`req_parser.pest`のSHIORIリクエストと共通です。
+
+use super::req::{ParseError, Parser, Rule};
+use crate::error::*;
+use pest::Parser as PestParser;
+use std::fmt;
+
+/// SSTPリクエストのメソッドです。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SstpMethod {
+    /// SEND SSTP/1.4
+    Send,
+    /// NOTIFY SSTP/1.1
+    Notify,
+    /// COMMUNICATE SSTP/1.1
+    Communicate,
+    /// EXECUTE SSTP/1.0
+    Execute,
+    /// GIVE SSTP/1.1
+    Give,
+}
+
+impl SstpMethod {
+    /// メソッド名を返します。
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SstpMethod::Send => "SEND",
+            SstpMethod::Notify => "NOTIFY",
+            SstpMethod::Communicate => "COMMUNICATE",
+            SstpMethod::Execute => "EXECUTE",
+            SstpMethod::Give => "GIVE",
+        }
+    }
+
+    /// メソッドの標準のバージョンを返します。`14`はSSTP/1.4です。
+    pub fn version(self) -> i32 {
+        match self {
+            SstpMethod::Send => 14,
+            SstpMethod::Execute => 10,
+            _ => 11,
+        }
+    }
+
+    /// メソッド名から値を求めます。
+    pub fn from_name(name: &str) -> Option<SstpMethod> {
+        Some(match name {
+            "SEND" => SstpMethod::Send,
+            "NOTIFY" => SstpMethod::Notify,
+            "COMMUNICATE" => SstpMethod::Communicate,
+            "EXECUTE" => SstpMethod::Execute,
+            "GIVE" => SstpMethod::Give,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for SstpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// SSTPレスポンスのステータスコードです。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SstpStatus {
+    /// 200 OK
+    Ok,
+    /// 204 No Content
+    NoContent,
+    /// 210 Break
+    Break,
+    /// 400 Bad Request
+    BadRequest,
+    /// 404 Not Found
+    NotFound,
+    /// 408 Request Timeout
+    RequestTimeout,
+    /// 409 Conflict
+    Conflict,
+    /// 420 Refuse
+    Refuse,
+    /// 501 Not Implemented
+    NotImplemented,
+    /// 503 Service Unavailable
+    ServiceUnavailable,
+    /// その他のステータスコードです。理由句は保持しません。
+    Other(u16),
+}
+
+impl SstpStatus {
+    /// ステータスコードを返します。
+    pub fn code(self) -> u16 {
+        match self {
+            SstpStatus::Ok => 200,
+            SstpStatus::NoContent => 204,
+            SstpStatus::Break => 210,
+            SstpStatus::BadRequest => 400,
+            SstpStatus::NotFound => 404,
+            SstpStatus::RequestTimeout => 408,
+            SstpStatus::Conflict => 409,
+            SstpStatus::Refuse => 420,
+            SstpStatus::NotImplemented => 501,
+            SstpStatus::ServiceUnavailable => 503,
+            SstpStatus::Other(code) => code,
+        }
+    }
+
+    /// ステータス文字列を返します。`Other`は空文字列です。
+    pub fn reason(self) -> &'static str {
+        match self {
+            SstpStatus::Ok => "OK",
+            SstpStatus::NoContent => "No Content",
+            SstpStatus::Break => "Break",
+            SstpStatus::BadRequest => "Bad Request",
+            SstpStatus::NotFound => "Not Found",
+            SstpStatus::RequestTimeout => "Request Timeout",
+            SstpStatus::Conflict => "Conflict",
+            SstpStatus::Refuse => "Refuse",
+            SstpStatus::NotImplemented => "Not Implemented",
+            SstpStatus::ServiceUnavailable => "Service Unavailable",
+            SstpStatus::Other(_) => "",
+        }
+    }
+
+    /// 既知のステータスコードから値を求めます。
+    pub fn from_code(code: u16) -> Option<SstpStatus> {
+        Some(match code {
+            200 => SstpStatus::Ok,
+            204 => SstpStatus::NoContent,
+            210 => SstpStatus::Break,
+            400 => SstpStatus::BadRequest,
+            404 => SstpStatus::NotFound,
+            408 => SstpStatus::RequestTimeout,
+            409 => SstpStatus::Conflict,
+            420 => SstpStatus::Refuse,
+            501 => SstpStatus::NotImplemented,
+            503 => SstpStatus::ServiceUnavailable,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for SstpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SstpStatus::Other(code) => write!(f, "{}", code),
+            _ => write!(f, "{} {}", self.code(), self.reason()),
+        }
+    }
+}
+
+/// `1.4`を`14`にします。
+fn parse_version(ver: &str) -> i32 {
+    ver.bytes()
+        .filter(u8::is_ascii_digit)
+        .fold(0, |v, b| v * 10 + (b - b'0') as i32)
+}
+
+/// SSTPリクエストの解析結果を格納します。
+#[derive(PartialEq, Eq, Debug)]
+pub struct SstpRequest<'a> {
+    pub text: &'a str,
+    pub method: SstpMethod,
+    /// `14`ならSSTP/1.4です。
+    pub version: i32,
+    pub sender: Option<&'a str>,
+    pub charset: Option<&'a str>,
+    pub security_level: Option<&'a str>,
+    pub reference: Vec<(i32, &'a str)>,
+    /// 全てのヘッダを出現順に格納します。SENDの`IfGhost`と`Script`のように重複することがあります。
+    pub headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> SstpRequest<'a> {
+    /// SSTPリクエスト文字列を解析します。
+    pub fn parse(text: &'a str) -> MyResult<SstpRequest<'a>> {
+        let mut req = SstpRequest {
+            text,
+            method: SstpMethod::Send,
+            version: 0,
+            sender: None,
+            charset: None,
+            security_level: None,
+            reference: Vec::new(),
+            headers: Vec::new(),
+        };
+        let mut it = Parser::parse(Rule::sstp_req, text)?.flatten();
+        while let Some(pair) = it.next() {
+            match pair.as_rule() {
+                Rule::sstp_method => {
+                    req.method = SstpMethod::from_name(pair.as_str()).unwrap();
+                }
+                Rule::sstp_ver => req.version = parse_version(pair.as_str()),
+                Rule::key_value => {
+                    let key = it.next().unwrap();
+                    if key.as_rule() == Rule::key_ref {
+                        let pair = it.next().unwrap();
+                        let nums = pair.as_str().parse().map_err(|_| {
+                            ParseError::new_from_span(
+                                pest::error::ErrorVariant::CustomError {
+                                    message: "reference number out of range".into(),
+                                },
+                                pair.as_span(),
+                            )
+                        })?;
+                        let value = it.next().unwrap().as_str();
+                        req.reference.push((nums, value));
+                        req.headers.push((key.as_str(), value));
+                        continue;
+                    }
+                    let value = it.next().unwrap().as_str();
+                    match key.as_rule() {
+                        Rule::key_sender => req.sender = Some(value),
+                        Rule::key_charset => req.charset = Some(value),
+                        Rule::key_security_level => req.security_level = Some(value),
+                        _ => (),
+                    }
+                    req.headers.push((key.as_str(), value));
+                }
+                _ => (),
+            }
+        }
+        Ok(req)
+    }
+
+    /// 最初に現れたヘッダを参照します。
+    pub fn header(&self, key: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+    }
+
+    /// 同名のヘッダの値を出現順に返します。
+    pub fn values<'s>(&'s self, key: &'s str) -> impl Iterator<Item = &'a str> + 's {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, v)| *v)
+    }
+}
+
+/// SSTPリクエストを作成します。ヘッダは追加した順に出力し、同名でも置き換えません。
+///
+/// ```
+/// use shiori3::sstp::SstpBuilder;
+///
+/// let text = SstpBuilder::notify("ghost", "OnMusicPlay")
+///     .with_reference("song")
+///     .with_header("Option", "nodescript")
+///     .to_string();
+/// assert_eq!(
+///     text,
+///     "NOTIFY SSTP/1.1\r\nCharset: UTF-8\r\nSender: ghost\r\nEvent: OnMusicPlay\r\n\
+///      Reference0: song\r\nOption: nodescript\r\n\r\n"
+/// );
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SstpBuilder {
+    pub method: SstpMethod,
+    pub version: i32,
+    pub headers: Vec<(String, String)>,
+    references: usize,
+}
+
+impl SstpBuilder {
+    /// CharsetとSenderを持つリクエストを作成します。Charsetは常にUTF-8です。
+    pub fn new<S: Into<String>>(method: SstpMethod, sender: S) -> SstpBuilder {
+        SstpBuilder {
+            method,
+            version: method.version(),
+            headers: vec![
+                ("Charset".into(), "UTF-8".into()),
+                ("Sender".into(), sender.into()),
+            ],
+            references: 0,
+        }
+    }
+
+    /// Scriptを持つ`SEND SSTP/1.4`を作成します。
+    pub fn send<S: Into<String>, T: Into<String>>(sender: S, script: T) -> SstpBuilder {
+        SstpBuilder::new(SstpMethod::Send, sender).with_header("Script", script)
+    }
+
+    /// Eventを持つ`NOTIFY SSTP/1.1`を作成します。
+    pub fn notify<S: Into<String>, T: Into<String>>(sender: S, event: T) -> SstpBuilder {
+        SstpBuilder::new(SstpMethod::Notify, sender).with_header("Event", event)
+    }
+
+    /// Commandを持つ`EXECUTE SSTP/1.0`を作成します。
+    pub fn execute<S: Into<String>, T: Into<String>>(sender: S, command: T) -> SstpBuilder {
+        SstpBuilder::new(SstpMethod::Execute, sender).with_header("Command", command)
+    }
+
+    /// バージョンを設定します。`14`はSSTP/1.4です。
+    pub fn with_version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// ヘッダを追加します。値の改行は出力しません。
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        let value: String = value.into();
+        let value = value.chars().filter(|c| *c != '\r' && *c != '\n').collect();
+        self.headers.push((key.into(), value));
+        self
+    }
+
+    /// 次の番号のReferenceを追加します。
+    pub fn with_reference<V: Into<String>>(mut self, value: V) -> Self {
+        let key = format!("Reference{}", self.references);
+        self.references += 1;
+        self.with_header(key, value)
+    }
+
+    /// SENDで、ゴーストごとのIfGhostとScriptの組を追加します。
+    pub fn with_if_ghost<G: Into<String>, S: Into<String>>(self, ghost: G, script: S) -> Self {
+        self.with_header("IfGhost", ghost)
+            .with_header("Script", script)
+    }
+}
+
+impl fmt::Display for SstpBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} SSTP/{}.{}\r\n",
+            self.method,
+            self.version / 10,
+            self.version % 10
+        )?;
+        for (key, value) in &self.headers {
+            write!(f, "{}: {}\r\n", key, value)?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+impl From<SstpBuilder> for String {
+    fn from(req: SstpBuilder) -> String {
+        req.to_string()
+    }
+}
+
+/// SSTPレスポンスを格納します。
+/// EXECUTEの応答のように、ヘッダではない行は`data`に格納します。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SstpResponse {
+    pub version: i32,
+    pub status: SstpStatus,
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<String>,
+}
+
+impl SstpResponse {
+    /// 指定ステータスのSSTP/1.4レスポンスを作成します。
+    pub fn new(status: SstpStatus) -> SstpResponse {
+        SstpResponse {
+            version: 14,
+            status,
+            headers: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// ヘッダを追加します。値の改行は出力しません。
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        let value: String = value.into();
+        let value = value.chars().filter(|c| *c != '\r' && *c != '\n').collect();
+        self.headers.push((key.into(), value));
+        self
+    }
+
+    /// ヘッダではない行を追加します。行の改行は出力しません。
+    pub fn with_data<S: Into<String>>(mut self, line: S) -> Self {
+        let line: String = line.into();
+        self.data
+            .push(line.chars().filter(|c| *c != '\r' && *c != '\n').collect());
+        self
+    }
+
+    /// 最初に現れたヘッダを参照します。
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// SSTPレスポンス文字列を解析します。
+    /// `名前: 値`の形の行はヘッダ、それ以外の行は`data`になります。
+    /// 未知のステータスコードは`SstpStatus::Other`になります。
+    pub fn parse(text: &str) -> MyResult<SstpResponse> {
+        let mut res = SstpResponse::new(SstpStatus::Ok);
+        let mut it = Parser::parse(Rule::sstp_res, text)?.flatten();
+        while let Some(pair) = it.next() {
+            match pair.as_rule() {
+                Rule::sstp_ver => res.version = parse_version(pair.as_str()),
+                Rule::sstp_code => {
+                    let code = pair.as_str().parse().map_err(|_| {
+                        MyError::ParseResponse(text.lines().next().unwrap_or_default().into())
+                    })?;
+                    res.status = SstpStatus::from_code(code).unwrap_or(SstpStatus::Other(code));
+                }
+                Rule::key_value => {
+                    let key = it.next().unwrap();
+                    if key.as_rule() == Rule::key_ref {
+                        it.next();
+                    }
+                    let value = it.next().unwrap().as_str();
+                    res.headers.push((key.as_str().into(), value.into()));
+                }
+                Rule::sstp_data => res.data.push(pair.as_str().into()),
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl fmt::Display for SstpResponse {
+    /// SSTPレスポンス文字列を出力します。ヘッダの後に`data`の行を出力します。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SSTP/{}.{} {}\r\n",
+            self.version / 10,
+            self.version % 10,
+            self.status
+        )?;
+        for (key, value) in &self.headers {
+            write!(f, "{}: {}\r\n", key, value)?;
+        }
+        for line in &self.data {
+            write!(f, "{}\r\n", line)?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+impl From<SstpResponse> for String {
+    fn from(res: SstpResponse) -> String {
+        res.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sstp_request() {
+        let text = "SEND SSTP/1.4\r\nSender: カードキャプター\r\nIfGhost: さくら,うにゅう\r\n\
+            Script: \\h\\s0こんにちは。\\e\r\nIfGhost: なる,ゆうと\r\nScript: \\h\\s0やあ。\\e\r\n\
+            Option: notranslate\r\nCharset: UTF-8\r\n\r\n";
+        let req = SstpRequest::parse(text).unwrap();
+        assert_eq!(req.method, SstpMethod::Send);
+        assert_eq!(req.version, 14);
+        assert_eq!(req.sender, Some("カードキャプター"));
+        assert_eq!(req.charset, Some("UTF-8"));
+        assert_eq!(req.header("Option"), Some("notranslate"));
+        let ghosts: Vec<_> = req.values("IfGhost").collect();
+        assert_eq!(ghosts, ["さくら,うにゅう", "なる,ゆうと"]);
+        assert_eq!(req.values("Script").count(), 2);
+
+        let text =
+            "NOTIFY SSTP/1.1\nSender: ghost\nEvent: OnMusicPlay\nReference0: a\nReference1: b\n\n";
+        let req = SstpRequest::parse(text).unwrap();
+        assert_eq!(req.method, SstpMethod::Notify);
+        assert_eq!(req.version, 11);
+        assert_eq!(req.reference, vec![(0, "a"), (1, "b")]);
+        assert_eq!(req.header("Event"), Some("OnMusicPlay"));
+        let text = "NOTIFY SSTP/1.1\nSender: ghost\nReference2147483648: a\n\n";
+        assert!(SstpRequest::parse(text).is_err());
+
+        for method in ["COMMUNICATE SSTP/1.1", "EXECUTE SSTP/1.0", "GIVE SSTP/1.1"] {
+            let text = format!("{}\r\nSender: a\r\n\r\n", method);
+            assert!(SstpRequest::parse(&text).is_ok(), "{}", method);
+        }
+        assert!(SstpRequest::parse("GET SHIORI/3.0\r\nID: a\r\n\r\n").is_err());
+        assert!(SstpRequest::parse("SEND SSTP/1.4\r\nSender: a\r\n").is_err());
+    }
+
+    #[test]
+    fn sstp_builder() {
+        let text = SstpBuilder::send("ghost", "\\h\\s0default\\e")
+            .with_if_ghost("さくら", "\\h\\s0sakura\\e")
+            .with_header("Option", "a\r\nb")
+            .to_string();
+        assert_eq!(
+            text,
+            "SEND SSTP/1.4\r\nCharset: UTF-8\r\nSender: ghost\r\nScript: \\h\\s0default\\e\r\n\
+             IfGhost: さくら\r\nScript: \\h\\s0sakura\\e\r\nOption: ab\r\n\r\n"
+        );
+        let req = SstpRequest::parse(&text).unwrap();
+        assert_eq!(req.values("Script").count(), 2);
+
+        let text: String = SstpBuilder::execute("ghost", "GetName").into();
+        let req = SstpRequest::parse(&text).unwrap();
+        assert_eq!(req.method, SstpMethod::Execute);
+        assert_eq!(req.version, 10);
+        assert_eq!(req.header("Command"), Some("GetName"));
+        let text = SstpBuilder::new(SstpMethod::Give, "ghost")
+            .with_version(11)
+            .with_header("Document", "text")
+            .to_string();
+        assert!(text.starts_with("GIVE SSTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn sstp_response() {
+        let res =
+            SstpResponse::parse("SSTP/1.4 200 OK\r\nCharset: UTF-8\r\nさくら\r\n\r\n").unwrap();
+        assert_eq!(res.version, 14);
+        assert_eq!(res.status, SstpStatus::Ok);
+        assert_eq!(res.header("Charset"), Some("UTF-8"));
+        assert_eq!(res.data, ["さくら"]);
+        assert_eq!(
+            res.to_string(),
+            "SSTP/1.4 200 OK\r\nCharset: UTF-8\r\nさくら\r\n\r\n"
+        );
+
+        let codes = [200, 204, 210, 400, 404, 408, 409, 420, 501, 503];
+        for code in codes {
+            let status = SstpStatus::from_code(code).unwrap();
+            assert_eq!(status.code(), code);
+            let text = SstpResponse::new(status).to_string();
+            assert_eq!(SstpResponse::parse(&text).unwrap().status, status);
+        }
+        let res = SstpResponse::parse("SSTP/1.1 210 Break\n").unwrap();
+        assert_eq!(res.status, SstpStatus::Break);
+        assert_eq!(
+            SstpResponse::new(SstpStatus::Refuse)
+                .with_header("X-SSTP-PassThru-Result", "1")
+                .with_data("line")
+                .to_string(),
+            "SSTP/1.4 420 Refuse\r\nX-SSTP-PassThru-Result: 1\r\nline\r\n\r\n"
+        );
+        let res = SstpResponse::parse("SSTP/1.4 512 Invisible\r\n\r\n").unwrap();
+        assert_eq!(res.status, SstpStatus::Other(512));
+        assert_eq!(res.status.code(), 512);
+        assert_eq!(res.to_string(), "SSTP/1.4 512\r\n\r\n");
+        assert_eq!(
+            SstpResponse::parse(&res.to_string()).unwrap().status,
+            SstpStatus::Other(512)
+        );
+        let res = SstpResponse::new(SstpStatus::Ok)
+            .with_header("X-Evil", "a\r\nScript: \\e")
+            .with_data("b\nc");
+        assert_eq!(
+            res.to_string(),
+            "SSTP/1.4 200 OK\r\nX-Evil: aScript: \\e\r\nbc\r\n\r\n"
+        );
+        assert!(SstpResponse::parse("SHIORI/3.0 200 OK\r\n\r\n").is_err());
+    }
+}
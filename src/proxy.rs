@@ -0,0 +1,319 @@
+use crate::api::{LayerFn, Shiori3DI, ShioriFactory, ShioriHandler};
+use crate::client::ShioriClient;
+use crate::context::RequestContext;
+use crate::error::*;
+use crate::middleware::{Middleware, Next};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 内側のSHIORI共有ライブラリへリクエストを転送するハンドラです。
+/// フックがなければ`RawShiori3`からバイト列をそのまま受け渡すため、
+/// 内側のSHIORIの文字コードの扱いは変わりません。
+/// 内側のSHIORIのパスを`load()`の引数で受け取れず、
+/// `ShioriHandler`の実装とも衝突するため、`Shiori3`は実装しません。
+/// `ProxyFactory`を利用してください。
+pub struct ProxyShiori {
+    client: ShioriClient,
+}
+
+impl ProxyShiori {
+    /// 内側のSHIORIを開き、load_dirでloadします。
+    pub fn load<P: AsRef<Path>, D: AsRef<Path>>(library: P, load_dir: D) -> MyResult<ProxyShiori> {
+        let client = ShioriClient::load(library, load_dir)?;
+        Ok(ProxyShiori::new(client))
+    }
+
+    /// ロード済みのクライアントを包みます。
+    pub fn new(client: ShioriClient) -> ProxyShiori {
+        ProxyShiori { client }
+    }
+
+    /// 内側のSHIORIのクライアントを参照します。
+    pub fn client_mut(&mut self) -> &mut ShioriClient {
+        &mut self.client
+    }
+}
+
+impl ShioriHandler for ProxyShiori {
+    /// 内側のSHIORIがNULLを返した場合はエラーとし、ベースウェアにもNULLを返します。
+    fn handle<'a>(
+        &mut self,
+        req: &'a str,
+        _ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        let res = self.client.request_text(req)?.ok_or(MyError::NoResponse)?;
+        Ok(Cow::Owned(res))
+    }
+
+    /// 文字コードを変換せずに転送します。
+    fn handle_bytes<'a>(
+        &mut self,
+        req: &'a [u8],
+        _ctx: &mut RequestContext,
+    ) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        let res = self.client.request_bytes(req)?.ok_or(MyError::NoResponse)?;
+        Ok(Cow::Owned(res))
+    }
+
+    fn close(&mut self) -> Result<(), anyhow::Error> {
+        if !self.client.unload() {
+            anyhow::bail!("inner SHIORI unload failed");
+        }
+        Ok(())
+    }
+}
+
+/// 内側のSHIORIに渡す前に呼ばれる関数です。応答を返すと内側のSHIORIを呼びません。
+type BeforeFn =
+    dyn Fn(&str, &mut RequestContext) -> Result<Option<String>, anyhow::Error> + Send + Sync;
+
+/// 内側のSHIORIの応答を書き換える関数です。
+type AfterFn =
+    dyn Fn(&str, &mut String, &mut RequestContext) -> Result<(), anyhow::Error> + Send + Sync;
+
+struct Before(Arc<BeforeFn>);
+
+impl Middleware for Before {
+    fn call<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+        next: Next<'_>,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        match (self.0)(req, ctx)? {
+            Some(res) => Ok(Cow::Owned(res)),
+            None => next.run(req, ctx),
+        }
+    }
+}
+
+struct After(Arc<AfterFn>);
+
+impl Middleware for After {
+    fn call<'a>(
+        &mut self,
+        req: &'a str,
+        ctx: &mut RequestContext,
+        next: Next<'_>,
+    ) -> Result<Cow<'a, str>, anyhow::Error> {
+        let mut res = next.run(req, ctx)?.into_owned();
+        (self.0)(req, &mut res, ctx)?;
+        Ok(Cow::Owned(res))
+    }
+}
+
+/// 内側のSHIORIをロードディレクトリから読み込み、`ProxyShiori`を作成します。
+/// YAYAや里々などの既存のSHIORIにRustの機能を重ねる場合に利用してください。
+/// `before()`、`after()`、`with_layer()`で登録したフックは登録順に外側から動作します。
+/// フックを登録すると、リクエストと応答はCharsetヘッダに従って文字列に変換されるため、
+/// バイト列のままでは中継されません。
+///
+/// ```ignore
+/// let factory = ProxyFactory::new("yaya.dll")
+///     .before(|req, _ctx| Ok(req.contains("\r\nID: OnRust\r\n").then(|| answer())))
+///     .after(|_req, res, _ctx| Ok(translate(res)));
+/// let shiori = RawShiori3::with_factory(factory);
+/// ```
+pub struct ProxyFactory {
+    library: PathBuf,
+    layers: Vec<LayerFn>,
+}
+
+impl ProxyFactory {
+    /// 内側のSHIORIのパスを指定します。相対パスはロードディレクトリからのパスです。
+    pub fn new<P: AsRef<Path>>(library: P) -> ProxyFactory {
+        ProxyFactory {
+            library: library.as_ref().to_path_buf(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// ミドルウェアを登録します。
+    pub fn with_layer<M, F>(mut self, f: F) -> Self
+    where
+        M: Middleware + 'static,
+        F: Fn() -> M + Send + 'static,
+    {
+        self.layers.push(Box::new(move || Box::new(f())));
+        self
+    }
+
+    /// 内側のSHIORIに渡す前のフックを登録します。
+    /// `Some`で応答を返すと、内側のSHIORIを呼ばずにその応答を返します。
+    /// フックを登録すると全てのリクエストを文字列に変換するため、
+    /// 変換できないCharset(Windows以外ではShift_JISなど)のリクエストはエラーになります。
+    pub fn before<F>(self, f: F) -> Self
+    where
+        F: Fn(&str, &mut RequestContext) -> Result<Option<String>, anyhow::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let f: Arc<BeforeFn> = Arc::new(f);
+        self.with_layer(move || Before(f.clone()))
+    }
+
+    /// 内側のSHIORIの応答を書き換えるフックを登録します。
+    /// `before()`と同じく、変換できないCharsetのリクエストはエラーになります。
+    pub fn after<F>(self, f: F) -> Self
+    where
+        F: Fn(&str, &mut String, &mut RequestContext) -> Result<(), anyhow::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let f: Arc<AfterFn> = Arc::new(f);
+        self.with_layer(move || After(f.clone()))
+    }
+
+    /// 内側のSHIORIのパスを返します。
+    pub fn library(&self, load_dir: &Path) -> PathBuf {
+        load_dir.join(&self.library)
+    }
+
+    fn wrap(&self, inner: ProxyShiori) -> Shiori3DI<ProxyShiori> {
+        let layers = self.layers.iter().map(|f| f()).collect();
+        Shiori3DI::new(inner).with_layers(layers)
+    }
+}
+
+impl ShioriFactory for ProxyFactory {
+    fn load(
+        &self,
+        _h_inst: usize,
+        load_dir: &Path,
+        _load_dir_bytes: &[u8],
+    ) -> Result<Box<dyn ShioriHandler>, anyhow::Error> {
+        let inner = ProxyShiori::load(self.library(load_dir), load_dir)?;
+        Ok(Box::new(self.wrap(inner)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{RawShiori3, ServiceHandler, ShioriService};
+    use crate::client::{Bool, to_bool};
+    use crate::hglobal::HGLOBAL;
+    use crate::parsers::req::ShioriRequest;
+    use crate::parsers::res::{ShioriResponse, Status};
+    use std::ffi::c_long;
+    use std::sync::{LazyLock, Mutex};
+    use std::time::UNIX_EPOCH;
+
+    /// 内側のSHIORIです。リクエストのIDとCharsetを返します。
+    struct Inner;
+
+    impl ShioriService for Inner {
+        fn request(
+            &mut self,
+            req: &ShioriRequest,
+            _ctx: &mut RequestContext,
+        ) -> Result<ShioriResponse, anyhow::Error> {
+            match req.id {
+                Some("OnError") => anyhow::bail!("error"),
+                id => Ok(ShioriResponse::ok(format!(
+                    "{}:{}",
+                    id.unwrap_or_default(),
+                    req.charset.unwrap_or_default()
+                ))),
+            }
+        }
+    }
+
+    static INNER: LazyLock<Mutex<RawShiori3>> = LazyLock::new(|| {
+        let factory = |_h_inst: usize, _load_dir: &Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = ServiceHandler::boxed(Inner);
+            Ok(shiori)
+        };
+        Mutex::new(RawShiori3::with_factory(factory))
+    });
+
+    extern "C" fn load(h: HGLOBAL, len: c_long) -> Bool {
+        to_bool(INNER.lock().unwrap().raw_load(h, len as usize))
+    }
+
+    extern "C" fn unload() -> Bool {
+        to_bool(INNER.lock().unwrap().raw_unload())
+    }
+
+    extern "C" fn request(h: HGLOBAL, len: *mut c_long) -> HGLOBAL {
+        let len = unsafe { &mut *len };
+        let mut l = *len as usize;
+        let rc = INNER.lock().unwrap().raw_request(h, &mut l);
+        *len = l as c_long;
+        rc
+    }
+
+    fn req(id: &str) -> String {
+        format!("GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: {}\r\n\r\n", id)
+    }
+
+    #[test]
+    fn proxy_hooks() {
+        let factory = ProxyFactory::new("yaya.so")
+            .before(|req, _ctx| {
+                Ok(req
+                    .contains("\r\nID: OnRust\r\n")
+                    .then(|| ShioriResponse::ok("rust").to_string()))
+            })
+            .after(|_req, res, _ctx| {
+                *res = res.replace("OnBoot", "OnBoot!");
+                Ok(())
+            });
+        assert_eq!(
+            factory.library(Path::new("/ghost/master")),
+            PathBuf::from("/ghost/master/yaya.so")
+        );
+        assert!(factory.load(0, Path::new("/nonexistent"), b"").is_err());
+
+        let mut client = ShioriClient::from_fns(load, unload, request);
+        client.raw_load(Path::new("ghost/master")).unwrap();
+        let mut shiori = factory.wrap(ProxyShiori::new(client));
+        let mut ctx = RequestContext::new(1, UNIX_EPOCH);
+
+        let boot = req("OnBoot");
+        let res = shiori.handle(&boot, &mut ctx).unwrap();
+        let res = ShioriResponse::parse(&res).unwrap();
+        assert_eq!(res.value(), Some("OnBoot!:UTF-8"));
+        assert_eq!(res.header("Charset"), Some("UTF-8"));
+
+        let rust = req("OnRust");
+        let res = shiori.handle(&rust, &mut ctx).unwrap();
+        assert_eq!(ShioriResponse::parse(&res).unwrap().value(), Some("rust"));
+
+        let error = req("OnError");
+        let res = shiori.handle(&error, &mut ctx).unwrap();
+        let res = ShioriResponse::parse(&res).unwrap();
+        assert_eq!(res.status, Status::InternalServerError);
+
+        let res = shiori.handle_bytes(boot.as_bytes(), &mut ctx).unwrap();
+        let res = ShioriResponse::parse(str::from_utf8(&res).unwrap()).unwrap();
+        assert_eq!(res.value(), Some("OnBoot!:UTF-8"));
+
+        // フックがあると文字列に変換するため、Windows以外ではShift_JISを扱えません。
+        let sjis = boot.replace("UTF-8", "Shift_JIS");
+        let res = shiori.handle_bytes(sjis.as_bytes(), &mut ctx);
+        #[cfg(not(windows))]
+        assert!(res.is_err());
+        #[cfg(windows)]
+        {
+            let res = ShioriResponse::parse(str::from_utf8(&res.unwrap()).unwrap()).unwrap();
+            assert_eq!(res.value(), Some("OnBoot!:Shift_JIS"));
+        }
+
+        // 変換できないCharsetも、フックを通さなければバイト列のまま内側のSHIORIに届きます。
+        let unknown = boot.replace("UTF-8", "X-Unknown");
+        assert!(shiori.inner_mut().handle(&unknown, &mut ctx).is_err());
+        let res = shiori
+            .inner_mut()
+            .handle_bytes(unknown.as_bytes(), &mut ctx)
+            .unwrap();
+        let res = ShioriResponse::parse(str::from_utf8(&res).unwrap()).unwrap();
+        assert_eq!(res.status, Status::BadRequest);
+
+        shiori.close().unwrap();
+        assert!(!shiori.inner().client.is_loaded());
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 乱数を提供します。
+/// テストでは差し替えて結果を固定できます。
+pub trait RandomSource: Send {
+    /// 64bitの乱数を返します。
+    fn next_u64(&mut self) -> u64;
+
+    /// `0..n`の乱数を返します。`n`が0なら0を返します。
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next_u64() % n }
+    }
+}
+
+/// 種を指定するxorshift乱数です。同じ種からは同じ乱数列を返します。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    /// 種を指定して作成します。xorshiftは0を扱えないため、0は別の値に置き換えます。
+    pub fn new(seed: u64) -> SeededRandom {
+        let state = if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        };
+        SeededRandom { state }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+/// 起動時刻を種とするxorshift乱数です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SystemRandom(SeededRandom);
+
+impl Default for SystemRandom {
+    fn default() -> SystemRandom {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        SystemRandom(SeededRandom::new(nanos))
+    }
+}
+
+impl SystemRandom {
+    pub fn new() -> SystemRandom {
+        Default::default()
+    }
+}
+
+impl RandomSource for SystemRandom {
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_random() {
+        let mut a = SeededRandom::new(42);
+        let mut b = SeededRandom::new(42);
+        let xs: Vec<u64> = (0..10).map(|_| a.below(100)).collect();
+        let ys: Vec<u64> = (0..10).map(|_| b.below(100)).collect();
+        assert_eq!(xs, ys);
+        assert!(xs.iter().all(|x| *x < 100));
+        assert_ne!(SeededRandom::new(0).next_u64(), 0);
+        assert_eq!(a.below(0), 0);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::clock::{Clock, SystemClock};
+use crate::parsers::req::ShioriRequest;
+use crate::random::{RandomSource, SystemRandom};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// トークを抑止するStatusヘッダの既定値です。
+pub const DEFAULT_BLOCKING_STATUS: [&str; 6] = [
+    "talking",
+    "choosing",
+    "minimizing",
+    "induction",
+    "passive",
+    "timecritical",
+];
+
+/// OnSecondChangeを元にランダムトーク(aitalk)の時期を決めます。
+pub struct TalkScheduler {
+    interval: Duration,
+    jitter: Duration,
+    clock: Arc<dyn Clock>,
+    blocking_status: Vec<String>,
+    next: Option<SystemTime>,
+    random: Arc<Mutex<dyn RandomSource>>,
+}
+
+impl TalkScheduler {
+    /// 指定間隔でトークするスケジューラを作成します。
+    pub fn new(interval: Duration) -> TalkScheduler {
+        TalkScheduler {
+            interval,
+            jitter: Duration::ZERO,
+            clock: Arc::new(SystemClock),
+            blocking_status: DEFAULT_BLOCKING_STATUS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            next: None,
+            random: Arc::new(Mutex::new(SystemRandom::new())),
+        }
+    }
+
+    /// 間隔の揺らぎ(±jitter)を設定します。
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// 時計を設定します。
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// 揺らぎに使う乱数を設定します。
+    pub fn with_random(mut self, random: Arc<Mutex<dyn RandomSource>>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// トークを抑止するStatusを設定します。
+    pub fn with_blocking_status<S: AsRef<str>>(mut self, status: &[S]) -> Self {
+        self.blocking_status = status.iter().map(|s| s.as_ref().to_string()).collect();
+        self
+    }
+
+    /// トーク間隔を返します。
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// トーク間隔を変更し、次回のトーク時刻を決め直します。
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        self.reset();
+    }
+
+    /// 次回のトーク時刻を返します。
+    pub fn next(&self) -> Option<SystemTime> {
+        self.next
+    }
+
+    /// 現在時刻から次回のトーク時刻を決め直します。
+    /// 他のイベントでトークした後などに呼び出してください。
+    pub fn reset(&mut self) {
+        let now = self.clock.now();
+        let wait = self.next_interval();
+        self.next = Some(now + wait);
+    }
+
+    /// リクエストの状態でトーク可能かどうかを返します。
+    /// Reference3(喋れるかどうか)とStatusヘッダを参照します。
+    pub fn can_talk(&self, req: &ShioriRequest) -> bool {
+        let cantalk = req
+            .reference
+            .iter()
+            .find(|(n, _)| *n == 3)
+            .map(|(_, v)| v.trim() != "0")
+            .unwrap_or(true);
+        if !cantalk {
+            return false;
+        }
+        let status = req.status.unwrap_or("");
+        !status
+            .split(',')
+            .map(|s| s.split('(').next().unwrap_or("").trim())
+            .any(|s| self.blocking_status.iter().any(|b| b == s))
+    }
+
+    /// OnSecondChangeリクエストを処理します。
+    /// トーク時刻を過ぎていてトーク可能なら`provider`を呼び出し、その結果を返します。
+    pub fn on_second_change<F, R>(&mut self, req: &ShioriRequest, provider: F) -> Option<R>
+    where
+        F: FnOnce() -> R,
+    {
+        if req.id != Some("OnSecondChange") {
+            return None;
+        }
+        let now = self.clock.now();
+        let next = match self.next {
+            Some(a) => a,
+            None => {
+                self.reset();
+                return None;
+            }
+        };
+        if now < next || !self.can_talk(req) {
+            return None;
+        }
+        let rc = provider();
+        let wait = self.next_interval();
+        self.next = Some(now + wait);
+        Some(rc)
+    }
+
+    fn next_interval(&mut self) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        if jitter == 0 {
+            return self.interval;
+        }
+        let offset = self
+            .random
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .below(jitter * 2 + 1);
+        let ms = (self.interval.as_millis() as u64 + offset).saturating_sub(jitter);
+        Duration::from_millis(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::random::SeededRandom;
+    use std::time::UNIX_EPOCH;
+
+    const CAN_TALK: &str = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnSecondChange\r\nReference0: 1\r\nReference1: 0\r\nReference2: 0\r\nReference3: 1\r\nReference4: 0\r\n\r\n";
+    const CANT_TALK: &str = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnSecondChange\r\nReference0: 1\r\nReference1: 0\r\nReference2: 0\r\nReference3: 0\r\nReference4: 0\r\n\r\n";
+    const TALKING: &str = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nStatus: talking,balloon(0=0)\r\nID: OnSecondChange\r\nReference3: 1\r\n\r\n";
+
+    #[test]
+    fn scheduler_1() {
+        let clock = Arc::new(VirtualClock::new(UNIX_EPOCH));
+        let mut sc = TalkScheduler::new(Duration::from_secs(3)).with_clock(clock.clone());
+        let can_talk = ShioriRequest::parse(CAN_TALK).unwrap();
+        let cant_talk = ShioriRequest::parse(CANT_TALK).unwrap();
+        let talking = ShioriRequest::parse(TALKING).unwrap();
+
+        assert_eq!(sc.on_second_change(&can_talk, || 1), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&can_talk, || 1), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&can_talk, || 1), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&cant_talk, || 1), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&talking, || 1), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&can_talk, || 1), Some(1));
+        assert_eq!(sc.next(), Some(UNIX_EPOCH + Duration::from_secs(8)));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sc.on_second_change(&can_talk, || 1), None);
+    }
+
+    #[test]
+    fn scheduler_jitter() {
+        let clock = Arc::new(VirtualClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+        let waits = |seed| {
+            let mut sc = TalkScheduler::new(Duration::from_secs(60))
+                .with_jitter(Duration::from_secs(10))
+                .with_clock(clock.clone())
+                .with_random(Arc::new(Mutex::new(SeededRandom::new(seed))));
+            (0..100)
+                .map(|_| {
+                    sc.reset();
+                    sc.next().unwrap().duration_since(clock.now()).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let a = waits(1);
+        assert!(a.iter().all(|w| *w >= Duration::from_secs(50)));
+        assert!(a.iter().all(|w| *w <= Duration::from_secs(70)));
+        assert_eq!(a, waits(1));
+        assert_ne!(a, waits(2));
+    }
+
+    #[test]
+    fn scheduler_ignore_other_event() {
+        let mut sc = TalkScheduler::new(Duration::ZERO);
+        let req = ShioriRequest::parse("GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n").unwrap();
+        assert_eq!(sc.on_second_change(&req, || 1), None);
+        assert!(sc.can_talk(&req));
+    }
+}
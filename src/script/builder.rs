@@ -0,0 +1,268 @@
+use std::fmt;
+
+/// さくらスクリプトを組み立てます。
+/// テキストは`\`と`%`をエスケープして出力します。
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct SakuraScript {
+    buf: String,
+}
+
+impl SakuraScript {
+    /// 空のスクリプトを作成します。
+    pub fn new() -> SakuraScript {
+        Default::default()
+    }
+
+    /// テキストをエスケープして追加します。改行は`\n`に置き換えます。
+    pub fn text<S: AsRef<str>>(&mut self, text: S) -> &mut Self {
+        let mut it = text.as_ref().chars().peekable();
+        while let Some(c) = it.next() {
+            match c {
+                '\\' => self.buf.push_str("\\\\"),
+                '%' => self.buf.push_str("\\%"),
+                '\r' => {
+                    if it.peek() == Some(&'\n') {
+                        it.next();
+                    }
+                    self.buf.push_str("\\n");
+                }
+                '\n' => self.buf.push_str("\\n"),
+                _ => self.buf.push(c),
+            }
+        }
+        self
+    }
+
+    /// スクリプトをエスケープせずに追加します。
+    pub fn raw<S: AsRef<str>>(&mut self, script: S) -> &mut Self {
+        self.buf.push_str(script.as_ref());
+        self
+    }
+
+    /// スコープを切り替えます。(`\0`, `\1`, `\p[n]`)
+    pub fn scope(&mut self, scope: u32) -> &mut Self {
+        match scope {
+            0 => self.raw("\\0"),
+            1 => self.raw("\\1"),
+            _ => self.tag("\\p", &[scope.to_string()]),
+        }
+    }
+
+    /// さくら側(`\0`)に切り替えます。
+    pub fn sakura(&mut self) -> &mut Self {
+        self.scope(0)
+    }
+
+    /// うにゅう側(`\1`)に切り替えます。
+    pub fn kero(&mut self) -> &mut Self {
+        self.scope(1)
+    }
+
+    /// サーフェスを切り替えます。(`\s[id]`)
+    pub fn surface(&mut self, id: i32) -> &mut Self {
+        self.tag("\\s", &[id.to_string()])
+    }
+
+    /// ミリ秒単位でウェイトします。(`\_w[ms]`)
+    pub fn wait(&mut self, ms: u32) -> &mut Self {
+        self.tag("\\_w", &[ms.to_string()])
+    }
+
+    /// 簡易ウェイトを入れます。(`\w1`～`\w9`、1単位50ms)
+    pub fn wait_unit(&mut self, n: u8) -> &mut Self {
+        let n = n.clamp(1, 9);
+        self.raw(format!("\\w{}", n))
+    }
+
+    /// 改行します。(`\n`)
+    pub fn newline(&mut self) -> &mut Self {
+        self.raw("\\n")
+    }
+
+    /// 半分の高さで改行します。(`\n[half]`)
+    pub fn newline_half(&mut self) -> &mut Self {
+        self.raw("\\n[half]")
+    }
+
+    /// バルーンをクリアします。(`\c`)
+    pub fn clear(&mut self) -> &mut Self {
+        self.raw("\\c")
+    }
+
+    /// 選択肢を追加します。(`\q[title,id]`)
+    pub fn choice<T: AsRef<str>, I: AsRef<str>>(&mut self, title: T, id: I) -> &mut Self {
+        self.choice_with_refs(title, id, &[] as &[&str])
+    }
+
+    /// Reference付きの選択肢を追加します。(`\q[title,id,r0,r1,...]`)
+    pub fn choice_with_refs<T: AsRef<str>, I: AsRef<str>, R: AsRef<str>>(
+        &mut self,
+        title: T,
+        id: I,
+        refs: &[R],
+    ) -> &mut Self {
+        let mut args = vec![title.as_ref(), id.as_ref()];
+        args.extend(refs.iter().map(|r| r.as_ref()));
+        self.tag("\\q", &args)
+    }
+
+    /// アンカーを開始します。(`\_a[id]`)
+    pub fn anchor<I: AsRef<str>>(&mut self, id: I) -> &mut Self {
+        self.tag("\\_a", &[id])
+    }
+
+    /// アンカーを終了します。(`\_a`)
+    pub fn anchor_end(&mut self) -> &mut Self {
+        self.raw("\\_a")
+    }
+
+    /// `\![...]`コマンドを追加します。
+    pub fn command<N: AsRef<str>, A: AsRef<str>>(&mut self, name: N, args: &[A]) -> &mut Self {
+        let mut items = vec![name.as_ref()];
+        items.extend(args.iter().map(|a| a.as_ref()));
+        self.tag("\\!", &items)
+    }
+
+    /// バルーンを切り替えます。(`\b[id]`)
+    pub fn balloon(&mut self, id: i32) -> &mut Self {
+        self.tag("\\b", &[id.to_string()])
+    }
+
+    /// バルーンを閉じます。(`\![close,balloon]`)
+    pub fn close_balloon(&mut self) -> &mut Self {
+        self.command("close", &["balloon"])
+    }
+
+    /// 時間待ちをせず一気に表示する区間を切り替えます。(`\_q`)
+    pub fn quick(&mut self) -> &mut Self {
+        self.raw("\\_q")
+    }
+
+    /// スクリプトを終了します。(`\e`)
+    pub fn end(&mut self) -> &mut Self {
+        self.raw("\\e")
+    }
+
+    /// スクリプトが空かどうかを返します。
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// スクリプト文字列を参照します。
+    pub fn as_str(&self) -> &str {
+        &self.buf
+    }
+
+    /// スクリプト文字列を作成します。
+    pub fn build(&self) -> String {
+        self.buf.clone()
+    }
+
+    fn tag<A: AsRef<str>>(&mut self, name: &str, args: &[A]) -> &mut Self {
+        self.buf.push_str(name);
+        self.buf.push('[');
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.buf.push(',');
+            }
+            push_arg(&mut self.buf, arg.as_ref());
+        }
+        self.buf.push(']');
+        self
+    }
+}
+
+/// タグ引数を追加します。
+/// `,`や`"`を含む引数は`"`で囲み、`"`は`""`、`]`は`\]`にエスケープします。
+fn push_arg(buf: &mut String, arg: &str) {
+    let quote = arg.contains([',', '"']);
+    if quote {
+        buf.push('"');
+    }
+    for c in arg.chars() {
+        match c {
+            '"' => buf.push_str("\"\""),
+            ']' => buf.push_str("\\]"),
+            '\r' | '\n' => (),
+            _ => buf.push(c),
+        }
+    }
+    if quote {
+        buf.push('"');
+    }
+}
+
+impl fmt::Display for SakuraScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.buf)
+    }
+}
+
+impl From<SakuraScript> for String {
+    fn from(script: SakuraScript) -> String {
+        script.buf
+    }
+}
+
+impl From<&mut SakuraScript> for String {
+    fn from(script: &mut SakuraScript) -> String {
+        script.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::res::ShioriResponse;
+
+    #[test]
+    fn script_1() {
+        let script = SakuraScript::new()
+            .sakura()
+            .surface(0)
+            .text("こんにちは。")
+            .wait(500)
+            .kero()
+            .surface(10)
+            .text("よう。")
+            .end()
+            .build();
+        assert_eq!(script, "\\0\\s[0]こんにちは。\\_w[500]\\1\\s[10]よう。\\e");
+    }
+
+    #[test]
+    fn script_2() {
+        let script = SakuraScript::new()
+            .text("C:\\ghost 100% \\e\r\nnext")
+            .build();
+        assert_eq!(script, "C:\\\\ghost 100\\% \\\\e\\nnext");
+    }
+
+    #[test]
+    fn script_3() {
+        let script = SakuraScript::new()
+            .scope(2)
+            .choice("はい", "OnYes")
+            .newline()
+            .choice_with_refs("a,b", "OnSelect", &["say \"hi\"", "x]y"])
+            .anchor("https://example.com/")
+            .text("link")
+            .anchor_end()
+            .command("set", &["alignmenttodesktop", "free"])
+            .balloon(2)
+            .close_balloon()
+            .build();
+        assert_eq!(
+            script,
+            "\\p[2]\\q[はい,OnYes]\\n\\q[\"a,b\",OnSelect,\"say \"\"hi\"\"\",x\\]y]\\_a[https://example.com/]link\\_a\\![set,alignmenttodesktop,free]\\b[2]\\![close,balloon]"
+        );
+    }
+
+    #[test]
+    fn script_to_response() {
+        let mut script = SakuraScript::new();
+        script.sakura().text("50%").end();
+        let res = ShioriResponse::ok(script);
+        assert_eq!(res.value(), Some("\\050\\%\\e"));
+    }
+}
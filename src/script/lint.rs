@@ -0,0 +1,329 @@
+use super::token::{ScriptTree, TokenKind};
+use crate::parsers::res::ShioriResponse;
+use log::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// リントルールです。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LintRule {
+    /// スクリプトが解析できない
+    Syntax,
+    /// `\_q`が閉じられていない
+    UnclosedQuick,
+    /// `\_s`が閉じられていない
+    UnclosedSync,
+    /// `\![`の括弧が閉じられていない
+    MalformedCommand,
+    /// `\q`の引数が足りない
+    ChoiceArgs,
+    /// `\s[]`のIDが数値でない
+    SurfaceId,
+    /// 末尾に`\e`がない
+    MissingEnd,
+}
+
+impl LintRule {
+    /// 全てのルールです。
+    pub const ALL: [LintRule; 7] = [
+        LintRule::Syntax,
+        LintRule::UnclosedQuick,
+        LintRule::UnclosedSync,
+        LintRule::MalformedCommand,
+        LintRule::ChoiceArgs,
+        LintRule::SurfaceId,
+        LintRule::MissingEnd,
+    ];
+}
+
+/// リント違反を検出したときの動作です。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum LintAction {
+    /// 何もしない
+    Ignore,
+    /// 警告ログを出力する
+    #[default]
+    Warn,
+    /// 警告ログを出力し、`500 Internal Server Error`を返す
+    Error,
+}
+
+/// リント違反です。位置はスクリプト(行)内のバイト位置です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LintWarning {
+    pub rule: LintRule,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}: {:?}: {}",
+            self.line, self.start, self.end, self.rule, self.message
+        )
+    }
+}
+
+/// さくらスクリプトのリンターです。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Linter {
+    pub rules: HashSet<LintRule>,
+    pub action: LintAction,
+}
+
+impl Default for Linter {
+    fn default() -> Linter {
+        Linter {
+            rules: LintRule::ALL.iter().cloned().collect(),
+            action: LintAction::default(),
+        }
+    }
+}
+
+impl Linter {
+    /// 全てのルールを有効にしたリンターを作成します。
+    pub fn new() -> Linter {
+        Default::default()
+    }
+
+    /// ルールを有効にします。
+    pub fn with_rule(mut self, rule: LintRule) -> Self {
+        self.rules.insert(rule);
+        self
+    }
+
+    /// ルールを無効にします。
+    pub fn without_rule(mut self, rule: LintRule) -> Self {
+        self.rules.remove(&rule);
+        self
+    }
+
+    /// 違反検出時の動作を設定します。
+    pub fn with_action(mut self, action: LintAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    fn push(&self, rc: &mut Vec<LintWarning>, rule: LintRule, pos: (usize, usize), message: &str) {
+        if self.rules.contains(&rule) {
+            rc.push(LintWarning {
+                rule,
+                line: 1,
+                start: pos.0,
+                end: pos.1,
+                message: message.into(),
+            });
+        }
+    }
+
+    /// スクリプトを検査します。
+    pub fn check(&self, script: &str) -> Vec<LintWarning> {
+        let mut rc = Vec::new();
+        let tree = match ScriptTree::parse(script) {
+            Ok(a) => a,
+            Err(e) => {
+                self.push(&mut rc, LintRule::Syntax, (0, script.len()), &e.to_string());
+                return rc;
+            }
+        };
+        let mut quick = None;
+        let mut sync = None;
+        for token in &tree.tokens {
+            let pos = (token.start, token.end);
+            match (token.kind, token.name) {
+                (TokenKind::Other, "") => {
+                    self.push(&mut rc, LintRule::Syntax, pos, "lone backslash");
+                }
+                (TokenKind::Other, "_q") => {
+                    quick = if quick.is_some() { None } else { Some(pos) };
+                }
+                // \_s[0,1]は常に開始、\_sは開始と終了を切り替えます。
+                (TokenKind::Other, "_s") => {
+                    sync = if sync.is_some() && !token.bracket {
+                        None
+                    } else {
+                        Some(pos)
+                    };
+                }
+                (TokenKind::Command, _) if !token.bracket || token.args.is_empty() => {
+                    self.push(
+                        &mut rc,
+                        LintRule::MalformedCommand,
+                        pos,
+                        "malformed \\![...]",
+                    );
+                }
+                (TokenKind::Choice, _) if token.bracket && token.args.len() < 2 => {
+                    self.push(
+                        &mut rc,
+                        LintRule::ChoiceArgs,
+                        pos,
+                        "\\q[title,id] requires 2 args",
+                    );
+                }
+                (TokenKind::Surface, _) if token.surface().is_none() => {
+                    self.push(
+                        &mut rc,
+                        LintRule::SurfaceId,
+                        pos,
+                        "surface id is not a number",
+                    );
+                }
+                _ => (),
+            }
+        }
+        if let Some(pos) = quick {
+            self.push(&mut rc, LintRule::UnclosedQuick, pos, "unclosed \\_q");
+        }
+        if let Some(pos) = sync {
+            self.push(&mut rc, LintRule::UnclosedSync, pos, "unclosed \\_s");
+        }
+        let ended = tree
+            .tokens
+            .iter()
+            .rev()
+            .find(|t| t.kind != TokenKind::Text || !t.text.trim().is_empty())
+            .is_some_and(|t| t.kind == TokenKind::End);
+        if !ended {
+            let end = script.len();
+            self.push(
+                &mut rc,
+                LintRule::MissingEnd,
+                (end, end),
+                "missing trailing \\e",
+            );
+        }
+        rc
+    }
+
+    /// 各行をスクリプトとみなして検査します。
+    /// 辞書ファイル等の検査に利用してください。
+    pub fn check_lines(&self, text: &str) -> Vec<LintWarning> {
+        let mut rc = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if !line.contains('\\') {
+                continue;
+            }
+            rc.extend(self.check(line).into_iter().map(|mut w| {
+                w.line = i + 1;
+                w
+            }));
+        }
+        rc
+    }
+
+    /// ファイルの各行を検査します。UTF-8として読めない文字は置き換えます。
+    pub fn check_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<LintWarning>> {
+        let bytes = std::fs::read(path)?;
+        Ok(self.check_lines(&String::from_utf8_lossy(&bytes)))
+    }
+
+    /// イベントに対するレスポンスのValueを検査します。
+    /// リソース取得等、`On`で始まらないIDのリクエストは検査しません。
+    pub fn check_response(&self, req: &str, res: &str) -> Vec<LintWarning> {
+        let is_event = req
+            .lines()
+            .any(|line| line.starts_with("ID: On") || line.starts_with("GET Sentence"));
+        if !is_event {
+            return Vec::new();
+        }
+        res.lines()
+            .filter_map(|line| line.strip_prefix("Value: "))
+            .flat_map(|value| self.check(value))
+            .collect()
+    }
+
+    /// レスポンスを検査し、設定された動作を行います。
+    pub fn filter_response<'a>(&self, req: &str, res: Cow<'a, str>) -> Cow<'a, str> {
+        if self.action == LintAction::Ignore {
+            return res;
+        }
+        let warnings = self.check_response(req, &res);
+        for w in &warnings {
+            warn!("[lint] {}", w);
+        }
+        match (self.action, warnings.first()) {
+            (LintAction::Error, Some(w)) => ShioriResponse::internal_server_error()
+                .with_header("X-Lint-Error", w.to_string())
+                .to_string()
+                .into(),
+            _ => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(warnings: &[LintWarning]) -> Vec<LintRule> {
+        warnings.iter().map(|w| w.rule).collect()
+    }
+
+    #[test]
+    fn lint_ok() {
+        let linter = Linter::new();
+        let script =
+            "\\0\\s[0]\\_qこんにちは\\_q\\_s\\0a\\1b\\_s\\q[はい,OnYes]\\![raise,OnTest]\\e";
+        assert_eq!(linter.check(script), vec![]);
+        assert_eq!(linter.check("\\_s[0,1]a\\_s\\e"), vec![]);
+        let w = linter.check("\\_s[0,1]a\\e");
+        assert_eq!(rules(&w), vec![LintRule::UnclosedSync]);
+    }
+
+    #[test]
+    fn lint_ng() {
+        let linter = Linter::new();
+        let w = linter.check("\\0\\_q\\_s\\q[only]\\s[smile]\\![open\\");
+        assert_eq!(
+            rules(&w),
+            vec![
+                LintRule::ChoiceArgs,
+                LintRule::SurfaceId,
+                LintRule::MalformedCommand,
+                LintRule::Syntax,
+                LintRule::UnclosedQuick,
+                LintRule::UnclosedSync,
+                LintRule::MissingEnd,
+            ]
+        );
+        assert_eq!((w[2].start, w[2].end), (25, 27));
+
+        let linter = Linter::new()
+            .without_rule(LintRule::MissingEnd)
+            .without_rule(LintRule::Syntax);
+        assert_eq!(linter.check("\\s[0]abc\\"), vec![]);
+    }
+
+    #[test]
+    fn lint_lines() {
+        let linter = Linter::new().without_rule(LintRule::MissingEnd);
+        let w = linter.check_lines("＊OnBoot\n\\s[0]起動\n\\s[x]だよ\n");
+        assert_eq!(w.len(), 1);
+        assert_eq!(w[0].line, 3);
+        assert_eq!(w[0].rule, LintRule::SurfaceId);
+    }
+
+    #[test]
+    fn lint_response() {
+        let req = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\n\r\n";
+        let res = "SHIORI/3.0 200 OK\r\nCharset: UTF-8\r\nValue: \\0\\s[a]\r\n\r\n";
+        let linter = Linter::new();
+        assert_eq!(linter.filter_response(req, res.into()), res);
+
+        let linter = linter.with_action(LintAction::Error);
+        let filtered = linter.filter_response(req, res.into());
+        assert!(filtered.starts_with("SHIORI/3.0 500 Internal Server Error\r\n"));
+
+        let req = "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: version\r\n\r\n";
+        let res = "SHIORI/3.0 200 OK\r\nCharset: UTF-8\r\nValue: 1.0\r\n\r\n";
+        assert_eq!(linter.filter_response(req, res.into()), res);
+    }
+}
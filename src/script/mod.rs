@@ -0,0 +1,8 @@
+pub mod builder;
+pub mod lint;
+pub mod script_parser;
+pub mod token;
+
+pub use self::builder::SakuraScript;
+pub use self::lint::Linter;
+pub use self::token::ScriptTree;
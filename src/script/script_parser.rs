@@ -0,0 +1,96 @@
+#[cfg(debug_assertions)]
+const _GRAMMAR: &str = include_str!("script_parser.pest");
+
+use pest_derive::*;
+
+#[allow(dead_code)]
+#[derive(Parser)]
+#[grammar = "script/script_parser.pest"]
+pub struct SakuraScriptParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pest::Parser;
+
+    #[test]
+    fn tag_1() {
+        let mut it = SakuraScriptParser::parse(Rule::tag, "\\s[10]")
+            .unwrap_or_else(|e| panic!("{}", e))
+            .flatten();
+
+        assert_eq!(it.next().unwrap().as_rule(), Rule::tag);
+        let pair = it.next().unwrap();
+        assert_eq!(pair.as_rule(), Rule::tag_name);
+        assert_eq!(pair.as_str(), "s");
+        assert_eq!(it.next().unwrap().as_rule(), Rule::args);
+        let pair = it.next().unwrap();
+        assert_eq!(pair.as_rule(), Rule::arg);
+        assert_eq!(pair.as_str(), "10");
+
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn tag_2() {
+        let mut it = SakuraScriptParser::parse(Rule::tag, "\\w9")
+            .unwrap_or_else(|e| panic!("{}", e))
+            .flatten();
+
+        assert_eq!(it.next().unwrap().as_rule(), Rule::tag);
+        let pair = it.next().unwrap();
+        assert_eq!(pair.as_rule(), Rule::short_name);
+        assert_eq!(pair.as_str(), "w");
+        let pair = it.next().unwrap();
+        assert_eq!(pair.as_rule(), Rule::short_arg);
+        assert_eq!(pair.as_str(), "9");
+
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn tag_3() {
+        let mut it = SakuraScriptParser::parse(Rule::tag, "\\__w[100]")
+            .unwrap_or_else(|e| panic!("{}", e))
+            .flatten();
+
+        assert_eq!(it.next().unwrap().as_rule(), Rule::tag);
+        let pair = it.next().unwrap();
+        assert_eq!(pair.as_rule(), Rule::tag_name);
+        assert_eq!(pair.as_str(), "__w");
+    }
+
+    #[test]
+    fn args_1() {
+        let items = SakuraScriptParser::parse(Rule::args, "[\"a,b\",OnSelect,x\\]y,]")
+            .unwrap_or_else(|e| panic!("{}", e))
+            .flatten()
+            .skip(1)
+            .map(|pair| pair.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(items, vec!["\"a,b\"", "OnSelect", "x\\]y", ""]);
+    }
+
+    #[test]
+    fn script_1() {
+        let items = SakuraScriptParser::parse(Rule::script, "\\0\\s[0]50\\%\\e\\")
+            .unwrap_or_else(|e| panic!("{}", e))
+            .next()
+            .unwrap()
+            .into_inner()
+            .map(|pair| (pair.as_rule(), pair.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            items,
+            vec![
+                (Rule::tag, "\\0"),
+                (Rule::tag, "\\s[0]"),
+                (Rule::text, "50"),
+                (Rule::escape, "\\%"),
+                (Rule::tag, "\\e"),
+                (Rule::lone, "\\"),
+                (Rule::EOI, ""),
+            ]
+        );
+    }
+}
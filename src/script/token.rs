@@ -0,0 +1,371 @@
+use crate::error::*;
+use pest;
+use pest::Parser as PestParser;
+use pest::iterators::Pair;
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+pub use super::script_parser::Rule;
+pub use super::script_parser::SakuraScriptParser as Parser;
+
+pub type ParseError = pest::error::Error<Rule>;
+
+/// トークンの種類です。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TokenKind {
+    /// テキスト
+    Text,
+    /// エスケープ文字(`\\`, `\%`)
+    Escape,
+    /// スコープ切り替え(`\0`, `\1`, `\h`, `\u`, `\p[n]`)
+    Scope,
+    /// サーフェス切り替え(`\s[n]`)
+    Surface,
+    /// ウェイト(`\w1`～`\w9`, `\_w[ms]`, `\__w[ms]`)
+    Wait,
+    /// 選択肢(`\q[...]`)
+    Choice,
+    /// アンカー(`\_a[...]`, `\_a`)
+    Anchor,
+    /// コマンド(`\![...]`)
+    Command,
+    /// 改行(`\n`, `\n[...]`)
+    Newline,
+    /// バルーン切り替え(`\b[n]`)
+    Balloon,
+    /// スクリプト終了(`\e`)
+    End,
+    /// その他のタグ
+    Other,
+}
+
+/// タグ引数です。`text`は引用符を含む元の文字列です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Arg<'a> {
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl<'a> Arg<'a> {
+    /// 引用符とエスケープを外した値を返します。
+    pub fn value(&self) -> Cow<'a, str> {
+        let text = self.text;
+        let quoted = text.len() >= 2 && text.starts_with('"') && text.ends_with('"');
+        let inner = if quoted {
+            &text[1..text.len() - 1]
+        } else {
+            text
+        };
+        if !(inner.contains("\\]") || quoted && inner.contains("\"\"")) {
+            return Cow::Borrowed(inner);
+        }
+        let mut value = inner.replace("\\]", "]");
+        if quoted {
+            value = value.replace("\"\"", "\"");
+        }
+        Cow::Owned(value)
+    }
+}
+
+/// さくらスクリプトのトークンです。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// トークンの元の文字列
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+    /// タグ名(`\`を除く)。テキストとエスケープでは空文字列です。
+    pub name: &'a str,
+    pub args: Vec<Arg<'a>>,
+    /// 引数が`[...]`で指定されているかどうか
+    pub bracket: bool,
+}
+
+impl<'a> Token<'a> {
+    fn from_pair(pair: Pair<'a, Rule>) -> Token<'a> {
+        let span = pair.as_span();
+        let mut token = Token {
+            kind: TokenKind::Other,
+            text: span.as_str(),
+            start: span.start(),
+            end: span.end(),
+            name: "",
+            args: Vec::new(),
+            bracket: false,
+        };
+        match pair.as_rule() {
+            Rule::text => token.kind = TokenKind::Text,
+            Rule::escape => token.kind = TokenKind::Escape,
+            Rule::tag => {
+                for pair in pair.into_inner().flatten() {
+                    let span = pair.as_span();
+                    match pair.as_rule() {
+                        Rule::short_name | Rule::tag_name => token.name = span.as_str(),
+                        Rule::args => token.bracket = true,
+                        Rule::short_arg | Rule::arg => token.args.push(Arg {
+                            text: span.as_str(),
+                            start: span.start(),
+                            end: span.end(),
+                        }),
+                        _ => (),
+                    }
+                }
+                token.kind = tag_kind(token.name);
+            }
+            _ => (),
+        }
+        token
+    }
+
+    /// タグかどうかを返します。
+    pub fn is_tag(&self) -> bool {
+        !matches!(self.kind, TokenKind::Text | TokenKind::Escape)
+    }
+
+    /// n番目の引数の値を返します。
+    pub fn arg(&self, n: usize) -> Option<Cow<'a, str>> {
+        self.args.get(n).map(|a| a.value())
+    }
+
+    /// スコープ切り替えの場合、スコープ番号を返します。
+    pub fn scope(&self) -> Option<u32> {
+        match (self.kind, self.name) {
+            (TokenKind::Scope, "0") | (TokenKind::Scope, "h") => Some(0),
+            (TokenKind::Scope, "1") | (TokenKind::Scope, "u") => Some(1),
+            (TokenKind::Scope, _) => self.arg(0)?.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// サーフェス切り替えの場合、サーフェスIDを返します。
+    pub fn surface(&self) -> Option<i32> {
+        match self.kind {
+            TokenKind::Surface => self.arg(0)?.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// ウェイトの場合、待ち時間を返します。
+    /// `\__w[ms]`はスクリプト開始からの経過時間です。
+    pub fn wait(&self) -> Option<Duration> {
+        if self.kind != TokenKind::Wait {
+            return None;
+        }
+        let n: u64 = self.arg(0)?.trim().parse().ok()?;
+        match self.name {
+            "w" => Some(Duration::from_millis(n * 50)),
+            _ => Some(Duration::from_millis(n)),
+        }
+    }
+}
+
+fn tag_kind(name: &str) -> TokenKind {
+    match name {
+        "0" | "1" | "h" | "u" | "p" => TokenKind::Scope,
+        "s" => TokenKind::Surface,
+        "w" | "_w" | "__w" => TokenKind::Wait,
+        "q" => TokenKind::Choice,
+        "_a" => TokenKind::Anchor,
+        "!" => TokenKind::Command,
+        "n" => TokenKind::Newline,
+        "b" => TokenKind::Balloon,
+        "e" => TokenKind::End,
+        _ => TokenKind::Other,
+    }
+}
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_tag() || self.name.is_empty() {
+            return f.write_str(self.text);
+        }
+        write!(f, "\\{}", self.name)?;
+        if self.bracket {
+            f.write_str("[")?;
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(arg.text)?;
+        }
+        if self.bracket {
+            f.write_str("]")?;
+        }
+        Ok(())
+    }
+}
+
+/// さくらスクリプトの解析結果を格納します。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ScriptTree<'a> {
+    pub text: &'a str,
+    pub tokens: Vec<Token<'a>>,
+}
+
+impl<'a> ScriptTree<'a> {
+    /// スクリプトを解析します。
+    pub fn parse(text: &'a str) -> MyResult<ScriptTree<'a>> {
+        let script = Parser::parse(Rule::script, text)?.next().unwrap();
+        let tokens = script
+            .into_inner()
+            .filter(|pair| pair.as_rule() != Rule::EOI)
+            .map(Token::from_pair)
+            .collect();
+        Ok(ScriptTree { text, tokens })
+    }
+
+    /// タグを除いた表示テキストを返します。
+    /// 選択肢はタイトルを、改行タグは改行文字を出力します。
+    pub fn strip_tags(&self) -> String {
+        let mut buf = String::new();
+        for token in &self.tokens {
+            match token.kind {
+                TokenKind::Text => buf.push_str(token.text),
+                TokenKind::Escape => buf.push_str(&token.text[1..]),
+                TokenKind::Newline => buf.push('\n'),
+                TokenKind::Choice => {
+                    if let Some(title) = token.arg(0) {
+                        buf.push_str(&title);
+                    }
+                }
+                _ => (),
+            }
+        }
+        buf
+    }
+
+    /// 利用しているサーフェスIDを出現順に重複なく返します。
+    pub fn surfaces(&self) -> Vec<i32> {
+        let mut rc = Vec::new();
+        for id in self.tokens.iter().filter_map(|t| t.surface()) {
+            if !rc.contains(&id) {
+                rc.push(id);
+            }
+        }
+        rc
+    }
+
+    /// ウェイトから見積もったトーク時間を返します。
+    pub fn duration(&self) -> Duration {
+        let mut total = Duration::ZERO;
+        for token in &self.tokens {
+            if let Some(wait) = token.wait() {
+                match token.name {
+                    "__w" => total = total.max(wait),
+                    _ => total += wait,
+                }
+            }
+        }
+        total
+    }
+}
+
+impl fmt::Display for ScriptTree<'_> {
+    /// トークン列からスクリプトを再構成します。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            write!(f, "{}", token)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\\0\\s[0]こんにちは、\\w9%usernameさん。\\_w[500]\\n\\1\\s10100\\% \\_q!\\_q\\n[half]\\p[2]\\q[\"a,b\",OnA,\"say \"\"hi\"\"\"]\\q[x\\]y,OnB]\\![raise,OnTest,r0]\\_a[https://example.com/]link\\_a\\b[2]\\__w[3000]\\e";
+
+    #[test]
+    fn tree_1() {
+        let tree = ScriptTree::parse(SCRIPT).unwrap_or_else(|e| panic!("{}", e));
+        let kinds = tree.tokens.iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Scope,
+                TokenKind::Surface,
+                TokenKind::Text,
+                TokenKind::Wait,
+                TokenKind::Text,
+                TokenKind::Wait,
+                TokenKind::Newline,
+                TokenKind::Scope,
+                TokenKind::Surface,
+                TokenKind::Text,
+                TokenKind::Escape,
+                TokenKind::Text,
+                TokenKind::Other,
+                TokenKind::Text,
+                TokenKind::Other,
+                TokenKind::Newline,
+                TokenKind::Scope,
+                TokenKind::Choice,
+                TokenKind::Choice,
+                TokenKind::Command,
+                TokenKind::Anchor,
+                TokenKind::Text,
+                TokenKind::Anchor,
+                TokenKind::Balloon,
+                TokenKind::Wait,
+                TokenKind::End,
+            ]
+        );
+
+        let token = &tree.tokens[1];
+        assert_eq!(token.text, "\\s[0]");
+        assert_eq!((token.start, token.end), (2, 7));
+        assert_eq!(token.surface(), Some(0));
+
+        assert_eq!(tree.tokens[7].scope(), Some(1));
+        assert_eq!(tree.tokens[8].surface(), Some(1));
+        assert_eq!(tree.tokens[9].text, "0100");
+        assert_eq!(tree.tokens[16].scope(), Some(2));
+
+        let token = &tree.tokens[17];
+        assert_eq!(token.arg(0).unwrap(), "a,b");
+        assert_eq!(token.arg(1).unwrap(), "OnA");
+        assert_eq!(token.arg(2).unwrap(), "say \"hi\"");
+        assert_eq!(tree.tokens[18].arg(0).unwrap(), "x]y");
+
+        let token = &tree.tokens[19];
+        assert_eq!(token.name, "!");
+        assert_eq!(token.args.len(), 3);
+        assert_eq!(token.arg(0).unwrap(), "raise");
+    }
+
+    #[test]
+    fn tree_print() {
+        let tree = ScriptTree::parse(SCRIPT).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(tree.to_string(), SCRIPT);
+
+        for src in ["", "\\", "abc\\", "\\![open", "\\s[]\\q[a]\\_w[x]", "\\_"] {
+            let tree = ScriptTree::parse(src).unwrap_or_else(|e| panic!("{}", e));
+            assert_eq!(tree.to_string(), src);
+        }
+    }
+
+    #[test]
+    fn tree_strip_tags() {
+        let tree = ScriptTree::parse(SCRIPT).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            tree.strip_tags(),
+            "こんにちは、%usernameさん。\n0100% !\na,bx]ylink"
+        );
+    }
+
+    #[test]
+    fn tree_surfaces_and_duration() {
+        let tree = ScriptTree::parse(SCRIPT).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(tree.surfaces(), vec![0, 1]);
+        assert_eq!(tree.duration(), Duration::from_millis(3000));
+
+        let tree = ScriptTree::parse("\\s[5]a\\w5b\\_w[100]\\s[5]\\s[-1]").unwrap();
+        assert_eq!(tree.surfaces(), vec![5, -1]);
+        assert_eq!(tree.duration(), Duration::from_millis(350));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::clock::{Clock, SystemClock};
+use crate::fs::{DiskFileSystem, FileSystem};
+use crate::random::{RandomSource, SystemRandom};
+use crate::scheduler::TalkScheduler;
+use crate::store::Store;
+use log::{Log, Metadata, Record};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// `log`クレートに設定されたロガーへ転送するロガーです。
+struct GlobalLogger;
+
+impl Log for GlobalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        log::logger().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        log::logger().log(record)
+    }
+
+    fn flush(&self) {
+        log::logger().flush()
+    }
+}
+
+/// ホストがロード時にSHIORIへ渡すサービスです。
+/// テストでは時計や乱数、ファイルシステムを差し替えられます。
+#[derive(Clone)]
+pub struct Services {
+    clock: Arc<dyn Clock>,
+    random: Arc<Mutex<dyn RandomSource>>,
+    fs: Option<Arc<dyn FileSystem>>,
+    store: Option<Arc<Mutex<Store>>>,
+    logger: Arc<dyn Log>,
+    scheduler: Option<Arc<Mutex<TalkScheduler>>>,
+}
+
+impl Default for Services {
+    fn default() -> Services {
+        Services {
+            clock: Arc::new(SystemClock),
+            random: Arc::new(Mutex::new(SystemRandom::new())),
+            fs: None,
+            store: None,
+            logger: Arc::new(GlobalLogger),
+            scheduler: None,
+        }
+    }
+}
+
+impl Services {
+    /// システム時計と乱数を使うサービスを作成します。
+    pub fn new() -> Services {
+        Default::default()
+    }
+
+    /// 時計を設定します。
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// 乱数を設定します。
+    pub fn with_random<R: RandomSource + 'static>(mut self, random: R) -> Self {
+        self.random = Arc::new(Mutex::new(random));
+        self
+    }
+
+    /// 共有する乱数を設定します。
+    pub fn with_shared_random(mut self, random: Arc<Mutex<dyn RandomSource>>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// ファイルシステムを設定します。
+    pub fn with_fs(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.fs = Some(fs);
+        self
+    }
+
+    /// ファイルシステムが未設定なら、rootをルートとするディスクを設定します。
+    pub fn or_fs_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        if self.fs.is_none() {
+            self.fs = Some(Arc::new(DiskFileSystem::new(root)));
+        }
+        self
+    }
+
+    /// 永続化ストアを設定します。
+    pub fn with_store(mut self, store: Arc<Mutex<Store>>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// ロガーを設定します。
+    pub fn with_logger(mut self, logger: Arc<dyn Log>) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// トークスケジューラを設定します。
+    pub fn with_scheduler(mut self, scheduler: Arc<Mutex<TalkScheduler>>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// 時計を返します。
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// 乱数を返します。
+    pub fn random(&self) -> &Arc<Mutex<dyn RandomSource>> {
+        &self.random
+    }
+
+    /// ファイルシステムを返します。設定されていなければNoneです。
+    pub fn fs(&self) -> Option<&Arc<dyn FileSystem>> {
+        self.fs.as_ref()
+    }
+
+    /// 永続化ストアを返します。設定されていなければNoneです。
+    pub fn store(&self) -> Option<&Arc<Mutex<Store>>> {
+        self.store.as_ref()
+    }
+
+    /// ロガーを返します。
+    pub fn logger(&self) -> &dyn Log {
+        self.logger.as_ref()
+    }
+
+    /// トークスケジューラを返します。設定されていなければNoneです。
+    pub fn scheduler(&self) -> Option<&Arc<Mutex<TalkScheduler>>> {
+        self.scheduler.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    struct Fixed;
+
+    impl Clock for Fixed {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(10)
+        }
+    }
+
+    struct Zero;
+
+    impl RandomSource for Zero {
+        fn next_u64(&mut self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn services() {
+        let services = Services::new().or_fs_root("disk");
+        assert_eq!(services.fs().unwrap().root(), Path::new("disk"));
+        assert!(services.store().is_none());
+        assert!(services.scheduler().is_none());
+
+        let services = Services::new()
+            .with_clock(Arc::new(Fixed))
+            .with_random(Zero)
+            .with_fs(Arc::new(MemoryFileSystem::new("mem")))
+            .or_fs_root("disk");
+        assert_eq!(services.clock().now(), UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(services.random().lock().unwrap().below(10), 0);
+        assert_eq!(services.fs().unwrap().root(), Path::new("mem"));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::api::{RawShiori3, Shiori3};
+use crate::clock::{Clock, VirtualClock};
+use crate::error::*;
+use crate::hglobal::{ShioriString, load_dir_bytes};
+use crate::parsers::res::Status;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 起動時に取得されるリソースです。
+const BOOT_RESOURCES: [&str; 5] = ["useorigin1", "name", "craftman", "craftmanw", "username"];
+
+/// ベースウェアが送ったリクエストと、SHIORIの応答の組です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Exchange {
+    pub request: String,
+    /// SHIORIがNULLを返した場合はNoneです。
+    pub response: Option<String>,
+}
+
+impl Exchange {
+    /// リクエストのIDを返します。
+    pub fn id(&self) -> Option<&str> {
+        header(&self.request, "ID")
+    }
+
+    /// 応答のステータスを返します。
+    pub fn status(&self) -> Option<Status> {
+        self.response
+            .as_deref()
+            .and_then(|res| res.lines().next())
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .and_then(Status::from_code)
+    }
+
+    /// 応答のValueヘッダを返します。
+    pub fn value(&self) -> Option<&str> {
+        self.response
+            .as_deref()
+            .and_then(|res| header(res, "Value"))
+    }
+}
+
+fn header<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    text.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(": "))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Linux等のテストでSHIORIを駆動する、プロセス内のベースウェアです。
+/// `RawShiori3`のload/request/unloadを実際のABIと同じ手順で呼び出し、
+/// 全てのリクエストと応答を記録します。
+/// 時計は`VirtualClock`で、既定では実行毎に同じ結果になるよう`UNIX_EPOCH`から始まります。
+/// `tick()`で進めると`OnSecondChange`等を送ります。
+pub struct BasewareSimulator<T = ()> {
+    shiori: RawShiori3<T>,
+    load_dir: PathBuf,
+    clock: Arc<VirtualClock>,
+    sender: String,
+    ghost_name: String,
+    shell_name: String,
+    loaded: bool,
+    exchanges: Vec<Exchange>,
+}
+
+impl<T: Shiori3> BasewareSimulator<T> {
+    /// `Shiori3`の実装`T`を駆動するシミュレータを作成します。
+    pub fn new<P: AsRef<Path>>(load_dir: P) -> BasewareSimulator<T> {
+        BasewareSimulator::with_raw(RawShiori3::default(), load_dir)
+    }
+}
+
+impl<T> BasewareSimulator<T> {
+    /// 作成済みの`RawShiori3`を駆動するシミュレータを作成します。
+    pub fn with_raw<P: AsRef<Path>>(shiori: RawShiori3<T>, load_dir: P) -> BasewareSimulator<T> {
+        let mut sim = BasewareSimulator {
+            shiori,
+            load_dir: load_dir.as_ref().to_path_buf(),
+            clock: Arc::new(VirtualClock::new(UNIX_EPOCH)),
+            sender: "shiori3".into(),
+            ghost_name: "ghost".into(),
+            shell_name: "master".into(),
+            loaded: false,
+            exchanges: Vec::new(),
+        };
+        sim.shiori.set_clock(sim.clock.clone());
+        sim
+    }
+
+    /// 仮想時計を設定します。
+    pub fn with_clock(mut self, clock: Arc<VirtualClock>) -> Self {
+        self.shiori.set_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Senderヘッダの値を設定します。
+    pub fn with_sender<S: Into<String>>(mut self, sender: S) -> Self {
+        self.sender = sender.into();
+        self
+    }
+
+    /// ゴースト名(ownerghostname)とシェル名(OnBoot)を設定します。
+    pub fn with_names<S: Into<String>>(mut self, ghost: S, shell: S) -> Self {
+        self.ghost_name = ghost.into();
+        self.shell_name = shell.into();
+        self
+    }
+
+    /// 仮想時計を返します。
+    pub fn clock(&self) -> &Arc<VirtualClock> {
+        &self.clock
+    }
+
+    /// 駆動している`RawShiori3`を返します。
+    pub fn shiori_mut(&mut self) -> &mut RawShiori3<T> {
+        &mut self.shiori
+    }
+
+    /// 記録したリクエストと応答を返します。
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    /// 指定IDの最後のリクエストと応答を返します。
+    pub fn find(&self, id: &str) -> Option<&Exchange> {
+        self.exchanges.iter().rev().find(|e| e.id() == Some(id))
+    }
+
+    /// 記録を消去します。
+    pub fn clear(&mut self) {
+        self.exchanges.clear();
+    }
+
+    /// shiori.dll:loadを呼び出します。
+    /// ロードディレクトリはパスセパレーターで終わる文字列として渡します。
+    pub fn load(&mut self) -> MyResult<()> {
+        let bytes = load_dir_bytes(&self.load_dir)?;
+        let h = ShioriString::clone_from_slice_nofree(&bytes);
+        let (h, len) = h.value();
+        if !self.shiori.raw_load(h, len) {
+            return Err(MyError::Load);
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    /// shiori.dll:unloadを呼び出します。drop時にも呼ばれます。
+    pub fn unload(&mut self) -> bool {
+        if !self.loaded {
+            return true;
+        }
+        self.loaded = false;
+        self.shiori.raw_unload()
+    }
+
+    /// shiori.dll:requestを呼び出し、記録した応答を返します。
+    pub fn request(&mut self, text: &str) -> MyResult<&Exchange> {
+        if !self.loaded {
+            return Err(MyError::NotInitialized);
+        }
+        let req = ShioriString::clone_from_str_charset_nofree(text)?;
+        let (h, mut len) = req.value();
+        let h = self.shiori.raw_request(h, &mut len);
+        let response = if h.is_null() {
+            None
+        } else {
+            let res = ShioriString::capture(h, len);
+            Some(res.to_charset_str()?.into_owned())
+        };
+        self.exchanges.push(Exchange {
+            request: text.into(),
+            response,
+        });
+        Ok(self.exchanges.last().unwrap())
+    }
+
+    /// `GET SHIORI/3.0`リクエストを送ります。
+    pub fn get(&mut self, id: &str, refs: &[&str]) -> MyResult<&Exchange> {
+        let text = self.build("GET", id, refs);
+        self.request(&text)
+    }
+
+    /// `NOTIFY SHIORI/3.0`リクエストを送ります。
+    pub fn notify(&mut self, id: &str, refs: &[&str]) -> MyResult<&Exchange> {
+        let text = self.build("NOTIFY", id, refs);
+        self.request(&text)
+    }
+
+    fn build(&self, method: &str, id: &str, refs: &[&str]) -> String {
+        let mut text = format!(
+            "{} SHIORI/3.0\r\nCharset: UTF-8\r\nSender: {}\r\nSecurityLevel: local\r\nID: {}\r\n",
+            method, self.sender, id
+        );
+        for (i, r) in refs.iter().enumerate() {
+            text.push_str(&format!("Reference{}: {}\r\n", i, r));
+        }
+        text.push_str("\r\n");
+        text
+    }
+
+    /// loadと、SSPが起動時に送る一連のリクエストを送ります。
+    /// `GET Version`、`version`、`OnInitialize`、`ownerghostname`、
+    /// `basewareversion`、リソースの取得、`OnBoot`の順です。
+    pub fn boot(&mut self) -> MyResult<()> {
+        if !self.loaded {
+            self.load()?;
+        }
+        let text = format!(
+            "GET Version SHIORI/2.6\r\nCharset: UTF-8\r\nSender: {}\r\n\r\n",
+            self.sender
+        );
+        self.request(&text)?;
+        self.get("version", &[])?;
+        self.notify("OnInitialize", &[""])?;
+        let ghost_name = self.ghost_name.clone();
+        self.notify("ownerghostname", &[&ghost_name])?;
+        let version = env!("CARGO_PKG_VERSION");
+        self.notify("basewareversion", &[version, "shiori3", version])?;
+        for id in BOOT_RESOURCES.iter() {
+            self.get(id, &[])?;
+        }
+        let shell_name = self.shell_name.clone();
+        self.get("OnBoot", &[&shell_name])?;
+        Ok(())
+    }
+
+    /// 仮想時計を進め、毎秒`OnSecondChange`を、分が変わると`OnMinuteChange`を送ります。
+    pub fn tick(&mut self, duration: Duration) -> MyResult<()> {
+        let clock = self.clock.clone();
+        let mut rc = Ok(());
+        clock.advance_with_ticks(duration, |req| {
+            if rc.is_ok() {
+                rc = self.request(req).map(|_| ());
+            }
+        });
+        rc
+    }
+
+    /// 仮想時計の現在時刻を返します。
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+}
+
+impl<T> Drop for BasewareSimulator<T> {
+    fn drop(&mut self) {
+        self.unload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ServiceHandler, ShioriHandler, ShioriService};
+    use crate::context::RequestContext;
+    use crate::parsers::req::ShioriRequest;
+    use crate::parsers::res::ShioriResponse;
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::UNIX_EPOCH;
+
+    static UNLOADED: AtomicBool = AtomicBool::new(false);
+
+    struct Ghost;
+
+    impl Shiori3 for Ghost {
+        fn load<P: AsRef<Path>>(
+            _h_inst: usize,
+            load_dir: P,
+            _load_dir_bytes: &[u8],
+        ) -> Result<Self, anyhow::Error> {
+            assert!(load_dir.as_ref().ends_with("ghost/master"));
+            Ok(Ghost)
+        }
+
+        fn request<'a, S: Into<&'a str>>(&mut self, req: S) -> Result<Cow<'a, str>, anyhow::Error> {
+            let req = ShioriRequest::parse(req.into())?;
+            let res = match req.id {
+                Some("OnBoot") => ShioriResponse::ok("\\0hello\\e"),
+                _ => ShioriResponse::no_content(),
+            };
+            Ok(res.to_string().into())
+        }
+
+        fn unload(&mut self) -> Result<(), anyhow::Error> {
+            UNLOADED.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn simulator_boot() {
+        let mut sim = BasewareSimulator::<Ghost>::new("ghost/master");
+        assert_eq!(sim.now(), UNIX_EPOCH);
+        sim.boot().unwrap();
+        let ids: Vec<_> = sim.exchanges().iter().map(|e| e.id()).collect();
+        assert_eq!(ids[0], None);
+        assert_eq!(ids[1], Some("version"));
+        assert_eq!(ids[2], Some("OnInitialize"));
+        assert_eq!(ids.last().unwrap(), &Some("OnBoot"));
+        let boot = sim.find("OnBoot").unwrap();
+        assert_eq!(boot.status(), Some(Status::Ok));
+        assert_eq!(boot.value(), Some("\\0hello\\e"));
+        assert_eq!(
+            sim.find("version").unwrap().status(),
+            Some(Status::NoContent)
+        );
+
+        sim.clear();
+        sim.tick(Duration::from_secs(5)).unwrap();
+        assert_eq!(sim.exchanges().len(), 5);
+        drop(sim);
+        assert!(UNLOADED.load(Ordering::SeqCst));
+    }
+
+    struct Booted;
+
+    impl ShioriService for Booted {
+        fn request(
+            &mut self,
+            _req: &ShioriRequest,
+            ctx: &mut RequestContext,
+        ) -> Result<ShioriResponse, anyhow::Error> {
+            let now = ctx.clock().now().duration_since(UNIX_EPOCH)?.as_secs();
+            Ok(ShioriResponse::ok(format!("{},{}", ctx.booted, now)))
+        }
+    }
+
+    #[test]
+    fn simulator_context() {
+        let factory = |_h_inst: usize, _load_dir: &Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = ServiceHandler::boxed(Booted);
+            Ok(shiori)
+        };
+        let clock = Arc::new(VirtualClock::new(UNIX_EPOCH + Duration::from_secs(59)));
+        let mut sim = BasewareSimulator::with_raw(RawShiori3::with_factory(factory), "ghost")
+            .with_clock(clock);
+        assert!(sim.get("OnBoot", &[]).is_err());
+        sim.load().unwrap();
+        assert_eq!(sim.get("OnBoot", &[]).unwrap().value(), Some("false,59"));
+        sim.tick(Duration::from_secs(1)).unwrap();
+        let ids: Vec<_> = sim.exchanges().iter().filter_map(|e| e.id()).collect();
+        assert_eq!(ids, vec!["OnBoot", "OnSecondChange", "OnMinuteChange"]);
+        assert_eq!(sim.find("OnMinuteChange").unwrap().value(), Some("true,60"));
+        assert!(sim.unload());
+    }
+}
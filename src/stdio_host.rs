@@ -0,0 +1,586 @@
+use crate::api::RawShiori3;
+use crate::hglobal::{HGLOBAL, ShioriString};
+use crate::parsers::res::ShioriResponse;
+use log::*;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// ホストからワーカーへ: loadディレクトリ
+const TAG_LOAD: u8 = b'L';
+/// ホストからワーカーへ: リクエスト
+const TAG_REQUEST: u8 = b'R';
+/// ホストからワーカーへ: unload
+const TAG_UNLOAD: u8 = b'U';
+/// ワーカーからホストへ: 成功と応答
+const TAG_OK: u8 = b'O';
+/// ワーカーからホストへ: 失敗、またはNULL応答
+const TAG_NULL: u8 = b'N';
+
+/// フレームの最大長です。壊れたストリームで巨大な確保をしないための上限です。
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// ワーカーの応答を待つ時間の既定値です。
+pub const DEFAULT_WORKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Frame = io::Result<Option<(u8, Vec<u8>)>>;
+
+/// `[タグ 1byte][長さ u32 LE][本体]`のフレームを書き込みます。
+fn write_frame<W: Write + ?Sized>(w: &mut W, tag: u8, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    w.write_all(&[tag])?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+/// フレームを読み込みます。フレームの境界でストリームが終わった場合は`None`を返します。
+fn read_frame<R: Read + ?Sized>(r: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut tag = [0u8; 1];
+    if r.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some((tag[0], payload)))
+}
+
+/// ワーカープロセスとの接続です。drop時にプロセスが残っていれば終了させます。
+/// 応答は読み込み用のスレッドで受け取り、待ち時間を超えるとエラーにします。
+pub struct WorkerConnection {
+    writer: Box<dyn Write + Send>,
+    frames: Receiver<Frame>,
+    timeout: Option<Duration>,
+    child: Option<Child>,
+}
+
+impl WorkerConnection {
+    /// 任意のストリームで接続を作成します。テストや独自の起動方法で利用します。
+    /// 応答は時間の制限なく待ちます。
+    pub fn new<W, R>(writer: W, reader: R) -> WorkerConnection
+    where
+        W: Write + Send + 'static,
+        R: Read + Send + 'static,
+    {
+        let (tx, frames) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let frame = read_frame(&mut reader);
+                let end = !matches!(frame, Ok(Some(_)));
+                if tx.send(frame).is_err() || end {
+                    break;
+                }
+            }
+        });
+        WorkerConnection {
+            writer: Box::new(BufWriter::new(writer)),
+            frames,
+            timeout: None,
+            child: None,
+        }
+    }
+
+    /// 応答を待つ時間を設定します。
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// コマンドを標準入出力をパイプにして起動します。標準エラーは引き継ぎます。
+    pub fn spawn(command: &mut Command) -> io::Result<WorkerConnection> {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let mut conn = WorkerConnection::new(stdin, stdout);
+        conn.child = Some(child);
+        Ok(conn)
+    }
+
+    /// フレームを送り、応答のフレームを受け取ります。
+    /// `TAG_NULL`の場合は`None`を返します。
+    /// 待ち時間を超えた場合は`TimedOut`のエラーです。接続は使えなくなるので破棄してください。
+    fn call(&mut self, tag: u8, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        write_frame(&mut self.writer, tag, payload)?;
+        let frame = match self.timeout {
+            Some(timeout) => self.frames.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => {
+                    io::Error::new(io::ErrorKind::TimedOut, "worker timed out")
+                }
+                RecvTimeoutError::Disconnected => io::ErrorKind::UnexpectedEof.into(),
+            })?,
+            None => self
+                .frames
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?,
+        };
+        match frame? {
+            Some((TAG_OK, payload)) => Ok(Some(payload)),
+            Some((TAG_NULL, _)) => Ok(None),
+            Some((tag, _)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected frame tag {:#04x}", tag),
+            )),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// 標準入力を閉じ、プロセスの終了を待ちます。
+    fn close(mut self) {
+        self.writer = Box::new(io::sink());
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for WorkerConnection {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+/// ロードディレクトリを受け取り、ワーカーを起動する関数です。
+type SpawnFn = Box<dyn Fn(&Path) -> io::Result<WorkerConnection> + Send>;
+
+/// SHIORIを別プロセスで動かすためのシムです。
+/// エクスポートするload/unload/requestから`RawShiori3`と同じように呼び出してください。
+/// リクエストは長さ付きのフレームでワーカーの標準入出力に中継し、
+/// 実際の`Shiori3`はワーカーの`StdioWorker`で動かします。
+///
+/// ワーカーが終了するか応答が`with_timeout()`の時間を超えた場合、
+/// ワーカーを終了させてそのリクエストには`500`を返し、
+/// 次のリクエストでワーカーを起動し直してloadからやり直します。
+/// 連続して`max_restarts`回を超えて失敗すると、以降は起動せずに`500`を返します。
+pub struct StdioHost {
+    spawn: SpawnFn,
+    max_restarts: usize,
+    timeout: Duration,
+    failures: usize,
+    load_dir: Option<(PathBuf, Vec<u8>)>,
+    worker: Option<WorkerConnection>,
+}
+
+impl StdioHost {
+    /// ロードディレクトリにあるワーカーの実行ファイルを起動するシムを作成します。
+    /// 相対パスはロードディレクトリからのパスです。
+    pub fn new<P: AsRef<Path>>(worker: P) -> StdioHost {
+        let worker = worker.as_ref().to_path_buf();
+        StdioHost::with_spawner(move |load_dir| {
+            let path: PathBuf = load_dir.join(&worker);
+            let mut command = Command::new(path);
+            command.current_dir(load_dir);
+            WorkerConnection::spawn(&mut command)
+        })
+    }
+
+    /// ワーカーの起動方法を指定してシムを作成します。
+    pub fn with_spawner<F>(spawn: F) -> StdioHost
+    where
+        F: Fn(&Path) -> io::Result<WorkerConnection> + Send + 'static,
+    {
+        StdioHost {
+            spawn: Box::new(spawn),
+            max_restarts: 3,
+            timeout: DEFAULT_WORKER_TIMEOUT,
+            failures: 0,
+            load_dir: None,
+            worker: None,
+        }
+    }
+
+    /// 連続して再起動する回数の上限を設定します。
+    pub fn with_max_restarts(mut self, max_restarts: usize) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// ワーカーの応答を待つ時間を設定します。
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// ワーカーが動いているかどうかを返します。
+    pub fn is_running(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// shiori.dll:load
+    pub fn raw_load(&mut self, hdir: HGLOBAL, len: usize) -> bool {
+        self.raw_unload();
+        let gdir = ShioriString::capture(hdir, len);
+        let path = match gdir.to_ansi_str() {
+            Ok(path) => PathBuf::from(path),
+            Err(e) => {
+                error!("[stdio] load: {}", e);
+                return false;
+            }
+        };
+        self.load_dir = Some((path, gdir.as_bytes().to_vec()));
+        self.failures = 0;
+        match self.worker() {
+            Ok(_) => true,
+            Err(e) => {
+                error!("[stdio] load: {}", e);
+                self.load_dir = None;
+                false
+            }
+        }
+    }
+
+    /// shiori.dll:unload
+    pub fn raw_unload(&mut self) -> bool {
+        self.load_dir = None;
+        let mut worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => return true,
+        };
+        match worker.call(TAG_UNLOAD, &[]) {
+            Ok(rc) => {
+                worker.close();
+                rc.is_some()
+            }
+            Err(e) => {
+                error!("[stdio] unload: {}", e);
+                false
+            }
+        }
+    }
+
+    /// shiori.dll:request
+    /// ワーカーとの通信に失敗した場合は`500`を返します。
+    pub fn raw_request(&mut self, hreq: HGLOBAL, len: &mut usize) -> HGLOBAL {
+        let greq = ShioriString::capture(hreq, *len);
+        let res = match self.request(greq.as_bytes()) {
+            Ok(Some(res)) => res,
+            Ok(None) => {
+                *len = 0;
+                return ptr::null_mut();
+            }
+            Err(e) => {
+                error!("[stdio] request: {}", e);
+                ShioriResponse::internal_server_error()
+                    .with_header("X-ERROR-REASON", format!("worker: {}", e))
+                    .to_string()
+                    .into_bytes()
+            }
+        };
+        let (h, l) = ShioriString::clone_from_slice_nofree(&res).value();
+        *len = l;
+        h
+    }
+
+    fn request(&mut self, req: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let worker = self.worker()?;
+        match worker.call(TAG_REQUEST, req) {
+            Ok(res) => {
+                self.failures = 0;
+                Ok(res)
+            }
+            Err(e) => {
+                self.worker = None;
+                self.failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// 動いているワーカーを返します。なければ起動してloadします。
+    fn worker(&mut self) -> io::Result<&mut WorkerConnection> {
+        if self.worker.is_none() {
+            let (path, dir) = self
+                .load_dir
+                .as_ref()
+                .ok_or_else(|| io::Error::other("not loaded"))?;
+            if self.failures > self.max_restarts {
+                return Err(io::Error::other("worker restart limit exceeded"));
+            }
+            let timeout = self.timeout;
+            let worker = (self.spawn)(path).and_then(|worker| {
+                let mut worker = worker.with_timeout(timeout);
+                match worker.call(TAG_LOAD, dir)? {
+                    Some(_) => Ok(worker),
+                    None => Err(io::Error::other("worker load failed")),
+                }
+            });
+            let worker = worker.inspect_err(|_| self.failures += 1)?;
+            self.worker = Some(worker);
+        }
+        Ok(self.worker.as_mut().unwrap())
+    }
+}
+
+impl Drop for StdioHost {
+    fn drop(&mut self) {
+        self.raw_unload();
+    }
+}
+
+/// ワーカープロセス側で`RawShiori3`を動かします。
+/// 標準出力はフレームの送信に使うため、ログは標準エラーかファイルに出力してください。
+pub struct StdioWorker<T = ()> {
+    shiori: RawShiori3<T>,
+}
+
+impl<T> StdioWorker<T> {
+    pub fn new(shiori: RawShiori3<T>) -> StdioWorker<T> {
+        StdioWorker { shiori }
+    }
+
+    /// 包んでいる`RawShiori3`を参照します。
+    pub fn shiori_mut(&mut self) -> &mut RawShiori3<T> {
+        &mut self.shiori
+    }
+
+    /// 標準入出力でホストの要求を処理します。unloadか入力の終わりで戻ります。
+    pub fn run_stdio(&mut self) -> io::Result<()> {
+        let stdin = io::stdin().lock();
+        let stdout = io::stdout().lock();
+        self.run(BufReader::new(stdin), BufWriter::new(stdout))
+    }
+
+    /// ホストの要求を処理します。unloadか入力の終わりで戻ります。
+    pub fn run<R: Read, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while self.serve(&mut input, &mut output)? {}
+        Ok(())
+    }
+
+    /// 要求を一つ処理します。unloadか入力の終わりで`false`を返します。
+    pub fn serve<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> io::Result<bool> {
+        let (tag, payload) = match read_frame(input)? {
+            Some(frame) => frame,
+            None => {
+                self.shiori.raw_unload();
+                return Ok(false);
+            }
+        };
+        match tag {
+            TAG_LOAD => {
+                let (h, len) = ShioriString::clone_from_slice_nofree(&payload).value();
+                let rc = self.shiori.raw_load(h, len);
+                write_frame(output, if rc { TAG_OK } else { TAG_NULL }, &[])?;
+            }
+            TAG_REQUEST => {
+                let (h, mut len) = ShioriString::clone_from_slice_nofree(&payload).value();
+                let h = self.shiori.raw_request(h, &mut len);
+                if h.is_null() {
+                    write_frame(output, TAG_NULL, &[])?;
+                } else {
+                    let res = ShioriString::capture(h, len);
+                    write_frame(output, TAG_OK, res.as_bytes())?;
+                }
+            }
+            TAG_UNLOAD => {
+                let rc = self.shiori.raw_unload();
+                write_frame(output, if rc { TAG_OK } else { TAG_NULL }, &[])?;
+                return Ok(false);
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected frame tag {:#04x}", tag),
+                ));
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ServiceHandler, ShioriHandler, ShioriService};
+    use crate::context::RequestContext;
+    use crate::parsers::req::ShioriRequest;
+    use crate::parsers::res::Status;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// OnDieを受け取るとワーカーを終了させるSHIORIです。
+    struct Ghost {
+        load_dir: PathBuf,
+        die: Arc<AtomicBool>,
+    }
+
+    impl ShioriService for Ghost {
+        fn request(
+            &mut self,
+            req: &ShioriRequest,
+            _ctx: &mut RequestContext,
+        ) -> Result<ShioriResponse, anyhow::Error> {
+            match req.id {
+                Some("OnDie") => {
+                    self.die.store(true, Ordering::SeqCst);
+                    Ok(ShioriResponse::no_content())
+                }
+                Some("OnDir") => Ok(ShioriResponse::ok(self.load_dir.display().to_string())),
+                Some("OnHang") => {
+                    thread::sleep(Duration::from_secs(1));
+                    Ok(ShioriResponse::no_content())
+                }
+                id => Ok(ShioriResponse::ok(id.unwrap_or_default())),
+            }
+        }
+    }
+
+    /// `die`が立つと書き込みに失敗する出力です。
+    struct Dying<W> {
+        inner: W,
+        die: Arc<AtomicBool>,
+    }
+
+    impl<W: Write> Write for Dying<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.die.load(Ordering::SeqCst) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// ワーカーをスレッドで起動するシムを作成します。
+    fn host(spawned: Arc<AtomicUsize>) -> StdioHost {
+        StdioHost::with_spawner(move |_load_dir| {
+            spawned.fetch_add(1, Ordering::SeqCst);
+            let (worker_in, host_out) = io::pipe()?;
+            let (host_in, worker_out) = io::pipe()?;
+            thread::spawn(move || {
+                let die = Arc::new(AtomicBool::new(false));
+                let flag = die.clone();
+                let factory = move |_h_inst: usize, load_dir: &Path, _bytes: &[u8]| {
+                    let shiori: Box<dyn ShioriHandler> = ServiceHandler::boxed(Ghost {
+                        load_dir: load_dir.to_path_buf(),
+                        die: flag.clone(),
+                    });
+                    Ok(shiori)
+                };
+                let mut worker = StdioWorker::new(RawShiori3::with_factory(factory));
+                let output = Dying {
+                    inner: worker_out,
+                    die,
+                };
+                let _ = worker.run(worker_in, output);
+            });
+            Ok(WorkerConnection::new(host_out, host_in))
+        })
+    }
+
+    fn load(host: &mut StdioHost, dir: &str) -> bool {
+        let (h, len) = ShioriString::clone_from_slice_nofree(dir.as_bytes()).value();
+        host.raw_load(h, len)
+    }
+
+    fn request(host: &mut StdioHost, id: &str) -> ShioriResponse {
+        let req = format!("GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: {}\r\n\r\n", id);
+        let (h, mut len) = ShioriString::clone_from_str_nofree(req.as_str()).value();
+        let h = host.raw_request(h, &mut len);
+        let res = ShioriString::capture(h, len);
+        ShioriResponse::parse(res.to_utf8_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn stdio_restart() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut host = host(spawned.clone()).with_max_restarts(1);
+        assert!(load(&mut host, "/ghost/master/"));
+        assert!(host.is_running());
+
+        let res = request(&mut host, "OnBoot");
+        assert_eq!(res.value(), Some("OnBoot"));
+        let res = request(&mut host, "OnDir");
+        assert_eq!(res.value(), Some("/ghost/master/"));
+
+        let res = request(&mut host, "OnDie");
+        assert_eq!(res.status, Status::InternalServerError);
+        assert!(res.header("X-ERROR-REASON").is_some());
+        assert!(!host.is_running());
+
+        // 再起動してloadからやり直します。
+        let res = request(&mut host, "OnDir");
+        assert_eq!(res.value(), Some("/ghost/master/"));
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+
+        // 上限を超えると再起動しません。
+        assert_eq!(
+            request(&mut host, "OnDie").status,
+            Status::InternalServerError
+        );
+        assert_eq!(
+            request(&mut host, "OnDie").status,
+            Status::InternalServerError
+        );
+        assert_eq!(
+            request(&mut host, "OnBoot").status,
+            Status::InternalServerError
+        );
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+
+        // loadし直すと数え直します。
+        assert!(load(&mut host, "/ghost/master/"));
+        assert_eq!(request(&mut host, "OnBoot").value(), Some("OnBoot"));
+        assert!(host.raw_unload());
+        assert!(!host.is_running());
+    }
+
+    #[test]
+    fn stdio_timeout() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut host = host(spawned.clone()).with_timeout(Duration::from_millis(100));
+        assert!(load(&mut host, "/ghost/master/"));
+
+        let res = request(&mut host, "OnHang");
+        assert_eq!(res.status, Status::InternalServerError);
+        assert!(res.header("X-ERROR-REASON").unwrap().contains("timed out"));
+        assert!(!host.is_running());
+
+        // 応答しないワーカーを捨てて起動し直します。
+        assert_eq!(request(&mut host, "OnBoot").value(), Some("OnBoot"));
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+        assert!(host.raw_unload());
+    }
+
+    #[test]
+    fn stdio_spawn_error() {
+        let mut host = StdioHost::new("nonexistent-worker");
+        assert!(!load(&mut host, "/nonexistent/"));
+        assert!(!host.is_running());
+        assert_eq!(
+            request(&mut host, "OnBoot").status,
+            Status::InternalServerError
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::fs::{DiskFileSystem, FileSystem};
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const HEADER: &str = "#shiori3-store";
+
+/// ストアに格納する値です。
+#[derive(Clone, PartialEq, Debug)]
+pub enum StoreValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<bool> for StoreValue {
+    fn from(v: bool) -> StoreValue {
+        StoreValue::Bool(v)
+    }
+}
+impl From<i32> for StoreValue {
+    fn from(v: i32) -> StoreValue {
+        StoreValue::Int(v.into())
+    }
+}
+impl From<u32> for StoreValue {
+    fn from(v: u32) -> StoreValue {
+        StoreValue::Int(v.into())
+    }
+}
+impl From<i64> for StoreValue {
+    fn from(v: i64) -> StoreValue {
+        StoreValue::Int(v)
+    }
+}
+impl From<f64> for StoreValue {
+    fn from(v: f64) -> StoreValue {
+        StoreValue::Float(v)
+    }
+}
+impl From<&str> for StoreValue {
+    fn from(v: &str) -> StoreValue {
+        StoreValue::Text(v.into())
+    }
+}
+impl From<String> for StoreValue {
+    fn from(v: String) -> StoreValue {
+        StoreValue::Text(v)
+    }
+}
+
+/// ストアの値から変換できる型です。
+pub trait FromStoreValue: Sized {
+    fn from_store_value(value: &StoreValue) -> Option<Self>;
+}
+
+impl FromStoreValue for bool {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        match value {
+            StoreValue::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+impl FromStoreValue for i64 {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        match value {
+            StoreValue::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+impl FromStoreValue for i32 {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        i64::from_store_value(value).and_then(|v| v.try_into().ok())
+    }
+}
+impl FromStoreValue for u32 {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        i64::from_store_value(value).and_then(|v| v.try_into().ok())
+    }
+}
+impl FromStoreValue for f64 {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        match value {
+            StoreValue::Float(v) => Some(*v),
+            StoreValue::Int(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+}
+impl FromStoreValue for String {
+    fn from_store_value(value: &StoreValue) -> Option<Self> {
+        match value {
+            StoreValue::Text(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StoreValue {
+    /// `型\t値`の形式で出力します。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreValue::Bool(v) => write!(f, "b\t{}", v),
+            StoreValue::Int(v) => write!(f, "i\t{}", v),
+            StoreValue::Float(v) => write!(f, "f\t{}", v),
+            StoreValue::Text(v) => write!(f, "s\t{}", escape(v)),
+        }
+    }
+}
+
+impl StoreValue {
+    fn parse(kind: &str, value: &str) -> Option<StoreValue> {
+        Some(match kind {
+            "b" => StoreValue::Bool(value.parse().ok()?),
+            "i" => StoreValue::Int(value.parse().ok()?),
+            "f" => StoreValue::Float(value.parse().ok()?),
+            "s" => StoreValue::Text(unescape(value)),
+            _ => return None,
+        })
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '\t' => buf.push_str("\\t"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            _ => buf.push(c),
+        }
+    }
+    buf
+}
+
+fn unescape(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len());
+    let mut it = text.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' {
+            buf.push(c);
+            continue;
+        }
+        match it.next() {
+            Some('t') => buf.push('\t'),
+            Some('n') => buf.push('\n'),
+            Some('r') => buf.push('\r'),
+            Some(c) => buf.push(c),
+            None => buf.push('\\'),
+        }
+    }
+    buf
+}
+
+/// スキーマ移行処理です。
+pub type Migration = Box<dyn Fn(&mut BTreeMap<String, StoreValue>) -> MyResult<()> + Send>;
+
+/// ロードディレクトリ以下に保存する永続変数ストアです。
+/// 読み書きは`FileSystem`を通して行い、保存は一時ファイルへの書き込みとリネームで行います。
+/// drop時に未保存の変更を保存します。
+pub struct Store {
+    fs: Option<Arc<dyn FileSystem>>,
+    /// `fs`がロード時にホストから設定されたものかどうか
+    bound: bool,
+    path: PathBuf,
+    version: u32,
+    values: BTreeMap<String, StoreValue>,
+    migrations: Vec<(u32, Migration)>,
+    dirty: bool,
+    autosave: Option<Duration>,
+    last_save: SystemTime,
+    clock: Arc<dyn Clock>,
+}
+
+impl Store {
+    /// 空のストアを作成します。ファイルは読み込みません。
+    /// 相対パスは`RawShiori3`に設定した場合、ロードディレクトリからのパスになります。
+    pub fn new<P: AsRef<Path>>(path: P, version: u32) -> Store {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Store {
+            fs: None,
+            bound: false,
+            path: path.as_ref().to_path_buf(),
+            version,
+            values: BTreeMap::new(),
+            migrations: Vec::new(),
+            dirty: false,
+            autosave: None,
+            last_save: clock.now(),
+            clock,
+        }
+    }
+
+    /// load_dir以下のファイルからストアを読み込みます。
+    /// SHIORI::load()での利用を想定しています。
+    pub fn open<P: AsRef<Path>>(load_dir: P, name: &str, version: u32) -> MyResult<Store> {
+        let fs = Arc::new(DiskFileSystem::new(load_dir));
+        let mut store = Store::new(name, version).with_fs(fs);
+        store.load()?;
+        Ok(store)
+    }
+
+    /// 保存先のファイルシステムを設定します。パスはファイルシステムのルートからのパスになります。
+    pub fn with_fs(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.fs = Some(fs);
+        self.bound = false;
+        self
+    }
+
+    /// バージョン`from`から`from + 1`への移行処理を登録します。
+    pub fn with_migration<F>(mut self, from: u32, f: F) -> Self
+    where
+        F: Fn(&mut BTreeMap<String, StoreValue>) -> MyResult<()> + Send + 'static,
+    {
+        self.migrations.push((from, Box::new(f)));
+        self.migrations.sort_by_key(|(v, _)| *v);
+        self
+    }
+
+    /// 自動保存の間隔を設定します。
+    pub fn with_autosave(mut self, interval: Duration) -> Self {
+        self.autosave = Some(interval);
+        self
+    }
+
+    /// 時計を設定します。
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_save = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 相対パスなら、ロード毎にホストのファイルシステムを設定し直します。
+    /// `with_fs()`で設定したファイルシステムは置き換えません。
+    pub(crate) fn resolve(&mut self, fs: &Arc<dyn FileSystem>) {
+        if (self.fs.is_none() || self.bound) && self.path.is_relative() {
+            self.fs = Some(fs.clone());
+            self.bound = true;
+        }
+    }
+
+    /// 保存先のパスを返します。
+    pub fn path(&self) -> PathBuf {
+        match &self.fs {
+            Some(fs) => fs.root().join(&self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// 保存先のファイルシステムと、そのルートからのパスを返します。
+    /// ファイルシステムが未設定なら、パスのディレクトリをルートとするディスクです。
+    fn storage(&self) -> (Arc<dyn FileSystem>, PathBuf) {
+        if let Some(fs) = &self.fs {
+            return (fs.clone(), self.path.clone());
+        }
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        let name = self.path.file_name().unwrap_or(self.path.as_os_str());
+        (Arc::new(DiskFileSystem::new(dir)), PathBuf::from(name))
+    }
+
+    /// スキーマバージョンを返します。
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// 未保存の変更があるかどうかを返します。
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// ファイルを読み込みます。ファイルがなければ空のストアになります。
+    /// 古いバージョンのファイルは登録された移行処理で変換します。
+    pub fn load(&mut self) -> MyResult<()> {
+        let (fs, path) = self.storage();
+        let text = match fs.read_to_string(&path) {
+            Ok(a) => a,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.values.clear();
+                self.dirty = false;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = text.lines();
+        let version = lines
+            .next()
+            .and_then(|line| line.strip_prefix(HEADER))
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .ok_or_else(|| MyError::store_error(format!("invalid header: {:?}", self.path())))?;
+        if version > self.version {
+            return Err(MyError::store_error(format!(
+                "unsupported version {} > {}",
+                version, self.version
+            )));
+        }
+        let mut values = BTreeMap::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            let mut it = line.splitn(3, '\t');
+            let (key, kind, value) = match (it.next(), it.next(), it.next()) {
+                (Some(k), Some(t), Some(v)) => (k, t, v),
+                _ => return Err(MyError::store_error(format!("invalid line: {}", line))),
+            };
+            let value = StoreValue::parse(kind, value)
+                .ok_or_else(|| MyError::store_error(format!("invalid value: {}", line)))?;
+            values.insert(unescape(key), value);
+        }
+        for (_, migration) in self
+            .migrations
+            .iter()
+            .filter(|(from, _)| version <= *from && *from < self.version)
+        {
+            migration(&mut values)?;
+        }
+        self.values = values;
+        self.dirty = version != self.version;
+        Ok(())
+    }
+
+    /// ファイルに保存します。
+    pub fn save(&mut self) -> MyResult<()> {
+        let (fs, path) = self.storage();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut text = String::new();
+        writeln!(text, "{} {}", HEADER, self.version).unwrap();
+        for (key, value) in &self.values {
+            writeln!(text, "{}\t{}", escape(key), value).unwrap();
+        }
+        fs.write(&tmp, text.as_bytes())?;
+        fs.rename(&tmp, &path)?;
+        self.dirty = false;
+        self.last_save = self.clock.now();
+        Ok(())
+    }
+
+    /// 未保存の変更があれば保存します。
+    pub fn flush(&mut self) -> MyResult<()> {
+        if self.dirty { self.save() } else { Ok(()) }
+    }
+
+    /// 自動保存の間隔が経過していれば保存します。保存した場合は`true`を返します。
+    /// リクエスト毎に呼び出してください。
+    pub fn autosave(&mut self) -> MyResult<bool> {
+        let interval = match self.autosave {
+            Some(a) if self.dirty => a,
+            _ => return Ok(false),
+        };
+        let elapsed = self
+            .clock
+            .now()
+            .duration_since(self.last_save)
+            .unwrap_or(Duration::ZERO);
+        if elapsed < interval {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// 値を取得します。型が異なる場合は`None`を返します。
+    pub fn get<T: FromStoreValue>(&self, key: &str) -> Option<T> {
+        self.values.get(key).and_then(T::from_store_value)
+    }
+
+    /// 値を取得します。値がないか型が異なる場合は`default`を返します。
+    pub fn get_or<T: FromStoreValue>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// 値を設定します。
+    pub fn set<K: Into<String>, V: Into<StoreValue>>(&mut self, key: K, value: V) {
+        let value = value.into();
+        let key = key.into();
+        if self.values.get(&key) != Some(&value) {
+            self.values.insert(key, value);
+            self.dirty = true;
+        }
+    }
+
+    /// 値を削除します。
+    pub fn remove(&mut self, key: &str) -> Option<StoreValue> {
+        let rc = self.values.remove(key);
+        if rc.is_some() {
+            self.dirty = true;
+        }
+        rc
+    }
+
+    /// キーが存在するかどうかを返します。
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// キーの一覧を返します。
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(|k| k.as_str())
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("[store] {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::fs::MemoryFileSystem;
+    use std::fs;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("shiori3-store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn store_1() {
+        let dir = test_dir("store_1");
+        {
+            let mut store = Store::open(&dir, "store.dat", 1).unwrap();
+            assert!(!store.contains_key("count"));
+            store.set("count", 3);
+            store.set("name", "さくら\t\\\r\nうにゅう");
+            store.set("rate", 0.25);
+            store.set("flag", true);
+        }
+        let store = Store::open(&dir, "store.dat", 1).unwrap();
+        assert_eq!(store.get::<i32>("count"), Some(3));
+        assert_eq!(
+            store.get::<String>("name").unwrap(),
+            "さくら\t\\\r\nうにゅう"
+        );
+        assert_eq!(store.get::<f64>("rate"), Some(0.25));
+        assert_eq!(store.get::<bool>("flag"), Some(true));
+        assert_eq!(store.get::<bool>("count"), None);
+        assert_eq!(store.get_or("none", 10), 10);
+        assert!(!store.is_dirty());
+        assert!(!dir.join("store.dat.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_migration() {
+        let dir = test_dir("store_migration");
+        {
+            let mut store = Store::open(&dir, "store.dat", 1).unwrap();
+            store.set("talk", 10);
+        }
+        let mut store = Store::new(dir.join("store.dat"), 3)
+            .with_migration(2, |values| {
+                values.insert("v3".into(), true.into());
+                Ok(())
+            })
+            .with_migration(1, |values| {
+                let talk = values.remove("talk").unwrap();
+                values.insert("talk_count".into(), talk);
+                Ok(())
+            });
+        store.load().unwrap();
+        assert_eq!(store.get::<i64>("talk_count"), Some(10));
+        assert_eq!(store.get::<bool>("v3"), Some(true));
+        assert!(store.is_dirty());
+        store.flush().unwrap();
+
+        let mut store = Store::new(dir.join("store.dat"), 2);
+        assert!(store.load().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_autosave() {
+        let dir = test_dir("store_autosave");
+        let clock = Arc::new(VirtualClock::new(SystemTime::UNIX_EPOCH));
+        let mut store = Store::new(dir.join("store.dat"), 1)
+            .with_clock(clock.clone())
+            .with_autosave(Duration::from_secs(60));
+        assert!(!store.autosave().unwrap());
+        store.set("a", 1);
+        clock.advance(Duration::from_secs(30));
+        assert!(!store.autosave().unwrap());
+        clock.advance(Duration::from_secs(30));
+        assert!(store.autosave().unwrap());
+        assert!(!store.is_dirty());
+        assert!(dir.join("store.dat").exists());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_fs() {
+        let mem = Arc::new(MemoryFileSystem::new("ghost"));
+        let mut store = Store::new("var/store.dat", 1).with_fs(mem.clone());
+        assert_eq!(store.path(), Path::new("ghost/var/store.dat"));
+        store.load().unwrap();
+        store.set("a", 1);
+        drop(store);
+        let text = mem.read_to_string(Path::new("var/store.dat")).unwrap();
+        assert_eq!(text, "#shiori3-store 1\na\ti\t1\n");
+        assert!(!mem.exists(Path::new("var/store.dat.tmp")));
+    }
+}
@@ -0,0 +1,475 @@
+use crate::api::ShioriHandler;
+use crate::context::RequestContext;
+use crate::error::*;
+use crate::services::Services;
+use crate::simulator::{BasewareSimulator, Exchange};
+use std::collections::HashSet;
+use std::fmt;
+
+const REQUEST: &str = "**** REQUEST ****";
+const RESPONSE: &str = "**** RESPONSE ****";
+const END: &str = "********";
+const COMMENT: &str = "//";
+
+/// 比較時にステータス行を表す名前です。
+const STATUS_LINE: &str = ":status";
+
+/// logfile.txt形式のトレースを解析します。
+/// `**** REQUEST ****`と`**** RESPONSE ****`で始まり`********`で終わるブロックを、
+/// 改行をCRLFにそろえたリクエストと応答の組にします。
+/// ブロックの外にある`//`で始まる行はコメントとして読み飛ばします。
+pub fn parse_trace(text: &str) -> MyResult<Vec<Exchange>> {
+    let mut rc: Vec<Exchange> = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with(COMMENT) {
+            continue;
+        }
+        let is_request = match line {
+            REQUEST => true,
+            RESPONSE => false,
+            _ => return Err(MyError::trace_error(i + 1, "expected block header")),
+        };
+        let mut message = String::new();
+        let mut closed = false;
+        for (_, line) in lines.by_ref() {
+            let line = line.trim_end_matches('\r');
+            if line == END {
+                closed = true;
+                break;
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        if !closed {
+            return Err(MyError::trace_error(i + 1, "unclosed block"));
+        }
+        while !message.ends_with("\r\n\r\n") {
+            message.push_str("\r\n");
+        }
+        if is_request {
+            rc.push(Exchange {
+                request: message,
+                response: None,
+            });
+        } else {
+            match rc.last_mut() {
+                Some(e) if e.response.is_none() => e.response = Some(message),
+                _ => return Err(MyError::trace_error(i + 1, "response without request")),
+            }
+        }
+    }
+    Ok(rc)
+}
+
+/// リクエストと応答をlogfile.txt形式のブロックにします。
+/// 応答がない場合はRESPONSEブロックを出力しません。
+pub fn format_exchange(exchange: &Exchange) -> String {
+    let mut buf = String::new();
+    write_block(&mut buf, REQUEST, &exchange.request);
+    if let Some(res) = &exchange.response {
+        write_block(&mut buf, RESPONSE, res);
+    }
+    buf
+}
+
+fn write_block(buf: &mut String, header: &str, message: &str) {
+    buf.push_str(header);
+    buf.push('\n');
+    let mut lines: Vec<&str> = message.lines().collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    for line in lines {
+        buf.push_str(line);
+        buf.push('\n');
+    }
+    buf.push('\n');
+    buf.push_str(END);
+    buf.push('\n');
+}
+
+/// 応答をステータス行とヘッダの組に分解します。順序と重複はそのまま残します。
+fn split_response(res: &str) -> Vec<(String, String)> {
+    let mut lines = res.lines();
+    let mut rc = Vec::new();
+    if let Some(status) = lines.next() {
+        rc.push((STATUS_LINE.to_string(), status.trim().to_string()));
+    }
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if let Some((k, v)) = line.split_once(':') {
+            rc.push((k.trim().to_string(), v.trim_start().to_string()));
+        }
+    }
+    rc
+}
+
+/// 一つのヘッダの差分です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HeaderDiff {
+    pub name: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// 記録と異なる応答です。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReplayDiff {
+    /// トレース内の位置(0から始まる)
+    pub index: usize,
+    pub id: Option<String>,
+    pub headers: Vec<HeaderDiff>,
+}
+
+impl fmt::Display for ReplayDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "#{} {}", self.index, self.id.as_deref().unwrap_or("-"))?;
+        for h in &self.headers {
+            if let Some(v) = &h.expected {
+                writeln!(f, "- {}: {}", h.name, v)?;
+            }
+            if let Some(v) = &h.actual {
+                writeln!(f, "+ {}: {}", h.name, v)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// リプレイの結果です。
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ReplayReport {
+    /// 送ったリクエストの数
+    pub total: usize,
+    /// 比較した応答の数
+    pub compared: usize,
+    pub diffs: Vec<ReplayDiff>,
+}
+
+impl ReplayReport {
+    /// 全ての応答が一致したかどうかを返します。
+    pub fn is_ok(&self) -> bool {
+        self.diffs.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for d in &self.diffs {
+            write!(f, "{}", d)?;
+        }
+        write!(
+            f,
+            "{} requests, {} compared, {} differ",
+            self.total,
+            self.compared,
+            self.diffs.len()
+        )
+    }
+}
+
+type Normalizer = Box<dyn Fn(&str) -> String>;
+
+/// 記録したリクエストをSHIORIに送り、応答を記録と比較します。
+#[derive(Default)]
+pub struct Replay {
+    ignore_headers: HashSet<String>,
+    normalizer: Option<Normalizer>,
+    services: Services,
+}
+
+impl Replay {
+    pub fn new() -> Replay {
+        Default::default()
+    }
+
+    /// 比較しないヘッダを追加します。Senderなど実行毎に変わるヘッダに利用してください。
+    pub fn ignore_header<S: Into<String>>(mut self, name: S) -> Self {
+        self.ignore_headers.insert(name.into());
+        self
+    }
+
+    /// Valueヘッダのスクリプトを比較前に正規化する関数を設定します。
+    pub fn with_normalizer<F: Fn(&str) -> String + 'static>(mut self, f: F) -> Self {
+        self.normalizer = Some(Box::new(f));
+        self
+    }
+
+    /// ハンドラに渡すサービスを設定します。リクエストの時刻はサービスの時計から取得します。
+    pub fn with_services(mut self, services: Services) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// ハンドラに直接リクエストを送ります。
+    /// エラーを返した場合は、応答がない(NULL)ものとして扱います。
+    pub fn run<H: ShioriHandler + ?Sized>(
+        &self,
+        shiori: &mut H,
+        trace: &[Exchange],
+    ) -> ReplayReport {
+        let mut sequence = 0;
+        self.run_with(trace, |req| {
+            sequence += 1;
+            let mut ctx = RequestContext::new(sequence, self.services.clock().now())
+                .with_services(self.services.clone());
+            shiori
+                .handle(req, &mut ctx)
+                .ok()
+                .map(|res| res.into_owned())
+        })
+    }
+
+    /// シミュレータを通して、実際のABIでリクエストを送ります。
+    pub fn run_simulator<T>(
+        &self,
+        sim: &mut BasewareSimulator<T>,
+        trace: &[Exchange],
+    ) -> MyResult<ReplayReport> {
+        let mut rc = Ok(());
+        let report = self.run_with(trace, |req| match sim.request(req) {
+            Ok(e) => e.response.clone(),
+            Err(e) => {
+                if rc.is_ok() {
+                    rc = Err(e);
+                }
+                None
+            }
+        });
+        rc.map(|_| report)
+    }
+
+    fn run_with<F>(&self, trace: &[Exchange], mut request: F) -> ReplayReport
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        let mut report = ReplayReport::default();
+        for (index, recorded) in trace.iter().enumerate() {
+            report.total += 1;
+            let actual = request(&recorded.request);
+            let expected = match &recorded.response {
+                Some(a) => a,
+                None => continue,
+            };
+            report.compared += 1;
+            let headers = self.compare(expected, actual.as_deref());
+            if !headers.is_empty() {
+                report.diffs.push(ReplayDiff {
+                    index,
+                    id: recorded.id().map(|s| s.to_string()),
+                    headers,
+                });
+            }
+        }
+        report
+    }
+
+    fn normalize(&self, name: &str, value: String) -> String {
+        match &self.normalizer {
+            Some(f) if name == "Value" => f(&value),
+            _ => value,
+        }
+    }
+
+    /// 記録と実際の応答を比較し、異なるヘッダを返します。
+    /// 同じ名前のヘッダは、現れた順に対応させて順序と重複を比較します。
+    /// 異なる名前のヘッダ間の順序は比較しません。
+    pub fn compare(&self, expected: &str, actual: Option<&str>) -> Vec<HeaderDiff> {
+        let expected = split_response(expected);
+        let actual = actual.map(split_response).unwrap_or_default();
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in expected.iter().chain(actual.iter()) {
+            if !self.ignore_headers.contains(name) && !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        let values = |headers: &[(String, String)], name: &str| -> Vec<String> {
+            headers
+                .iter()
+                .filter(|(k, _)| k == name)
+                .map(|(_, v)| self.normalize(name, v.clone()))
+                .collect()
+        };
+        let mut rc: Vec<HeaderDiff> = Vec::new();
+        for name in names {
+            let e = values(&expected, name);
+            let a = values(&actual, name);
+            for i in 0..e.len().max(a.len()) {
+                if e.get(i) != a.get(i) {
+                    rc.push(HeaderDiff {
+                        name: name.to_string(),
+                        expected: e.get(i).cloned(),
+                        actual: a.get(i).cloned(),
+                    });
+                }
+            }
+        }
+        rc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::RawShiori3;
+    use crate::clock::VirtualClock;
+    use crate::parsers::res::ShioriResponse;
+    use crate::script::token::ScriptTree;
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const LOGFILE: &str = include_str!("parsers/test_data/logfile.txt");
+
+    struct Pasta {
+        boot: &'static str,
+    }
+
+    impl ShioriHandler for Pasta {
+        fn handle<'a>(
+            &mut self,
+            req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            let res = if req.contains("\r\nID: OnBoot\r\n") && !self.boot.is_empty() {
+                ShioriResponse::ok(self.boot)
+            } else {
+                ShioriResponse::no_content()
+            };
+            Ok(res.to_string().into())
+        }
+    }
+
+    #[test]
+    fn parse_logfile() {
+        let trace = parse_trace(LOGFILE).unwrap();
+        assert_eq!(trace.len(), 201);
+        assert!(
+            trace[0]
+                .request
+                .starts_with("GET Version SHIORI/2.6\r\nCharset: UTF-8\r\n")
+        );
+        assert!(trace[0].request.ends_with("Sender: SSP\r\n\r\n"));
+        assert!(trace.iter().all(|e| e.response.is_some()));
+        assert_eq!(trace[1].id(), Some("version"));
+
+        assert!(parse_trace("**** REQUEST ****\nGET SHIORI/3.0\n").is_err());
+        assert!(parse_trace("**** RESPONSE ****\nSHIORI/3.0 200 OK\n********\n").is_err());
+        assert!(parse_trace("GET SHIORI/3.0\n").is_err());
+    }
+
+    #[test]
+    fn format_logfile() {
+        let trace = parse_trace(LOGFILE).unwrap();
+        let text: String = trace.iter().map(format_exchange).collect();
+        assert_eq!(text, LOGFILE.replace("\r\n", "\n"));
+        let e = Exchange {
+            request: "GET SHIORI/3.0\r\nID: a\r\n\r\n".into(),
+            response: None,
+        };
+        assert_eq!(parse_trace(&format_exchange(&e)).unwrap(), vec![e]);
+    }
+
+    #[test]
+    fn replay_logfile() {
+        let trace = parse_trace(LOGFILE).unwrap();
+        let mut shiori = Pasta { boot: "" };
+        let report = Replay::new().run(&mut shiori, &trace);
+        assert_eq!(report.total, 201);
+        assert_eq!(report.compared, 201);
+        assert_eq!(report.diffs.len(), 201);
+        assert!(report.diffs.iter().all(|d| d.headers.len() == 1));
+        assert_eq!(
+            report.diffs[0].headers[0],
+            HeaderDiff {
+                name: "Sender".into(),
+                expected: Some("pasta".into()),
+                actual: None,
+            }
+        );
+
+        let replay = Replay::new().ignore_header("Sender");
+        let report = replay.run(&mut shiori, &trace);
+        assert!(report.is_ok(), "{}", report);
+
+        let mut shiori = Pasta {
+            boot: "\\0\\s[0]hello\\w9\\e",
+        };
+        let report = replay.run(&mut shiori, &trace);
+        assert_eq!(report.diffs.len(), 1);
+        assert_eq!(report.diffs[0].id.as_deref(), Some("OnBoot"));
+        assert!(report.to_string().contains("+ :status: SHIORI/3.0 200 OK"));
+    }
+
+    #[test]
+    fn replay_simulator() {
+        let trace = parse_trace(LOGFILE).unwrap();
+        let factory = |_h_inst: usize, _load_dir: &std::path::Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = Box::new(Pasta { boot: "" });
+            Ok(shiori)
+        };
+        let mut sim = BasewareSimulator::with_raw(RawShiori3::with_factory(factory), "ghost");
+        let replay = Replay::new().ignore_header("Sender");
+        assert!(replay.run_simulator(&mut sim, &trace).is_err());
+        sim.load().unwrap();
+        let report = replay.run_simulator(&mut sim, &trace).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(sim.exchanges().len(), 201);
+    }
+
+    /// リクエストの時刻を応答します。
+    struct Stamp;
+
+    impl ShioriHandler for Stamp {
+        fn handle<'a>(
+            &mut self,
+            _req: &'a str,
+            ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            assert_eq!(ctx.clock().now(), ctx.timestamp);
+            let secs = ctx.timestamp.duration_since(UNIX_EPOCH)?.as_secs();
+            Ok(ShioriResponse::ok(secs.to_string()).to_string().into())
+        }
+    }
+
+    #[test]
+    fn replay_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(100);
+        let services = Services::new().with_clock(Arc::new(VirtualClock::new(start)));
+        let trace = vec![Exchange {
+            request: "GET SHIORI/3.0\r\nID: OnBoot\r\n\r\n".into(),
+            response: Some(ShioriResponse::ok("100").to_string()),
+        }];
+        let report = Replay::new()
+            .with_services(services)
+            .run(&mut Stamp, &trace);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn replay_normalize() {
+        let replay = Replay::new().with_normalizer(|value| {
+            ScriptTree::parse(value)
+                .map(|t| t.strip_tags())
+                .unwrap_or_else(|_| value.to_string())
+        });
+        let expected = "SHIORI/3.0 200 OK\r\nValue: \\0hello\\w9\\e\r\n\r\n";
+        let actual = "SHIORI/3.0 200 OK\r\nValue: \\0\\s[1]hello\\e\r\n\r\n";
+        assert!(replay.compare(expected, Some(actual)).is_empty());
+        assert_eq!(Replay::new().compare(expected, Some(actual)).len(), 1);
+        assert_eq!(Replay::new().compare(expected, None).len(), 2);
+
+        let expected = "SHIORI/3.0 200 OK\r\nX-A: 1\r\nX-A: 2\r\nValue: a\r\n\r\n";
+        let actual = "SHIORI/3.0 200 OK\r\nValue: a\r\nX-A: 1\r\nX-A: 3\r\n\r\n";
+        let diffs = Replay::new().compare(expected, Some(actual));
+        assert_eq!(
+            diffs,
+            vec![HeaderDiff {
+                name: "X-A".into(),
+                expected: Some("2".into()),
+                actual: Some("3".into()),
+            }]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::simulator::Exchange;
+use crate::trace::format_exchange;
+use log::*;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 伏せ字にしたヘッダの値です。
+const REDACTED: &str = "***";
+
+/// トレースの記録を実行中に切り替えるスイッチです。
+/// `TraceLogger::switch()`で取得し、ゴーストのコードから操作できます。
+#[derive(Clone, Debug)]
+pub struct TraceSwitch(Arc<AtomicBool>);
+
+impl TraceSwitch {
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// リクエストと応答をlogfile.txt形式でファイルに追記します。
+/// 相対パスは`RawShiori3`のロード時にロードディレクトリからのパスになります。
+#[derive(Debug)]
+pub struct TraceLogger {
+    path: PathBuf,
+    switch: TraceSwitch,
+    max_size: Option<u64>,
+    max_files: usize,
+    redact: HashSet<String>,
+}
+
+impl TraceLogger {
+    /// pathに記録するロガーを作成します。ローテーションはしません。
+    pub fn new<P: AsRef<Path>>(path: P) -> TraceLogger {
+        TraceLogger {
+            path: path.as_ref().to_path_buf(),
+            switch: TraceSwitch(Arc::new(AtomicBool::new(true))),
+            max_size: None,
+            max_files: 0,
+            redact: HashSet::new(),
+        }
+    }
+
+    /// ファイルがmax_sizeを超える場合に`path.1`、`path.2`…へローテーションし、
+    /// 古いファイルをmax_files個まで残します。
+    pub fn with_rotation(mut self, max_size: u64, max_files: usize) -> Self {
+        self.max_size = Some(max_size);
+        self.max_files = max_files;
+        self
+    }
+
+    /// 値を伏せ字にするヘッダを追加します。
+    pub fn with_redact<S: Into<String>>(mut self, name: S) -> Self {
+        self.redact.insert(name.into());
+        self
+    }
+
+    /// 記録の有効・無効を設定します。
+    pub fn with_enabled(self, enabled: bool) -> Self {
+        self.switch.set(enabled);
+        self
+    }
+
+    /// 記録先のパスを返します。
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 実行中に記録を切り替えるスイッチを返します。
+    pub fn switch(&self) -> TraceSwitch {
+        self.switch.clone()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.switch.is_enabled()
+    }
+
+    /// 相対パスをdirからのパスにします。
+    pub(crate) fn resolve(&mut self, dir: &Path) {
+        if self.path.is_relative() {
+            self.path = dir.join(&self.path);
+        }
+    }
+
+    /// リクエストと応答を記録します。無効の場合は何もしません。
+    /// 書き込みに失敗してもSHIORIの動作は止めず、警告ログを出力します。
+    pub fn record(&self, request: &str, response: Option<&str>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let exchange = Exchange {
+            request: self.redact(request),
+            response: response.map(|res| self.redact(res)),
+        };
+        if let Err(e) = self.write(&format_exchange(&exchange)) {
+            warn!("[trace] {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn redact(&self, message: &str) -> String {
+        if self.redact.is_empty() {
+            return message.to_string();
+        }
+        let mut buf = String::with_capacity(message.len());
+        for line in message.split_inclusive('\n') {
+            match line.split_once(':') {
+                Some((name, _)) if self.redact.contains(name.trim()) => {
+                    buf.push_str(name);
+                    buf.push_str(": ");
+                    buf.push_str(REDACTED);
+                    buf.push_str(if line.ends_with("\r\n") { "\r\n" } else { "\n" });
+                }
+                _ => buf.push_str(line),
+            }
+        }
+        buf
+    }
+
+    fn write(&self, text: &str) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if let Some(max_size) = self.max_size {
+            let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+            if size > 0 && size + text.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(text.as_bytes())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let _ = fs::remove_file(self.rotated(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{RawShiori3, ShioriHandler};
+    use crate::context::RequestContext;
+    use crate::simulator::BasewareSimulator;
+    use crate::trace::parse_trace;
+    use std::borrow::Cow;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("shiori3-trace-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const REQ: &str =
+        "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\nReference0: secret\r\n\r\n";
+    const RES: &str = "SHIORI/3.0 200 OK\r\nCharset: UTF-8\r\nValue: \\0hello\\e\r\n\r\n";
+
+    #[test]
+    fn trace_logger() {
+        let dir = test_dir("logger");
+        let mut logger = TraceLogger::new("log/trace.txt").with_redact("Reference0");
+        logger.resolve(&dir);
+        assert_eq!(logger.path(), dir.join("log/trace.txt"));
+        logger.record(REQ, Some(RES));
+        let switch = logger.switch();
+        switch.disable();
+        logger.record(REQ, Some(RES));
+        switch.enable();
+        logger.record(REQ, None);
+
+        let text = fs::read_to_string(logger.path()).unwrap();
+        let trace = parse_trace(&text).unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(
+            trace[0].request,
+            "GET SHIORI/3.0\r\nCharset: UTF-8\r\nID: OnBoot\r\nReference0: ***\r\n\r\n"
+        );
+        assert_eq!(trace[0].response.as_deref(), Some(RES));
+        assert_eq!(trace[1].response, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trace_rotation() {
+        let dir = test_dir("rotation");
+        let logger = TraceLogger::new(dir.join("trace.txt")).with_rotation(200, 2);
+        for _ in 0..5 {
+            logger.record(REQ, Some(RES));
+        }
+        assert!(dir.join("trace.txt").exists());
+        assert!(dir.join("trace.txt.1").exists());
+        assert!(dir.join("trace.txt.2").exists());
+        assert!(!dir.join("trace.txt.3").exists());
+        for name in ["trace.txt", "trace.txt.1", "trace.txt.2"] {
+            let text = fs::read_to_string(dir.join(name)).unwrap();
+            assert_eq!(parse_trace(&text).unwrap().len(), 1);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Echo;
+
+    impl ShioriHandler for Echo {
+        fn handle<'a>(
+            &mut self,
+            req: &'a str,
+            _ctx: &mut RequestContext,
+        ) -> Result<Cow<'a, str>, anyhow::Error> {
+            if req.contains("\r\nID: OnBoot\r\n") {
+                Ok("SHIORI/3.0 200 OK\r\nValue: \\0hello\\e\r\n\r\n".into())
+            } else {
+                Err(anyhow::anyhow!("unknown event"))
+            }
+        }
+    }
+
+    #[test]
+    fn trace_raw_shiori() {
+        let dir = test_dir("raw");
+        let factory = |_h_inst: usize, _load_dir: &Path, _bytes: &[u8]| {
+            let shiori: Box<dyn ShioriHandler> = Box::new(Echo);
+            Ok(shiori)
+        };
+        let mut raw = RawShiori3::with_factory(factory);
+        raw.set_trace(TraceLogger::new("trace.txt"));
+        let mut sim = BasewareSimulator::with_raw(raw, &dir);
+        sim.load().unwrap();
+        sim.get("OnBoot", &[]).unwrap();
+        sim.get("OnClose", &[]).unwrap();
+        sim.shiori_mut().set_trace_enabled(false);
+        sim.get("OnBoot", &[]).unwrap();
+
+        let text = fs::read_to_string(dir.join("trace.txt")).unwrap();
+        let trace = parse_trace(&text).unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].id(), Some("OnBoot"));
+        assert_eq!(trace[0].value(), Some("\\0hello\\e"));
+        assert_eq!(trace[1].id(), Some("OnClose"));
+        assert_eq!(trace[1].response, None);
+        drop(sim);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}